@@ -0,0 +1,153 @@
+// Benchmarks of the tables on generated workloads, run by hasty-bench, and
+// HTML reports of their results by hasty-report
+
+mod engine;
+mod html;
+mod plot;
+mod stats;
+mod workload;
+mod ycsb;
+
+pub use engine::{BenchTable, Engine};
+pub use html::html;
+pub use stats::{Latencies, Report, SizeSample};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+pub use workload::{Distribution, Op, OpGenerator, OpKind, OpMix, Workload};
+pub use ycsb::{YcsbReport, YcsbWorkload};
+
+// Loads the records of the workload, then runs its operations and takes
+// `size_samples` measurements of the size on disk along the way. Workloads
+// with scans need an ordered engine.
+pub fn run(
+    table: &mut dyn BenchTable,
+    engine: Engine,
+    workload: &Workload,
+    size_samples: usize,
+) -> Report {
+    let mut load_sizes = vec![SizeSample {
+        ops: 0,
+        elapsed: Duration::ZERO,
+        bytes: table.on_disk_size(),
+    }];
+    let load_every = (workload.records as usize)
+        .div_ceil(size_samples.max(1))
+        .max(1);
+    let mut load_time = Duration::ZERO;
+    let mut start = Instant::now();
+    for key in 0..workload.records {
+        table.set(key, key);
+        let loaded = key as usize + 1;
+        if loaded.is_multiple_of(load_every) || key + 1 == workload.records {
+            load_time += start.elapsed();
+            load_sizes.push(SizeSample {
+                ops: loaded,
+                elapsed: load_time,
+                bytes: table.on_disk_size(),
+            });
+            start = Instant::now();
+        }
+    }
+
+    let mut latencies = BTreeMap::<OpKind, Vec<u64>>::new();
+    let mut sizes = vec![SizeSample {
+        ops: 0,
+        elapsed: Duration::ZERO,
+        bytes: table.on_disk_size(),
+    }];
+    let sample_every = workload.ops.div_ceil(size_samples.max(1)).max(1);
+    // Time spent measuring the size doesn't count
+    let mut time = Duration::ZERO;
+    for (i, op) in workload.generate().enumerate() {
+        let start = Instant::now();
+        match op {
+            Op::Get(key) => {
+                table.get(key);
+            }
+            Op::Set(key, value) | Op::Insert(key, value) => table.set(key, value),
+            Op::Remove(key) => table.remove(key),
+            Op::Scan(key, len) => {
+                table.scan(key, len).expect("scans need an ordered engine");
+            }
+            Op::ReadModifyWrite(key, value) => {
+                let old = table.get(key).unwrap_or(0);
+                table.set(key, old ^ value);
+            }
+        }
+        let elapsed = start.elapsed();
+        time += elapsed;
+        let samples = latencies.entry(op.kind()).or_default();
+        samples.push(elapsed.as_nanos() as u64);
+        if (i + 1) % sample_every == 0 || i + 1 == workload.ops {
+            sizes.push(SizeSample {
+                ops: i + 1,
+                elapsed: time,
+                bytes: table.on_disk_size(),
+            });
+        }
+    }
+
+    Report {
+        engine: engine.to_string(),
+        workload: format!(
+            "{}, {} records, {} ops ({}), seed {}",
+            workload.distribution, workload.records, workload.ops, workload.mix, workload.seed
+        ),
+        load_ops: workload.records as usize,
+        load_time,
+        load_sizes,
+        ops: workload.ops,
+        time,
+        latencies: latencies
+            .into_iter()
+            .map(|(kind, samples)| (kind, Latencies::new(samples)))
+            .collect(),
+        sizes,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn run_engines() {
+        let workload = Workload {
+            distribution: Distribution::Zipfian,
+            records: 1000,
+            ops: 5000,
+            mix: OpMix {
+                read: 0.5,
+                write: 0.4,
+                delete: 0.1,
+                ..Default::default()
+            },
+            seed: 1,
+        };
+        let dir = "bench_run_engines";
+        let _ = fs::remove_dir_all(dir);
+        for engine in Engine::ALL {
+            let mut table = engine.open(dir);
+            let report = run(&mut *table, engine, &workload, 10);
+            let count = |kind| report.latencies[&kind].count;
+            assert_eq!(report.latencies.len(), 3);
+            assert_eq!(
+                count(OpKind::Read) + count(OpKind::Write) + count(OpKind::Delete),
+                5000
+            );
+            assert!(count(OpKind::Read) > 2000 && count(OpKind::Delete) > 0);
+            assert_eq!(report.sizes.len(), 11);
+            assert_eq!(report.load_sizes.len(), 11);
+            assert_eq!(report.load_sizes.last().unwrap().ops, 1000);
+            assert_eq!(report.sizes.last().unwrap().ops, 5000);
+            assert!(report.sizes.iter().all(|sample| sample.bytes > 0));
+            assert!(report
+                .to_string()
+                .starts_with(&format!("{}: zipfian", engine)));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+use crate::bitcask::{Bitcask, BitcaskOptions};
+use crate::btree::{BPlusTree, BPlusTreeOptions};
+use crate::hash_table::HashTable;
+use crate::linear_probing::{LPHashTable, LPHashTableOptions};
+use crate::lsmt::{LSMTree, LSMTreeOptions};
+use std::{fmt, fs, str::FromStr};
+
+// The tables under benchmark. Scans read entries in key order, which only
+// the ordered engines keep.
+pub trait BenchTable: HashTable {
+    // Reads up to `len` entries from `start` on and returns how many there
+    // were, None if the table isn't ordered
+    fn scan(&self, start: u64, len: usize) -> Option<usize>;
+}
+
+impl BenchTable for LPHashTable {
+    fn scan(&self, _start: u64, _len: usize) -> Option<usize> {
+        None
+    }
+}
+
+impl BenchTable for Bitcask {
+    fn scan(&self, _start: u64, _len: usize) -> Option<usize> {
+        None
+    }
+}
+
+impl BenchTable for LSMTree {
+    fn scan(&self, start: u64, len: usize) -> Option<usize> {
+        Some(self.range(start..).take(len).count())
+    }
+}
+
+impl BenchTable for BPlusTree {
+    fn scan(&self, start: u64, len: usize) -> Option<usize> {
+        Some(self.range(start..).take(len).count())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Lp,
+    Lsm,
+    Bitcask,
+    BTree,
+}
+
+impl Engine {
+    pub const ALL: [Engine; 4] = [Engine::Lp, Engine::Lsm, Engine::Bitcask, Engine::BTree];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Lp => "lp",
+            Engine::Lsm => "lsm",
+            Engine::Bitcask => "bitcask",
+            Engine::BTree => "btree",
+        }
+    }
+
+    pub fn is_ordered(self) -> bool {
+        matches!(self, Engine::Lsm | Engine::BTree)
+    }
+
+    // Opens the table with default options on its files in `dir`
+    pub fn open(self, dir: &str) -> Box<dyn BenchTable> {
+        fs::create_dir_all(dir).unwrap();
+        match self {
+            Engine::Lp => Box::new(LPHashTable::new(&LPHashTableOptions {
+                filename: format!("{}/lp.bin", dir),
+                ..Default::default()
+            })),
+            Engine::Lsm => Box::new(LSMTree::new(&LSMTreeOptions {
+                dir: format!("{}/lsm", dir),
+                ..Default::default()
+            })),
+            Engine::Bitcask => Box::new(Bitcask::new(&BitcaskOptions {
+                dir: format!("{}/bitcask", dir),
+                ..Default::default()
+            })),
+            Engine::BTree => Box::new(BPlusTree::new(&BPlusTreeOptions {
+                filename: format!("{}/btree.bin", dir),
+                ..Default::default()
+            })),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|engine| engine.name() == s)
+            .ok_or_else(|| format!("unknown engine {}", s))
+    }
+}
@@ -0,0 +1,221 @@
+use super::{
+    plot::{escape, Plot, Series},
+    stats::{Nanos, Report},
+    workload::OpKind,
+};
+use std::{collections::BTreeSet, fmt::Write};
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: right; }
+th:first-child, td:first-child { text-align: left; }
+svg { display: block; margin-bottom: 2em; }";
+
+// A self-contained HTML page comparing the reports: tables of throughput and
+// latency percentiles, and plots of latency CDFs, throughput over time and
+// size on disk, with a line per report in each plot
+pub fn html(reports: &[Report]) -> String {
+    // Reports of the same engine, say from different files, get numbered
+    let names = reports
+        .iter()
+        .enumerate()
+        .map(|(i, report)| {
+            let same_engine = reports.iter().filter(|r| r.engine == report.engine);
+            if same_engine.count() > 1 {
+                format!("{} #{}", report.engine, i + 1)
+            } else {
+                report.engine.clone()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>hasty benchmark report</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>hasty benchmark report</h1>",
+        STYLE
+    )
+    .unwrap();
+
+    html.push_str("<h2>Throughput</h2>\n<table>\n<tr><th>engine</th><th>workload</th><th>load ops/s</th><th>run ops/s</th><th>bytes on disk</th></tr>\n");
+    for (name, report) in names.iter().zip(reports) {
+        let load_ops_per_sec =
+            report.load_ops as f64 / report.load_time.as_secs_f64().max(f64::MIN_POSITIVE);
+        writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{:.0}</td><td>{:.0}</td><td>{}</td></tr>",
+            escape(name),
+            escape(&report.workload),
+            load_ops_per_sec,
+            report.throughput(),
+            report.sizes.last().map_or(0, |sample| sample.bytes)
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Latency percentiles</h2>\n<table>\n<tr><th>engine</th><th>op</th><th>count</th><th>mean</th><th>min</th><th>p50</th><th>p90</th><th>p95</th><th>p99</th><th>p99.9</th><th>max</th></tr>\n");
+    for (name, report) in names.iter().zip(reports) {
+        for (kind, latencies) in &report.latencies {
+            write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td>",
+                escape(name),
+                kind,
+                latencies.count
+            )
+            .unwrap();
+            for nanos in [
+                latencies.mean as u64,
+                latencies.min,
+                latencies.p50,
+                latencies.p90,
+                latencies.p95,
+                latencies.p99,
+                latencies.p999,
+                latencies.max,
+            ] {
+                write!(html, "<td>{}</td>", Nanos(nanos)).unwrap();
+            }
+            html.push_str("</tr>\n");
+        }
+    }
+    html.push_str("</table>\n");
+
+    html.push_str("<h2>Latency distributions</h2>\n");
+    let kinds = reports
+        .iter()
+        .flat_map(|report| report.latencies.keys().copied())
+        .collect::<BTreeSet<OpKind>>();
+    for kind in kinds {
+        let series = names
+            .iter()
+            .zip(reports)
+            .filter_map(|(name, report)| {
+                let latencies = report.latencies.get(&kind)?;
+                Some(Series {
+                    name: name.clone(),
+                    points: latencies
+                        .cdf
+                        .iter()
+                        .map(|&(fraction, nanos)| (nanos as f64 / 1000.0, fraction))
+                        .collect(),
+                })
+            })
+            .collect();
+        let plot = Plot {
+            title: format!("{} latency CDF", kind),
+            x_label: "latency (µs)".to_string(),
+            y_label: "fraction of operations".to_string(),
+            log_x: true,
+            series,
+        };
+        html.push_str(&plot.svg());
+    }
+
+    html.push_str("<h2>Throughput over time</h2>\n");
+    let series = names
+        .iter()
+        .zip(reports)
+        .map(|(name, report)| Series {
+            name: name.clone(),
+            points: report
+                .sizes
+                .windows(2)
+                .map(|samples| {
+                    let time = (samples[1].elapsed - samples[0].elapsed).as_secs_f64();
+                    let ops = (samples[1].ops - samples[0].ops) as f64;
+                    (
+                        samples[1].elapsed.as_secs_f64(),
+                        ops / time.max(f64::MIN_POSITIVE),
+                    )
+                })
+                .collect(),
+        })
+        .collect();
+    let plot = Plot {
+        title: "run throughput".to_string(),
+        x_label: "elapsed (s)".to_string(),
+        y_label: "ops/s".to_string(),
+        log_x: false,
+        series,
+    };
+    html.push_str(&plot.svg());
+
+    html.push_str("<h2>Size on disk</h2>\n");
+    for (title, x_label, load) in [
+        ("size on disk while loading", "keys", true),
+        ("size on disk during the run", "operations", false),
+    ] {
+        let series = names
+            .iter()
+            .zip(reports)
+            .map(|(name, report)| Series {
+                name: name.clone(),
+                points: if load {
+                    &report.load_sizes
+                } else {
+                    &report.sizes
+                }
+                .iter()
+                .map(|sample| (sample.ops as f64, sample.bytes as f64 / 1e6))
+                .collect(),
+            })
+            .collect();
+        let plot = Plot {
+            title: title.to_string(),
+            x_label: x_label.to_string(),
+            y_label: "MB".to_string(),
+            log_x: false,
+            series,
+        };
+        html.push_str(&plot.svg());
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::{self, Distribution, Engine, OpMix, Workload};
+    use std::fs;
+
+    #[test]
+    fn report_page() {
+        let workload = Workload {
+            distribution: Distribution::Uniform,
+            records: 500,
+            ops: 1000,
+            mix: OpMix {
+                read: 0.5,
+                write: 0.5,
+                ..Default::default()
+            },
+            seed: 5,
+        };
+        let dir = "bench_report_page";
+        let _ = fs::remove_dir_all(dir);
+        let mut reports = Vec::new();
+        for engine in [Engine::Lp, Engine::Lsm, Engine::Lsm] {
+            let mut table = engine.open(dir);
+            reports.push(bench::run(&mut *table, engine, &workload, 5));
+            drop(table);
+            fs::remove_dir_all(dir).unwrap();
+        }
+        // Reports survive a round trip through JSON, as hasty-report reads them
+        let json = serde_json::to_string(&reports).unwrap();
+        let reports = serde_json::from_str::<Vec<Report>>(&json).unwrap();
+
+        let html = html(&reports);
+        assert!(html.starts_with("<!DOCTYPE html>") && html.ends_with("</html>\n"));
+        // Nothing to fetch
+        assert!(!html.contains("src=") && !html.contains("<link"));
+        // Read and write CDFs, throughput and two size plots
+        assert_eq!(html.matches("<svg ").count(), 5);
+        assert_eq!(html.matches("<polyline").count(), 15);
+        assert!(html.contains(">lp</text>") && html.contains(">lsm #3</text>"));
+        assert!(html.contains("<td>lsm #2</td><td>write</td><td>"));
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt::Write;
+
+// Line charts as standalone SVG, one line per series with a legend on the
+// right. Values are expected to be non-negative.
+
+const COLORS: [&str; 8] = [
+    "#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#17becf",
+];
+const WIDTH: f64 = 720.0;
+const HEIGHT: f64 = 360.0;
+const LEFT: f64 = 70.0;
+const RIGHT: f64 = 150.0;
+const TOP: f64 = 30.0;
+const BOTTOM: f64 = 50.0;
+
+pub struct Series {
+    pub name: String,
+    pub points: Vec<(f64, f64)>,
+}
+
+pub struct Plot {
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub log_x: bool,
+    pub series: Vec<Series>,
+}
+
+struct Axis {
+    min: f64,
+    max: f64,
+    log: bool,
+}
+
+impl Axis {
+    fn new(values: impl Iterator<Item = f64>, log: bool) -> Self {
+        let values = values.filter(|v| v.is_finite() && (!log || *v > 0.0));
+        if log {
+            let (min, max) = values.fold((f64::MAX, f64::MIN), |(min, max), v| {
+                (min.min(v), max.max(v))
+            });
+            if min > max {
+                return Axis {
+                    min: 0.0,
+                    max: 1.0,
+                    log,
+                };
+            }
+            let min = min.log10().floor();
+            Axis {
+                min,
+                max: max.log10().ceil().max(min + 1.0),
+                log,
+            }
+        } else {
+            let max = values.fold(0.0, f64::max);
+            let step = step(max);
+            Axis {
+                min: 0.0,
+                max: ((max / step).ceil() * step).max(step),
+                log,
+            }
+        }
+    }
+
+    // Where `value` goes between 0 and 1
+    fn position(&self, value: f64) -> f64 {
+        let value = if self.log {
+            value.max(10f64.powf(self.min)).log10()
+        } else {
+            value
+        };
+        (value - self.min) / (self.max - self.min)
+    }
+
+    fn ticks(&self) -> Vec<f64> {
+        if self.log {
+            (self.min as i32..=self.max as i32)
+                .map(|exponent| 10f64.powi(exponent))
+                .collect()
+        } else {
+            let step = step(self.max);
+            (0..=(self.max / step).round() as usize)
+                .map(|i| i as f64 * step)
+                .collect()
+        }
+    }
+}
+
+// 1, 2 or 5 times a power of ten that splits 0..max in about 5 ticks
+fn step(max: f64) -> f64 {
+    if max <= 0.0 {
+        return 1.0;
+    }
+    let raw = max / 5.0;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .into_iter()
+        .map(|factor| factor * magnitude)
+        .find(|&step| step >= raw)
+        .unwrap()
+}
+
+fn label(value: f64) -> String {
+    for (unit, suffix) in [(1e9, "G"), (1e6, "M"), (1e3, "k")] {
+        if value >= unit {
+            return format!("{}{}", (value / unit * 100.0).round() / 100.0, suffix);
+        }
+    }
+    format!("{}", (value * 1000.0).round() / 1000.0)
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Plot {
+    pub fn svg(&self) -> String {
+        let points = || self.series.iter().flat_map(|series| &series.points);
+        let x_axis = Axis::new(points().map(|&(x, _)| x), self.log_x);
+        let y_axis = Axis::new(points().map(|&(_, y)| y), false);
+        let width = WIDTH - LEFT - RIGHT;
+        let height = HEIGHT - TOP - BOTTOM;
+        let x = |value| LEFT + x_axis.position(value) * width;
+        let y = |value| TOP + (1.0 - y_axis.position(value)) * height;
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#,
+            w = WIDTH,
+            h = HEIGHT
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="18" text-anchor="middle" font-weight="bold">{}</text>"#,
+            LEFT + width / 2.0,
+            escape(&self.title)
+        )
+        .unwrap();
+        for tick in x_axis.ticks() {
+            writeln!(
+                svg,
+                r##"<line x1="{x:.1}" y1="{TOP}" x2="{x:.1}" y2="{bottom}" stroke="#eee"/><text x="{x:.1}" y="{label_y}" text-anchor="middle">{label}</text>"##,
+                x = x(tick),
+                bottom = TOP + height,
+                label_y = TOP + height + 16.0,
+                label = label(tick)
+            )
+            .unwrap();
+        }
+        for tick in y_axis.ticks() {
+            writeln!(
+                svg,
+                r##"<line x1="{LEFT}" y1="{y:.1}" x2="{right}" y2="{y:.1}" stroke="#eee"/><text x="{label_x}" y="{y:.1}" text-anchor="end" dominant-baseline="middle">{label}</text>"##,
+                y = y(tick),
+                right = LEFT + width,
+                label_x = LEFT - 6.0,
+                label = label(tick)
+            )
+            .unwrap();
+        }
+        writeln!(
+            svg,
+            r##"<rect x="{LEFT}" y="{TOP}" width="{width}" height="{height}" fill="none" stroke="#999"/>"##
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="{}" y="{}" text-anchor="middle">{}</text>"#,
+            LEFT + width / 2.0,
+            HEIGHT - 10.0,
+            escape(&self.x_label)
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text transform="translate(16 {}) rotate(-90)" text-anchor="middle">{}</text>"#,
+            TOP + height / 2.0,
+            escape(&self.y_label)
+        )
+        .unwrap();
+
+        for (i, series) in self.series.iter().enumerate() {
+            let color = COLORS[i % COLORS.len()];
+            let points = series
+                .points
+                .iter()
+                .map(|&(px, py)| format!("{:.1},{:.1}", x(px), y(py)))
+                .collect::<Vec<_>>();
+            writeln!(
+                svg,
+                r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="1.5"/>"#,
+                points.join(" "),
+                color
+            )
+            .unwrap();
+            let legend_x = LEFT + width + 12.0;
+            let legend_y = TOP + 8.0 + 18.0 * i as f64;
+            writeln!(
+                svg,
+                r#"<line x1="{legend_x}" y1="{legend_y}" x2="{}" y2="{legend_y}" stroke="{color}" stroke-width="3"/><text x="{}" y="{legend_y}" dominant-baseline="middle">{}</text>"#,
+                legend_x + 20.0,
+                legend_x + 26.0,
+                escape(&series.name)
+            )
+            .unwrap();
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axes_and_svg() {
+        assert_eq!(step(1000.0), 200.0);
+        assert_eq!(step(7.0), 2.0);
+        let linear = Axis::new([3.0, 930.0].into_iter(), false);
+        assert_eq!((linear.min, linear.max), (0.0, 1000.0));
+        assert_eq!(linear.ticks().len(), 6);
+        let log = Axis::new([0.0, 0.5, 2500.0].into_iter(), true);
+        assert_eq!((log.min, log.max), (-1.0, 4.0));
+        assert_eq!(log.position(0.1), 0.0);
+        assert_eq!(log.position(10000.0), 1.0);
+        assert_eq!(label(2_500_000.0), "2.5M");
+        assert_eq!(label(0.01), "0.01");
+
+        let plot = Plot {
+            title: "a < b".to_string(),
+            x_label: "x".to_string(),
+            y_label: "y".to_string(),
+            log_x: false,
+            series: vec![
+                Series {
+                    name: "lp".to_string(),
+                    points: vec![(0.0, 1.0), (1.0, 2.0)],
+                },
+                Series {
+                    name: "lsm".to_string(),
+                    points: Vec::new(),
+                },
+            ],
+        };
+        let svg = plot.svg();
+        assert!(svg.starts_with("<svg ") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains("a &lt; b") && svg.contains(">lsm</text>"));
+    }
+}
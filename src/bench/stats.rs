@@ -0,0 +1,167 @@
+use super::workload::OpKind;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+// Percentiles of the latencies of one kind of operation, in nanoseconds
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Latencies {
+    pub count: usize,
+    pub mean: f64,
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+    // (fraction, latency) points of the distribution, for plotting
+    pub cdf: Vec<(f64, u64)>,
+}
+
+impl Latencies {
+    pub fn new(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Latencies::default();
+        }
+        samples.sort_unstable();
+        let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+        Latencies {
+            count: samples.len(),
+            mean: samples.iter().sum::<u64>() as f64 / samples.len() as f64,
+            min: samples[0],
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            p999: percentile(0.999),
+            max: *samples.last().unwrap(),
+            cdf: (0..100)
+                .map(|i| i as f64 / 100.0)
+                .chain([0.995, 0.999, 0.9999, 1.0])
+                .map(|p| (p, percentile(p)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SizeSample {
+    pub ops: usize,
+    pub elapsed: Duration,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub engine: String,
+    pub workload: String,
+    pub load_ops: usize,
+    pub load_time: Duration,
+    // Taken while loading, `ops` is the number of keys
+    pub load_sizes: Vec<SizeSample>,
+    pub ops: usize,
+    pub time: Duration,
+    // Only the kinds of operations the workload ran
+    pub latencies: BTreeMap<OpKind, Latencies>,
+    pub sizes: Vec<SizeSample>,
+}
+
+fn ops_per_sec(ops: usize, time: Duration) -> f64 {
+    ops as f64 / time.as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
+impl Report {
+    pub fn throughput(&self) -> f64 {
+        ops_per_sec(self.ops, self.time)
+    }
+}
+
+pub(super) struct Nanos(pub(super) u64);
+
+impl fmt::Display for Nanos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self.0 {
+            nanos if nanos < 10_000 => format!("{}ns", nanos),
+            nanos if nanos < 10_000_000 => format!("{}µs", nanos / 1000),
+            nanos => format!("{}ms", nanos / 1_000_000),
+        };
+        f.pad(&text)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.engine, self.workload)?;
+        for (phase, ops, time) in [
+            ("load", self.load_ops, self.load_time),
+            ("run", self.ops, self.time),
+        ] {
+            writeln!(
+                f,
+                "  {:<5}{:>10} ops in {:.2?} ({:.0} ops/s)",
+                phase,
+                ops,
+                time,
+                ops_per_sec(ops, time)
+            )?;
+        }
+        writeln!(
+            f,
+            "  {:<8}{:>10}{:>9}{:>9}{:>9}{:>9}{:>9}{:>9}",
+            "op", "count", "mean", "p50", "p90", "p99", "p99.9", "max"
+        )?;
+        for (op, latencies) in &self.latencies {
+            writeln!(
+                f,
+                "  {:<8}{:>10}{:>9}{:>9}{:>9}{:>9}{:>9}{:>9}",
+                op,
+                latencies.count,
+                Nanos(latencies.mean as u64),
+                Nanos(latencies.p50),
+                Nanos(latencies.p90),
+                Nanos(latencies.p99),
+                Nanos(latencies.p999),
+                Nanos(latencies.max)
+            )?;
+        }
+        writeln!(f, "  {:>10}{:>12}{:>14}", "ops", "elapsed", "bytes on disk")?;
+        for sample in &self.sizes {
+            writeln!(
+                f,
+                "  {:>10}{:>12.2?}{:>14}",
+                sample.ops, sample.elapsed, sample.bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let latencies = Latencies::new((1..=1000).rev().collect());
+        assert_eq!(latencies.count, 1000);
+        assert_eq!(latencies.mean, 500.5);
+        assert_eq!(latencies.min, 1);
+        assert_eq!(
+            (
+                latencies.p50,
+                latencies.p90,
+                latencies.p95,
+                latencies.p99,
+                latencies.p999
+            ),
+            (501, 900, 950, 990, 999)
+        );
+        assert_eq!(latencies.max, 1000);
+        assert_eq!(latencies.cdf[0], (0.0, 1));
+        assert_eq!(latencies.cdf[50], (0.5, 501));
+        assert_eq!(*latencies.cdf.last().unwrap(), (1.0, 1000));
+        assert!(latencies.cdf.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(Latencies::new(Vec::new()), Latencies::default());
+        assert_eq!(format!("{:>6}", Nanos(12_345)), "  12µs");
+    }
+}
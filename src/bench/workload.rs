@@ -0,0 +1,403 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+// Which keys the operations go to. Keys are record numbers, the first
+// `records` of them are loaded before the operations run and inserts add the
+// next ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Distribution {
+    Uniform,
+    // A few keys get most of the operations, spread over the key space
+    Zipfian,
+    // Keys one after the other, wrapping around
+    Sequential,
+    // The most recently inserted keys get most of the operations
+    Latest,
+}
+
+impl Distribution {
+    pub const ALL: [Distribution; 4] = [
+        Distribution::Uniform,
+        Distribution::Zipfian,
+        Distribution::Sequential,
+        Distribution::Latest,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Distribution::Uniform => "uniform",
+            Distribution::Zipfian => "zipfian",
+            Distribution::Sequential => "sequential",
+            Distribution::Latest => "latest",
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Distribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|distribution| distribution.name() == s)
+            .ok_or_else(|| format!("unknown distribution {}", s))
+    }
+}
+
+// Shares of the kinds of operations, they don't have to add up to 1. Writes
+// overwrite existing keys, inserts add new ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpMix {
+    pub read: f64,
+    pub write: f64,
+    pub insert: f64,
+    pub delete: f64,
+    pub scan: f64,
+    pub read_modify_write: f64,
+}
+
+impl OpMix {
+    fn shares(&self) -> [(OpKind, f64); 6] {
+        [
+            (OpKind::Read, self.read),
+            (OpKind::Write, self.write),
+            (OpKind::Insert, self.insert),
+            (OpKind::Delete, self.delete),
+            (OpKind::Scan, self.scan),
+            (OpKind::ReadModifyWrite, self.read_modify_write),
+        ]
+    }
+
+    fn total(&self) -> f64 {
+        self.shares().iter().map(|(_, share)| share).sum()
+    }
+}
+
+impl fmt::Display for OpMix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shares = self
+            .shares()
+            .into_iter()
+            .filter(|&(_, share)| share > 0.0)
+            .map(|(kind, share)| format!("{}% {}", (100.0 * share / self.total()).round(), kind))
+            .collect::<Vec<_>>();
+        f.write_str(&shares.join(", "))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum OpKind {
+    Read,
+    Write,
+    Insert,
+    Delete,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl OpKind {
+    pub const ALL: [OpKind; 6] = [
+        OpKind::Read,
+        OpKind::Write,
+        OpKind::Insert,
+        OpKind::Delete,
+        OpKind::Scan,
+        OpKind::ReadModifyWrite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OpKind::Read => "read",
+            OpKind::Write => "write",
+            OpKind::Insert => "insert",
+            OpKind::Delete => "delete",
+            OpKind::Scan => "scan",
+            OpKind::ReadModifyWrite => "rmw",
+        }
+    }
+}
+
+impl fmt::Display for OpKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub distribution: Distribution,
+    pub records: u64,
+    pub ops: usize,
+    pub mix: OpMix,
+    pub seed: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Get(u64),
+    Set(u64, u64),
+    Insert(u64, u64),
+    Remove(u64),
+    // Up to this many entries from the key on, in key order
+    Scan(u64, usize),
+    // Reads the key and writes the value back
+    ReadModifyWrite(u64, u64),
+}
+
+impl Op {
+    pub fn kind(&self) -> OpKind {
+        match self {
+            Op::Get(_) => OpKind::Read,
+            Op::Set(..) => OpKind::Write,
+            Op::Insert(..) => OpKind::Insert,
+            Op::Remove(_) => OpKind::Delete,
+            Op::Scan(..) => OpKind::Scan,
+            Op::ReadModifyWrite(..) => OpKind::ReadModifyWrite,
+        }
+    }
+}
+
+impl Workload {
+    pub const MAX_SCAN_LEN: usize = 100;
+
+    pub fn generate(&self) -> OpGenerator {
+        assert!(self.records > 0 && self.mix.total() > 0.0);
+        // As in YCSB, the zipfian distribution covers the keys expected to be
+        // inserted too and draws again when it hits one that isn't yet
+        let inserts = self.ops as f64 * self.mix.insert / self.mix.total();
+        let zipfian_keys = self.records + inserts as u64;
+        OpGenerator {
+            workload: self.clone(),
+            rng: StdRng::seed_from_u64(self.seed),
+            zipfian: Zipfian::new(zipfian_keys),
+            zipfian_keys,
+            next_sequential: 0,
+            inserted: self.records,
+            left: self.ops,
+        }
+    }
+}
+
+pub struct OpGenerator {
+    workload: Workload,
+    rng: StdRng,
+    zipfian: Zipfian,
+    zipfian_keys: u64,
+    next_sequential: u64,
+    // Keys below this one exist
+    inserted: u64,
+    left: usize,
+}
+
+impl OpGenerator {
+    fn existing_key(&mut self) -> u64 {
+        match self.workload.distribution {
+            Distribution::Uniform => self.rng.gen_range(0..self.inserted),
+            Distribution::Zipfian => loop {
+                let rank = self.zipfian.next(&mut self.rng, self.zipfian_keys);
+                let key = scramble(rank) % self.zipfian_keys;
+                if key < self.inserted {
+                    break key;
+                }
+            },
+            Distribution::Sequential => {
+                let key = self.next_sequential % self.inserted;
+                self.next_sequential = key + 1;
+                key
+            }
+            Distribution::Latest => {
+                self.inserted - 1 - self.zipfian.next(&mut self.rng, self.inserted)
+            }
+        }
+    }
+}
+
+impl Iterator for OpGenerator {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let mut choice = self.rng.gen::<f64>() * self.workload.mix.total();
+        let kind = self
+            .workload
+            .mix
+            .shares()
+            .into_iter()
+            .find(|&(_, share)| {
+                choice -= share;
+                choice < 0.0
+            })
+            .map_or(OpKind::Read, |(kind, _)| kind);
+        let op = match kind {
+            OpKind::Read => Op::Get(self.existing_key()),
+            OpKind::Write => Op::Set(self.existing_key(), self.rng.gen()),
+            OpKind::Insert => {
+                self.inserted += 1;
+                Op::Insert(self.inserted - 1, self.rng.gen())
+            }
+            OpKind::Delete => Op::Remove(self.existing_key()),
+            OpKind::Scan => {
+                let len = self.rng.gen_range(1..=Workload::MAX_SCAN_LEN);
+                Op::Scan(self.existing_key(), len)
+            }
+            OpKind::ReadModifyWrite => Op::ReadModifyWrite(self.existing_key(), self.rng.gen()),
+        };
+        Some(op)
+    }
+}
+
+// Spreads the hot ranks of the zipfian distribution over the key space
+fn scramble(rank: u64) -> u64 {
+    let mut x = rank.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// Ranks in [0, n) where rank 0 is the most popular, after "Quickly
+// Generating Billion-Record Synthetic Databases" by Gray et al., as in YCSB.
+// The item count can grow between calls.
+struct Zipfian {
+    n: u64,
+    zetan: f64,
+}
+
+impl Zipfian {
+    const THETA: f64 = 0.99;
+
+    fn new(n: u64) -> Self {
+        let mut zipfian = Zipfian { n: 0, zetan: 0.0 };
+        zipfian.grow(n);
+        zipfian
+    }
+
+    fn grow(&mut self, n: u64) {
+        for i in self.n + 1..=n {
+            self.zetan += 1.0 / (i as f64).powf(Self::THETA);
+        }
+        self.n = n;
+    }
+
+    fn next(&mut self, rng: &mut StdRng, n: u64) -> u64 {
+        if n > self.n {
+            self.grow(n);
+        }
+        let theta = Self::THETA;
+        let zeta2 = 1.0 + 0.5f64.powf(theta);
+        let alpha = 1.0 / (1.0 - theta);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / self.zetan);
+        let u = rng.gen::<f64>();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < zeta2 {
+            return 1.min(n - 1);
+        }
+        ((n as f64 * (eta * u - eta + 1.0).powf(alpha)) as u64).min(n - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn workload(distribution: Distribution, mix: OpMix) -> Workload {
+        Workload {
+            distribution,
+            records: 1000,
+            ops: 100000,
+            mix,
+            seed: 7,
+        }
+    }
+
+    fn key(op: Op) -> u64 {
+        match op {
+            Op::Get(key)
+            | Op::Set(key, _)
+            | Op::Insert(key, _)
+            | Op::Remove(key)
+            | Op::Scan(key, _)
+            | Op::ReadModifyWrite(key, _) => key,
+        }
+    }
+
+    #[test]
+    fn distributions() {
+        let reads = OpMix {
+            read: 1.0,
+            ..Default::default()
+        };
+        let counts = |distribution| {
+            let mut counts = HashMap::new();
+            for op in workload(distribution, reads).generate() {
+                assert!(key(op) < 1000);
+                *counts.entry(key(op)).or_insert(0) += 1;
+            }
+            let mut counts = counts.into_values().collect::<Vec<usize>>();
+            counts.sort_unstable_by(|a, b| b.cmp(a));
+            counts
+        };
+        // The hottest key of a zipfian distribution over 1000 keys gets
+        // about 13% of the operations
+        let zipfian = counts(Distribution::Zipfian);
+        assert!(zipfian[0] > 10000 && zipfian[0] < 16000, "{}", zipfian[0]);
+        assert!(counts(Distribution::Uniform)[0] < 200);
+        assert!(workload(Distribution::Sequential, reads)
+            .generate()
+            .take(2000)
+            .map(key)
+            .eq((0..1000).chain(0..1000)));
+
+        let generated = workload(Distribution::Zipfian, reads).generate();
+        assert!(generated.eq(workload(Distribution::Zipfian, reads).generate()));
+    }
+
+    #[test]
+    fn mix_and_latest() {
+        let mix = OpMix {
+            read: 2.0,
+            insert: 1.0,
+            delete: 1.0,
+            ..Default::default()
+        };
+        let ops = workload(Distribution::Latest, mix)
+            .generate()
+            .collect::<Vec<_>>();
+        let inserts = ops.iter().filter(|op| matches!(op, Op::Insert(..))).count();
+        let removes = ops.iter().filter(|op| matches!(op, Op::Remove(_))).count();
+        assert!((24000..26000).contains(&inserts));
+        assert!((24000..26000).contains(&removes));
+        // New keys are appended and reads mostly hit the last ones
+        let mut inserted = 1000;
+        let mut recent = 0;
+        for op in ops {
+            match op {
+                Op::Insert(key, _) => {
+                    assert_eq!(key, inserted);
+                    inserted += 1;
+                }
+                Op::Get(key) => {
+                    assert!(key < inserted);
+                    recent += (inserted - key <= 100) as usize;
+                }
+                Op::Remove(_) => {}
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+        assert!(recent > 20000, "{}", recent);
+        assert_eq!(mix.to_string(), "50% read, 25% insert, 25% delete");
+    }
+}
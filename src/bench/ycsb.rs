@@ -0,0 +1,187 @@
+use super::{
+    stats::Report,
+    workload::{Distribution, OpKind, OpMix},
+};
+use std::{fmt, str::FromStr};
+
+// The core workloads of YCSB. Its keys are hashed strings, ours are record
+// numbers that the zipfian distribution scrambles over the key space the same
+// way. Updates are writes of a whole value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum YcsbWorkload {
+    // Update heavy, 50% reads and 50% updates
+    A,
+    // Read mostly, 95% reads and 5% updates
+    B,
+    // Read only
+    C,
+    // Read latest, 95% reads of recent keys and 5% inserts
+    D,
+    // Short ranges, 95% scans of up to 100 entries and 5% inserts
+    E,
+    // Read-modify-write, 50% reads and 50% read-modify-writes
+    F,
+}
+
+impl YcsbWorkload {
+    pub const ALL: [YcsbWorkload; 6] = [
+        YcsbWorkload::A,
+        YcsbWorkload::B,
+        YcsbWorkload::C,
+        YcsbWorkload::D,
+        YcsbWorkload::E,
+        YcsbWorkload::F,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            YcsbWorkload::A => "a",
+            YcsbWorkload::B => "b",
+            YcsbWorkload::C => "c",
+            YcsbWorkload::D => "d",
+            YcsbWorkload::E => "e",
+            YcsbWorkload::F => "f",
+        }
+    }
+
+    pub fn distribution(self) -> Distribution {
+        match self {
+            YcsbWorkload::D => Distribution::Latest,
+            _ => Distribution::Zipfian,
+        }
+    }
+
+    pub fn mix(self) -> OpMix {
+        let (read, write, insert, scan, read_modify_write) = match self {
+            YcsbWorkload::A => (0.5, 0.5, 0.0, 0.0, 0.0),
+            YcsbWorkload::B => (0.95, 0.05, 0.0, 0.0, 0.0),
+            YcsbWorkload::C => (1.0, 0.0, 0.0, 0.0, 0.0),
+            YcsbWorkload::D => (0.95, 0.0, 0.05, 0.0, 0.0),
+            YcsbWorkload::E => (0.0, 0.0, 0.05, 0.95, 0.0),
+            YcsbWorkload::F => (0.5, 0.0, 0.0, 0.0, 0.5),
+        };
+        OpMix {
+            read,
+            write,
+            insert,
+            delete: 0.0,
+            scan,
+            read_modify_write,
+        }
+    }
+}
+
+impl fmt::Display for YcsbWorkload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for YcsbWorkload {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|workload| workload.name() == s.trim_start_matches("workload"))
+            .ok_or_else(|| format!("unknown YCSB workload {}", s))
+    }
+}
+
+fn ycsb_name(kind: OpKind) -> &'static str {
+    match kind {
+        OpKind::Read => "READ",
+        OpKind::Write => "UPDATE",
+        OpKind::Insert => "INSERT",
+        OpKind::Delete => "DELETE",
+        OpKind::Scan => "SCAN",
+        OpKind::ReadModifyWrite => "READ-MODIFY-WRITE",
+    }
+}
+
+// The run phase of a report in the output format of YCSB's measurements, so
+// that the numbers line up with published ones
+pub struct YcsbReport<'a>(pub &'a Report);
+
+impl fmt::Display for YcsbReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let report = self.0;
+        let micros = |nanos: f64| nanos / 1000.0;
+        writeln!(f, "# {}: {}", report.engine, report.workload)?;
+        writeln!(f, "[OVERALL], RunTime(ms), {}", report.time.as_millis())?;
+        writeln!(f, "[OVERALL], Throughput(ops/sec), {}", report.throughput())?;
+        for (&kind, latencies) in &report.latencies {
+            let name = ycsb_name(kind);
+            writeln!(f, "[{}], Operations, {}", name, latencies.count)?;
+            writeln!(
+                f,
+                "[{}], AverageLatency(us), {}",
+                name,
+                micros(latencies.mean)
+            )?;
+            for (metric, nanos) in [
+                ("MinLatency", latencies.min),
+                ("MaxLatency", latencies.max),
+                ("95thPercentileLatency", latencies.p95),
+                ("99thPercentileLatency", latencies.p99),
+            ] {
+                writeln!(f, "[{}], {}(us), {}", name, metric, nanos / 1000)?;
+            }
+            writeln!(f, "[{}], Return=OK, {}", name, latencies.count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::{self, Engine, Workload};
+    use std::fs;
+
+    #[test]
+    fn core_workloads() {
+        let dir = "bench_core_workloads";
+        let _ = fs::remove_dir_all(dir);
+        for ycsb in YcsbWorkload::ALL {
+            assert_eq!(ycsb.name().parse(), Ok(ycsb));
+            let workload = Workload {
+                distribution: ycsb.distribution(),
+                records: 1000,
+                ops: 2000,
+                mix: ycsb.mix(),
+                seed: 3,
+            };
+            let mut table = Engine::Lsm.open(dir);
+            let report = bench::run(&mut *table, Engine::Lsm, &workload, 1);
+            drop(table);
+            fs::remove_dir_all(dir).unwrap();
+
+            let counts = report
+                .latencies
+                .iter()
+                .map(|(&kind, latencies)| (kind, latencies.count))
+                .collect::<Vec<_>>();
+            assert_eq!(counts.iter().map(|(_, count)| count).sum::<usize>(), 2000);
+            let kinds = counts.iter().map(|&(kind, _)| kind).collect::<Vec<_>>();
+            let expected = match ycsb {
+                YcsbWorkload::A | YcsbWorkload::B => vec![OpKind::Read, OpKind::Write],
+                YcsbWorkload::C => vec![OpKind::Read],
+                YcsbWorkload::D => vec![OpKind::Read, OpKind::Insert],
+                YcsbWorkload::E => vec![OpKind::Insert, OpKind::Scan],
+                YcsbWorkload::F => vec![OpKind::Read, OpKind::ReadModifyWrite],
+            };
+            assert_eq!(kinds, expected, "workload {}", ycsb);
+
+            let output = YcsbReport(&report).to_string();
+            assert!(output.contains("[OVERALL], Throughput(ops/sec), "));
+            for (kind, count) in counts {
+                let name = ycsb_name(kind);
+                assert!(output.contains(&format!("[{}], Operations, {}\n", name, count)));
+                assert!(output.contains(&format!("[{}], 95thPercentileLatency(us), ", name)));
+            }
+        }
+        assert_eq!("workloadE".parse(), Ok(YcsbWorkload::E));
+    }
+}
@@ -0,0 +1,110 @@
+use clap::Parser;
+use hasty::bench::{self, Distribution, Engine, OpMix, Workload, YcsbReport, YcsbWorkload};
+use std::fs;
+
+/// Runs a generated workload against the tables and reports throughput,
+/// latency percentiles and the size on disk over time
+#[derive(Parser)]
+struct Args {
+    /// Engines to run, comma separated: lp, lsm, bitcask, btree
+    #[arg(long, value_delimiter = ',', default_value = "lp,lsm,bitcask,btree")]
+    engines: Vec<Engine>,
+    /// YCSB core workload, a to f, in place of the distribution and shares
+    /// of operations below
+    #[arg(long)]
+    workload: Option<YcsbWorkload>,
+    /// Key distribution: uniform, zipfian, sequential or latest
+    #[arg(long, default_value = "uniform")]
+    distribution: Distribution,
+    /// Records loaded before the run
+    #[arg(long, default_value_t = 100_000)]
+    records: u64,
+    /// Operations of the run
+    #[arg(long, default_value_t = 1_000_000)]
+    ops: usize,
+    /// Share of reads
+    #[arg(long, default_value_t = 0.5)]
+    read: f64,
+    /// Share of writes
+    #[arg(long, default_value_t = 0.5)]
+    write: f64,
+    /// Share of inserts of new keys
+    #[arg(long, default_value_t = 0.0)]
+    insert: f64,
+    /// Share of deletes
+    #[arg(long, default_value_t = 0.0)]
+    delete: f64,
+    /// Share of scans of up to 100 entries, only the ordered engines run them
+    #[arg(long, default_value_t = 0.0)]
+    scan: f64,
+    /// Share of read-modify-writes
+    #[arg(long, default_value_t = 0.0)]
+    rmw: f64,
+    /// Seed of the workload, random by default
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Measurements of the size on disk during the run
+    #[arg(long, default_value_t = 10)]
+    size_samples: usize,
+    /// Where the tables are created, it is emptied before every engine runs
+    #[arg(long, default_value = "hasty-bench")]
+    dir: String,
+    /// Print the results in YCSB's output format
+    #[arg(long)]
+    ycsb: bool,
+    /// Save the results as JSON, for hasty-report
+    #[arg(long)]
+    json: Option<String>,
+    /// Write an HTML report of the results
+    #[arg(long)]
+    html: Option<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let (distribution, mix) = match args.workload {
+        Some(ycsb) => (ycsb.distribution(), ycsb.mix()),
+        None => (
+            args.distribution,
+            OpMix {
+                read: args.read,
+                write: args.write,
+                insert: args.insert,
+                delete: args.delete,
+                scan: args.scan,
+                read_modify_write: args.rmw,
+            },
+        ),
+    };
+    let workload = Workload {
+        distribution,
+        records: args.records,
+        ops: args.ops,
+        mix,
+        seed: args.seed.unwrap_or_else(rand::random),
+    };
+    let mut reports = Vec::new();
+    for engine in args.engines {
+        if mix.scan > 0.0 && !engine.is_ordered() {
+            eprintln!("skipping {}, it can't scan", engine);
+            continue;
+        }
+        let _ = fs::remove_dir_all(&args.dir);
+        let mut table = engine.open(&args.dir);
+        let report = bench::run(&mut *table, engine, &workload, args.size_samples);
+        drop(table);
+        if args.ycsb {
+            println!("{}", YcsbReport(&report));
+        } else {
+            println!("{}", report);
+        }
+        reports.push(report);
+    }
+    if let Some(path) = &args.json {
+        fs::write(path, serde_json::to_string_pretty(&reports).unwrap()).unwrap();
+    }
+    if let Some(path) = &args.html {
+        fs::write(path, bench::html(&reports)).unwrap();
+    }
+    let _ = fs::remove_dir_all(&args.dir);
+}
@@ -0,0 +1,45 @@
+use clap::Parser;
+use hasty::bench::Engine;
+use hasty::trace::{self, Speed, TraceReader};
+use std::fs;
+
+/// Replays a trace recorded with RecordingTable against the engines
+#[derive(Parser)]
+struct Args {
+    /// Trace file
+    trace: String,
+    /// Engines to run, comma separated: lp, lsm, bitcask, btree
+    #[arg(long, value_delimiter = ',', default_value = "lp,lsm")]
+    engines: Vec<Engine>,
+    /// Keep the recorded time between operations instead of going as fast as
+    /// possible
+    #[arg(long)]
+    original_speed: bool,
+    /// Where the tables are created, it is emptied before every engine runs
+    #[arg(long, default_value = "hasty-replay")]
+    dir: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    let speed = if args.original_speed {
+        Speed::Original
+    } else {
+        Speed::Max
+    };
+    for engine in args.engines {
+        let _ = fs::remove_dir_all(&args.dir);
+        let mut table = engine.open(&args.dir);
+        let trace = TraceReader::open(&args.trace).unwrap();
+        let stats = trace::replay(trace, &mut *table, speed).unwrap();
+        println!(
+            "{}: {} ops in {:.2?} ({:.0} ops/s), {} bytes on disk",
+            engine,
+            stats.ops,
+            stats.elapsed,
+            stats.ops as f64 / stats.elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+            table.on_disk_size()
+        );
+    }
+    let _ = fs::remove_dir_all(&args.dir);
+}
@@ -0,0 +1,27 @@
+use clap::Parser;
+use hasty::bench::{self, Report};
+use std::fs;
+
+/// Turns results saved by hasty-bench --json into a self-contained HTML
+/// report with latency CDFs, percentile tables, throughput over time and the
+/// size on disk, comparing every engine of every file
+#[derive(Parser)]
+struct Args {
+    /// JSON files written by hasty-bench
+    #[arg(required = true)]
+    results: Vec<String>,
+    /// Where the report goes
+    #[arg(short, long, default_value = "hasty-report.html")]
+    output: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut reports = Vec::new();
+    for path in &args.results {
+        let json = fs::read_to_string(path).unwrap();
+        reports.extend(serde_json::from_str::<Vec<Report>>(&json).unwrap());
+    }
+    fs::write(&args.output, bench::html(&reports)).unwrap();
+    println!("{} reports written to {}", reports.len(), args.output);
+}
@@ -0,0 +1,267 @@
+use clap::{Parser, Subcommand};
+use hasty::export::{self, Format};
+use hasty::fsck::FsckReport;
+use hasty::hash_table::HashTable;
+use hasty::linear_probing::{LPHashTable, LPHashTableOptions};
+use hasty::lsmt::{LSMTree, LSMTreeOptions};
+use std::{
+    fs,
+    io::{self, BufRead, BufWriter, Write},
+    path::Path,
+    process,
+};
+
+/// Looks inside and edits an LPHashTable file or an LSMTree directory
+#[derive(Parser)]
+struct Args {
+    /// Table file of an LPHashTable or directory of an LSMTree
+    path: String,
+    /// Engine of a store that doesn't exist yet: lp or lsm. Existing stores
+    /// are recognized by their path
+    #[arg(long)]
+    engine: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the value of a key
+    Get { key: u64 },
+    /// Sets a key to a value
+    Set { key: u64, value: u64 },
+    /// Removes a key
+    Remove { key: u64 },
+    /// Prints the entries with keys in a range, in key order
+    Scan {
+        /// First key of the range
+        #[arg(long, default_value_t = 0)]
+        from: u64,
+        /// Last key of the range
+        #[arg(long, default_value_t = u64::MAX)]
+        to: u64,
+        /// Prints at most this many entries
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Prints the layout of the store
+    Stats,
+    /// Writes every entry, in no particular order
+    Dump {
+        /// text ("key value [expires_at]" lines), jsonl, csv or bin
+        #[arg(long, default_value = "text")]
+        format: Format,
+        /// Writes to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Sets the entries of a dump from a file, or stdin
+    Load {
+        file: Option<String>,
+        /// text ("key value [expires_at]" lines), jsonl, csv or bin
+        #[arg(long, default_value = "text")]
+        format: Format,
+    },
+    /// Checks the store without opening it, exits with 1 on problems
+    Fsck {
+        /// Rebuilds what can be rebuilt, keeping damaged files as *.corrupt
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
+enum Store {
+    Lp(LPHashTable),
+    Lsm(LSMTree),
+}
+
+impl Store {
+    fn open(path: &str, engine: Option<&str>) -> Store {
+        let engine = match (Path::new(path), engine) {
+            (path, _) if path.is_dir() => "lsm",
+            (path, _) if path.is_file() => "lp",
+            (_, Some(engine)) => engine,
+            (_, None) => fail(format!(
+                "{} doesn't exist, pick an --engine to create it",
+                path
+            )),
+        };
+        match engine {
+            "lp" => Store::Lp(LPHashTable::new(&LPHashTableOptions {
+                filename: path.to_string(),
+                ..Default::default()
+            })),
+            "lsm" => {
+                fs::create_dir_all(path).unwrap();
+                Store::Lsm(LSMTree::new(&LSMTreeOptions {
+                    dir: path.to_string(),
+                    ..Default::default()
+                }))
+            }
+            engine => fail(format!("unknown engine {}", engine)),
+        }
+    }
+
+    fn table(&mut self) -> &mut dyn HashTable {
+        match self {
+            Store::Lp(table) => table,
+            Store::Lsm(tree) => tree,
+        }
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("hasty: {}", message);
+    process::exit(1);
+}
+
+fn fsck(path: &str, repair: bool) -> FsckReport {
+    let path = Path::new(path);
+    if path.is_dir() {
+        LSMTree::fsck(
+            &LSMTreeOptions {
+                dir: path.to_str().unwrap().to_string(),
+                ..Default::default()
+            },
+            repair,
+        )
+    } else if path.is_file() {
+        LPHashTable::fsck(
+            &LPHashTableOptions {
+                filename: path.to_str().unwrap().to_string(),
+                ..Default::default()
+            },
+            repair,
+        )
+    } else {
+        fail(format!("{} doesn't exist", path.display()))
+    }
+}
+
+fn print_stats(store: &Store) {
+    match store {
+        Store::Lp(table) => {
+            let stats = table.stats();
+            println!("engine          lp");
+            println!("entries         {}", stats.len);
+            println!("capacity        {}", stats.capacity);
+            println!("used capacity   {}", stats.used_capacity);
+            println!(
+                "load            {:.3} (grows over {})",
+                stats.load(),
+                stats.load_factor
+            );
+            println!("bytes on disk   {}", table.on_disk_size());
+            let total = stats.probe_lengths.iter().sum::<usize>().max(1);
+            let mean = stats
+                .probe_lengths
+                .iter()
+                .enumerate()
+                .map(|(length, count)| length * count)
+                .sum::<usize>() as f64
+                / total as f64;
+            println!(
+                "probe lengths   mean {:.2}, max {}",
+                mean,
+                stats.probe_lengths.len().saturating_sub(1)
+            );
+            for (length, &count) in stats.probe_lengths.iter().enumerate() {
+                if count > 0 {
+                    println!(
+                        "  {:>6}{:>12}{:>8.2}%",
+                        length,
+                        count,
+                        100.0 * count as f64 / total as f64
+                    );
+                }
+            }
+        }
+        Store::Lsm(tree) => {
+            let stats = tree.stats();
+            println!("engine            lsm");
+            println!("memtable entries  {}", stats.memtable_entries);
+            println!("wal bytes         {}", stats.wal_bytes);
+            println!("bytes on disk     {}", tree.on_disk_size());
+            println!("disktables        {}, oldest first", stats.disktables.len());
+            for disktable in &stats.disktables {
+                println!(
+                    "  {:<24}{:>12} entries{:>14} bytes",
+                    disktable.filename, disktable.entries, disktable.bytes
+                );
+            }
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    // Opening a store replays and truncates its logs, fsck looks first
+    if let Command::Fsck { repair } = args.command {
+        let report = fsck(&args.path, repair);
+        print!("{}", report);
+        if report.is_ok() {
+            return;
+        }
+        if repair {
+            let report = fsck(&args.path, false);
+            print!("after the repair: {}", report);
+            if report.is_ok() {
+                return;
+            }
+        }
+        process::exit(1);
+    }
+    let mut store = Store::open(&args.path, args.engine.as_deref());
+    let mut out = BufWriter::new(io::stdout().lock());
+    match args.command {
+        Command::Get { key } => match store.table().get(key) {
+            Some(value) => writeln!(out, "{}", value).unwrap(),
+            None => fail(format!("{} not found", key)),
+        },
+        Command::Set { key, value } => store.table().set(key, value),
+        Command::Remove { key } => store.table().remove(key),
+        Command::Scan { from, to, limit } => {
+            let entries: Box<dyn Iterator<Item = (u64, u64)>> = match &store {
+                Store::Lsm(tree) => Box::new(tree.range(from..=to)),
+                // Hash order is no order, the range is sorted first
+                Store::Lp(table) => {
+                    let mut entries = table
+                        .iter()
+                        .filter(|(key, _)| (from..=to).contains(key))
+                        .collect::<Vec<_>>();
+                    entries.sort_unstable();
+                    Box::new(entries.into_iter())
+                }
+            };
+            for (key, value) in entries.take(limit.unwrap_or(usize::MAX)) {
+                writeln!(out, "{} {}", key, value).unwrap();
+            }
+        }
+        Command::Stats => print_stats(&store),
+        Command::Dump { format, output } => {
+            let out: Box<dyn Write> = match output {
+                Some(output) => Box::new(BufWriter::new(
+                    fs::File::create(&output)
+                        .unwrap_or_else(|err| fail(format!("can't create {}: {}", output, err))),
+                )),
+                None => Box::new(out),
+            };
+            let dumped = export::export(store.table(), format, out)
+                .unwrap_or_else(|err| fail(err.to_string()));
+            eprintln!("dumped {} entries", dumped);
+        }
+        Command::Fsck { .. } => unreachable!(),
+        Command::Load { file, format } => {
+            let input: Box<dyn BufRead> = match file {
+                Some(file) => Box::new(io::BufReader::new(
+                    fs::File::open(&file)
+                        .unwrap_or_else(|err| fail(format!("can't open {}: {}", file, err))),
+                )),
+                None => Box::new(io::stdin().lock()),
+            };
+            let loaded = export::import(input, format, store.table())
+                .unwrap_or_else(|err| fail(err.to_string()));
+            eprintln!("loaded {} entries", loaded);
+        }
+    }
+}
@@ -0,0 +1,592 @@
+// Log-structured hash table: every write is appended to the active data file
+// and an in-memory index points at the latest version of each key. Full data
+// files become immutable and get a hint file with their index entries, so that
+// opening the table doesn't have to read the data. Compaction rewrites the
+// live entries of the immutable files and drops the rest.
+
+use crate::clock::{Clock, SystemClock};
+use crate::hash_table::HashTable;
+use crate::merge::MergeOperator;
+use crate::record_log::{self, RecordLog};
+use crate::storage::{self, FsStorage, OpenMode, Storage, StorageFile};
+use crate::write_batch::{BatchOp, WriteBatch};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+const MANIFEST_FILENAME: &str = "MANIFEST";
+
+// The record holding the latest version of a key and the position of the
+// operation in its batch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location {
+    file: u64,
+    offset: u64,
+    op: u32,
+}
+
+// (key, Some((record offset, operation)) for sets and None for removes), in
+// the order they were written
+type Hint = Vec<(u64, Option<(u64, u32)>)>;
+
+// Data files of the table, oldest first. The last one is the active file.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    files: Vec<u64>,
+}
+
+impl Manifest {
+    // None for a directory without a manifest, any other failure is an error
+    fn read(storage: &dyn Storage, dir: &str) -> io::Result<Option<Self>> {
+        let bytes = match storage::read_file(storage, &format!("{}/{}", dir, MANIFEST_FILENAME)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        bincode::DefaultOptions::new()
+            .deserialize(&bytes)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn write(&self, storage: &dyn Storage, dir: &str) {
+        let bytes = bincode::DefaultOptions::new().serialize(self).unwrap();
+        storage::write_atomically(storage, &format!("{}/{}", dir, MANIFEST_FILENAME), &bytes)
+            .unwrap();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BitcaskOptions {
+    pub dir: String,
+    // The active data file is closed and a new one started past this size
+    pub max_file_size: usize,
+    // fsync the active data file after every write
+    pub sync: bool,
+    pub merge_operator: MergeOperator,
+    pub clock: Arc<dyn Clock>,
+    pub storage: Arc<dyn Storage>,
+}
+
+impl Default for BitcaskOptions {
+    fn default() -> Self {
+        BitcaskOptions {
+            dir: "bitcask".to_string(),
+            max_file_size: 16 * 1024 * 1024,
+            sync: false,
+            merge_operator: MergeOperator::default(),
+            clock: Arc::new(SystemClock),
+            storage: Arc::new(FsStorage),
+        }
+    }
+}
+
+pub struct Bitcask {
+    options: BitcaskOptions,
+    index: HashMap<u64, Location>,
+    files: Vec<u64>,
+    readers: HashMap<u64, Box<dyn StorageFile>>,
+    active: RecordLog,
+    next_file: u64,
+    // Operations in each data file that no key points at anymore
+    dead: HashMap<u64, usize>,
+}
+
+impl Bitcask {
+    pub fn new(options: &BitcaskOptions) -> Self {
+        let dir = &options.dir;
+        let storage = &*options.storage;
+        storage.create_dir_all(dir).unwrap();
+        // The files of a table are removed unless the manifest lists them,
+        // so a table is never opened from a manifest that couldn't be read
+        let manifest = match Manifest::read(storage, dir) {
+            Ok(Some(manifest)) => manifest,
+            // A new table writes its manifest before its first data file, so
+            // data files without one are what is left of a table that lost it
+            Ok(None) => {
+                let filenames = storage.read_dir(dir).unwrap();
+                if let Some(filename) = filenames
+                    .iter()
+                    .find(|filename| filename.ends_with(".data"))
+                {
+                    panic!("{} has {} but no manifest", dir, filename);
+                }
+                let manifest = Manifest { files: vec![0] };
+                manifest.write(storage, dir);
+                manifest
+            }
+            Err(err) => panic!("can't read the manifest of {}: {}", dir, err),
+        };
+
+        // Leftovers of rotations and compactions interrupted by a crash
+        for filename in storage.read_dir(dir).unwrap() {
+            let (id, extension) = filename.split_once('.').unwrap_or_default();
+            let Ok(id) = id.parse::<u64>() else {
+                continue;
+            };
+            let is_leftover = extension.ends_with(".tmp") || !manifest.files.contains(&id);
+            if matches!(extension, "data" | "hint" | "data.tmp" | "hint.tmp") && is_leftover {
+                storage.remove(&format!("{}/{}", dir, filename)).unwrap();
+            }
+        }
+
+        let active_id = *manifest.files.last().unwrap();
+        let (active, _) = RecordLog::open(storage, &Self::data_path(dir, active_id)).unwrap();
+        let mut table = Bitcask {
+            options: options.clone(),
+            index: HashMap::new(),
+            files: manifest.files.clone(),
+            readers: HashMap::new(),
+            active,
+            next_file: manifest.files.iter().max().unwrap() + 1,
+            dead: HashMap::new(),
+        };
+        for &id in &manifest.files {
+            let reader = storage
+                .open(&Self::data_path(dir, id), OpenMode::Existing)
+                .unwrap();
+            table.readers.insert(id, reader);
+            let hint = match storage::read_file(storage, &Self::hint_path(dir, id)) {
+                Ok(bytes) if id != active_id => {
+                    bincode::DefaultOptions::new().deserialize(&bytes).unwrap()
+                }
+                _ => table.read_hint(id),
+            };
+            for (key, location) in hint {
+                table.index_op(id, key, location);
+            }
+        }
+        table
+    }
+
+    fn data_path(dir: &str, id: u64) -> String {
+        format!("{}/{:08}.data", dir, id)
+    }
+
+    fn hint_path(dir: &str, id: u64) -> String {
+        format!("{}/{:08}.hint", dir, id)
+    }
+
+    // Builds the hint of a data file from its records
+    fn read_hint(&self, id: u64) -> Hint {
+        let path = Self::data_path(&self.options.dir, id);
+        let (_, records) = RecordLog::open_with_offsets(&*self.options.storage, &path).unwrap();
+        let mut hint = Hint::new();
+        for (offset, record) in records {
+            let batch = WriteBatch::deserialize(&record).unwrap();
+            for (i, op) in batch.ops().iter().enumerate() {
+                let location = match op {
+                    BatchOp::Set { .. } => Some((offset, i as u32)),
+                    _ => None,
+                };
+                hint.push((op.key(), location));
+            }
+        }
+        hint
+    }
+
+    fn write_hint(&self, id: u64) {
+        let hint = self.read_hint(id);
+        let bytes = bincode::DefaultOptions::new().serialize(&hint).unwrap();
+        let path = Self::hint_path(&self.options.dir, id);
+        storage::write_atomically(&*self.options.storage, &path, &bytes).unwrap();
+    }
+
+    fn write_manifest(&self) {
+        let manifest = Manifest {
+            files: self.files.clone(),
+        };
+        manifest.write(&*self.options.storage, &self.options.dir);
+    }
+
+    fn active_id(&self) -> u64 {
+        *self.files.last().unwrap()
+    }
+
+    // Points the key at its new version, or drops it for a remove. The
+    // version it replaces and the tombstone itself are dead weight.
+    fn index_op(&mut self, file: u64, key: u64, location: Option<(u64, u32)>) {
+        let old = match location {
+            Some((offset, op)) => self.index.insert(key, Location { file, offset, op }),
+            None => {
+                *self.dead.entry(file).or_default() += 1;
+                self.index.remove(&key)
+            }
+        };
+        if let Some(old) = old {
+            *self.dead.entry(old.file).or_default() += 1;
+        }
+    }
+
+    fn read_entry(&self, key: u64) -> Option<(u64, Option<u64>)> {
+        let location = self.index.get(&key)?;
+        let record =
+            record_log::read_record(&*self.readers[&location.file], location.offset).unwrap();
+        let batch = WriteBatch::deserialize(&record).unwrap();
+        match batch.ops()[location.op as usize] {
+            BatchOp::Set {
+                value, expires_at, ..
+            } => Some((value, expires_at)),
+            _ => unreachable!(),
+        }
+    }
+
+    // The active file becomes immutable and gets its hint file
+    fn rotate(&mut self) {
+        self.active.sync().unwrap();
+        self.write_hint(self.active_id());
+        let id = self.next_file;
+        self.next_file += 1;
+        let path = Self::data_path(&self.options.dir, id);
+        let storage = &*self.options.storage;
+        let (active, _) = RecordLog::open(storage, &path).unwrap();
+        let reader = storage.open(&path, OpenMode::Existing).unwrap();
+        self.readers.insert(id, reader);
+        self.files.push(id);
+        self.write_manifest();
+        self.active = active;
+
+        let dead = self
+            .files
+            .iter()
+            .filter(|&&id| id != self.active_id())
+            .map(|id| self.dead.get(id).copied().unwrap_or(0))
+            .sum::<usize>();
+        if dead > self.index.len() {
+            self.compact();
+        }
+    }
+
+    // Rewrites the live entries of the immutable files into new files, which
+    // take their place. No tombstones are needed, since every older version
+    // of a removed key goes away together with them.
+    pub fn compact(&mut self) {
+        let active_id = self.active_id();
+        let old = self.files[..self.files.len() - 1].to_vec();
+        if old.is_empty() {
+            return;
+        }
+        let mut keys = self
+            .index
+            .iter()
+            .filter(|(_, location)| location.file != active_id)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        let mut new_files = Vec::new();
+        let mut output: Option<(u64, RecordLog)> = None;
+        let mut moved = Vec::new();
+        for key in keys {
+            let (value, expires_at) = self.read_entry(key).unwrap();
+            if self.options.clock.is_expired(expires_at) {
+                self.index.remove(&key);
+                continue;
+            }
+            if output
+                .as_ref()
+                .is_none_or(|(_, log)| log.size() >= self.options.max_file_size)
+            {
+                if let Some((_, log)) = output.take() {
+                    log.sync().unwrap();
+                }
+                let id = self.next_file;
+                self.next_file += 1;
+                let path = Self::data_path(&self.options.dir, id);
+                let (log, _) = RecordLog::open(&*self.options.storage, &path).unwrap();
+                output = Some((id, log));
+                new_files.push(id);
+            }
+            let (id, log) = output.as_mut().unwrap();
+            let batch = WriteBatch::from_iter([BatchOp::Set {
+                key,
+                value,
+                expires_at,
+            }]);
+            let offset = log.append(&batch.serialize().unwrap()).unwrap();
+            moved.push((
+                key,
+                Location {
+                    file: *id,
+                    offset,
+                    op: 0,
+                },
+            ));
+        }
+        if let Some((_, log)) = output {
+            log.sync().unwrap();
+        }
+        for &id in &new_files {
+            self.write_hint(id);
+            let path = Self::data_path(&self.options.dir, id);
+            let reader = self
+                .options
+                .storage
+                .open(&path, OpenMode::Existing)
+                .unwrap();
+            self.readers.insert(id, reader);
+        }
+        self.files.splice(..old.len(), new_files);
+        self.write_manifest();
+
+        self.index.extend(moved);
+        for id in old {
+            self.readers.remove(&id);
+            self.dead.remove(&id);
+            let storage = &*self.options.storage;
+            storage
+                .remove(&Self::data_path(&self.options.dir, id))
+                .unwrap();
+            let _ = storage.remove(&Self::hint_path(&self.options.dir, id));
+        }
+    }
+}
+
+impl HashTable for Bitcask {
+    fn set(&mut self, key: u64, value: u64) {
+        self.write(WriteBatch::new().set(key, value));
+    }
+
+    fn set_with_ttl(&mut self, key: u64, value: u64, ttl: Duration) {
+        let expires_at = self.options.clock.expires_at(ttl);
+        self.write(WriteBatch::new().set_with_expiry(key, value, expires_at));
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.read_entry(key)
+            .filter(|(_, expires_at)| !self.options.clock.is_expired(*expires_at))
+            .map(|(value, _)| value)
+    }
+
+    fn remove(&mut self, key: u64) {
+        self.write(WriteBatch::new().remove(key));
+    }
+
+    fn merge(&mut self, key: u64, operand: u64) {
+        self.write(WriteBatch::new().merge(key, operand));
+    }
+
+    // A batch is a single record, so after a crash either all of it or
+    // nothing is there. Merges are resolved before, the data files only hold
+    // sets and removes.
+    fn write(&mut self, batch: &WriteBatch) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut written = HashMap::new();
+        let mut resolved = Vec::new();
+        for op in batch.ops() {
+            let op = match *op {
+                BatchOp::Merge { key, operand } => {
+                    let current = match written.get(&key) {
+                        Some(current) => *current,
+                        None => self.read_entry(key),
+                    }
+                    .filter(|(_, expires_at)| !self.options.clock.is_expired(*expires_at));
+                    BatchOp::Set {
+                        key,
+                        value: self
+                            .options
+                            .merge_operator
+                            .full_merge(current.map(|(value, _)| value), operand),
+                        expires_at: current.and_then(|(_, expires_at)| expires_at),
+                    }
+                }
+                op => op,
+            };
+            let current = match op {
+                BatchOp::Set {
+                    value, expires_at, ..
+                } => Some((value, expires_at)),
+                _ => None,
+            };
+            written.insert(op.key(), current);
+            resolved.push(op);
+        }
+        let resolved = resolved.into_iter().collect::<WriteBatch>();
+
+        let offset = self.active.append(&resolved.serialize().unwrap()).unwrap();
+        if self.options.sync {
+            self.active.sync().unwrap();
+        }
+        let active_id = self.active_id();
+        for (i, op) in resolved.ops().iter().enumerate() {
+            let location = match op {
+                BatchOp::Set { .. } => Some((offset, i as u32)),
+                _ => None,
+            };
+            self.index_op(active_id, op.key(), location);
+        }
+        if self.active.size() >= self.options.max_file_size {
+            self.rotate();
+        }
+    }
+
+    fn on_disk_size(&self) -> usize {
+        self.files
+            .iter()
+            .flat_map(|&id| {
+                [
+                    Self::data_path(&self.options.dir, id),
+                    Self::hint_path(&self.options.dir, id),
+                ]
+            })
+            .filter_map(|path| self.options.storage.open(&path, OpenMode::Existing).ok())
+            .map(|file| file.len().unwrap() as usize)
+            .sum()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (u64, u64)> + '_> {
+        Box::new(
+            self.index
+                .keys()
+                .filter_map(|&key| self.get(key).map(|value| (key, value))),
+        )
+    }
+
+    fn iter_with_expiry(&self) -> Box<dyn Iterator<Item = (u64, u64, Option<u64>)> + '_> {
+        Box::new(self.index.keys().filter_map(|&key| {
+            self.read_entry(key)
+                .filter(|(_, expires_at)| !self.options.clock.is_expired(*expires_at))
+                .map(|(value, expires_at)| (key, value, expires_at))
+        }))
+    }
+}
+
+impl Drop for Bitcask {
+    fn drop(&mut self) {
+        self.active.sync().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemStorage;
+    use std::fs;
+
+    #[test]
+    fn rotation_compaction_and_recovery() {
+        let clock = Arc::new(ManualClock::new(1000));
+        let options = BitcaskOptions {
+            dir: "bitcask/rotation_compaction_and_recovery".to_string(),
+            max_file_size: 4096,
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let _ = fs::remove_dir_all(&options.dir);
+        let mut table = Bitcask::new(&options);
+        for round in 0..5 {
+            for key in 0..500 {
+                table.set(key, key + round);
+            }
+        }
+        for key in (0..500).step_by(2) {
+            table.remove(key);
+        }
+        table.set_with_ttl(1000, 1, Duration::from_secs(1));
+        table.merge(1000, 1);
+        let mut batch = WriteBatch::new();
+        batch.merge(1001, 5).merge(1001, 5).set(1, 0);
+        table.write(&batch);
+        assert!(table.files.len() > 1);
+        assert_eq!(table.get(1000), Some(2));
+        assert_eq!(table.get(1001), Some(10));
+
+        // Compaction drops the overwritten, removed and expired versions
+        let size = table.on_disk_size();
+        clock.advance(Duration::from_secs(1));
+        table.compact();
+        assert!(table.on_disk_size() < size);
+        let expected = |key: u64| match key {
+            1 => Some(0),
+            1001 => Some(10),
+            _ if key < 500 && key % 2 == 1 => Some(key + 4),
+            _ => None,
+        };
+        for key in 0..1002 {
+            assert_eq!(table.get(key), expected(key));
+        }
+        drop(table);
+
+        // Files no manifest refers to are what a crash left behind
+        fs::write(Bitcask::data_path(&options.dir, 1000), b"garbage").unwrap();
+        fs::write(format!("{}.tmp", Bitcask::hint_path(&options.dir, 0)), b"").unwrap();
+        let mut table = Bitcask::new(&options);
+        assert!(!std::path::Path::new(&Bitcask::data_path(&options.dir, 1000)).exists());
+        for key in 0..1002 {
+            assert_eq!(table.get(key), expected(key));
+        }
+        let mut entries = table.iter().collect::<Vec<_>>();
+        entries.sort_unstable();
+        assert_eq!(
+            entries,
+            (0..1002)
+                .filter_map(|key| expected(key).map(|value| (key, value)))
+                .collect::<Vec<_>>()
+        );
+        table.merge(1001, 1);
+        assert_eq!(table.get(1001), Some(11));
+        drop(table);
+        fs::remove_dir_all(&options.dir).unwrap();
+    }
+
+    #[test]
+    fn lost_manifest() {
+        let storage = Arc::new(MemStorage::new());
+        let options = BitcaskOptions {
+            dir: "lost_manifest".to_string(),
+            max_file_size: 1024,
+            storage: storage.clone(),
+            ..Default::default()
+        };
+        {
+            let mut table = Bitcask::new(&options);
+            for key in 0..500 {
+                table.set(key, key);
+            }
+        }
+        let files = || {
+            let mut filenames = storage.read_dir("lost_manifest").unwrap();
+            filenames.retain(|filename| filename != MANIFEST_FILENAME);
+            filenames.sort_unstable();
+            filenames
+        };
+        let before = files();
+        assert!(before.len() > 2);
+        let open = || {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                Bitcask::new(&options);
+            }))
+        };
+
+        // Neither a manifest that can't be decoded nor a missing one opens
+        // the table, and the data files stay
+        let manifest_path = format!("lost_manifest/{}", MANIFEST_FILENAME);
+        let manifest = storage::read_file(&*storage, &manifest_path).unwrap();
+        storage::write_atomically(&*storage, &manifest_path, b"garbage").unwrap();
+        assert!(open().is_err());
+        assert_eq!(files(), before);
+        storage.remove(&manifest_path).unwrap();
+        assert!(open().is_err());
+        assert_eq!(files(), before);
+
+        storage::write_atomically(&*storage, &manifest_path, &manifest).unwrap();
+        assert_eq!(Bitcask::new(&options).get(499), Some(499));
+    }
+
+    #[test]
+    fn conformance() {
+        let clock = Arc::new(ManualClock::default());
+        let options = BitcaskOptions {
+            dir: "bitcask/conformance".to_string(),
+            max_file_size: 16 * 1024,
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let _ = fs::remove_dir_all(&options.dir);
+        crate::conformance::run(|| Bitcask::new(&options), &clock, 1);
+        fs::remove_dir_all(&options.dir).unwrap();
+    }
+}
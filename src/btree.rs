@@ -0,0 +1,758 @@
+// Page-based B+tree. Page 0 holds the metadata with the root pointer, the
+// other pages are leaves, which are linked in key order, internal nodes or
+// free pages, which are chained into the free list.
+
+use crate::clock::{Clock, SystemClock};
+use crate::hash_table::HashTable;
+use crate::merge::MergeOperator;
+use crate::record_log::RecordLog;
+use crate::storage::{self, FsStorage, OpenMode, Storage, StorageFile};
+use crate::write_batch::{BatchOp, WriteBatch};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+    time::Duration,
+};
+
+const PAGE_SIZE: usize = 4096;
+const LEAF_CAPACITY: usize = 128;
+const INTERNAL_CAPACITY: usize = 128;
+// No page has this id, page 0 is the metadata
+const NO_PAGE: u64 = 0;
+
+// (key, value, expiry time in milliseconds since the Unix epoch)
+type Entry = (u64, u64, Option<u64>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+enum Page {
+    Free { next: u64 },
+    Leaf { entries: Vec<Entry>, next: u64 },
+    // keys[i] separates the keys of children[i], which are smaller, from the
+    // keys of children[i + 1]
+    Internal { keys: Vec<u64>, children: Vec<u64> },
+}
+
+impl Page {
+    fn len(&self) -> usize {
+        match self {
+            Page::Free { .. } => 0,
+            Page::Leaf { entries, .. } => entries.len(),
+            Page::Internal { children, .. } => children.len(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Page::Internal { .. } => INTERNAL_CAPACITY,
+            _ => LEAF_CAPACITY,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Meta {
+    root: u64,
+    free_head: u64,
+    page_count: u64,
+    len: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BPlusTreeOptions {
+    pub filename: String,
+    pub merge_operator: MergeOperator,
+    pub clock: Arc<dyn Clock>,
+    pub storage: Arc<dyn Storage>,
+    // fsync the redo log and the file after every write
+    pub sync: bool,
+}
+
+impl Default for BPlusTreeOptions {
+    fn default() -> Self {
+        BPlusTreeOptions {
+            filename: "btree.bin".to_string(),
+            merge_operator: MergeOperator::default(),
+            clock: Arc::new(SystemClock),
+            storage: Arc::new(FsStorage),
+            sync: false,
+        }
+    }
+}
+
+pub struct BPlusTree {
+    file: Box<dyn StorageFile>,
+    meta: Meta,
+    redo_log: RecordLog,
+    sync: bool,
+    // Pages the batch being applied writes, which go to the redo log before
+    // they reach the file. The metadata is page 0.
+    staged: Option<BTreeMap<u64, Vec<u8>>>,
+    merge_operator: MergeOperator,
+    clock: Arc<dyn Clock>,
+}
+
+impl BPlusTree {
+    pub fn new(options: &BPlusTreeOptions) -> Self {
+        let storage = &*options.storage;
+        let created = !storage.exists(&options.filename);
+        let file = storage.open(&options.filename, OpenMode::Create).unwrap();
+        if created {
+            storage
+                .sync_dir(storage::parent_dir(&options.filename))
+                .unwrap();
+        }
+        let redo_path = format!("{}.redo", options.filename);
+        let (redo_log, records) = RecordLog::open(storage, &redo_path).unwrap();
+        let mut tree = BPlusTree {
+            file,
+            meta: Meta {
+                root: 1,
+                free_head: NO_PAGE,
+                page_count: 2,
+                len: 0,
+            },
+            redo_log,
+            sync: options.sync,
+            staged: None,
+            merge_operator: options.merge_operator.clone(),
+            clock: options.clock.clone(),
+        };
+        if !records.is_empty() {
+            for record in records {
+                tree.write_record(&record);
+            }
+            tree.file.sync().unwrap();
+            tree.redo_log.clear().unwrap();
+        }
+        if tree.file.is_empty().unwrap() {
+            let record = tree.stage(|tree| {
+                tree.write_page(
+                    1,
+                    &Page::Leaf {
+                        entries: Vec::new(),
+                        next: NO_PAGE,
+                    },
+                );
+                tree.write_meta();
+            });
+            tree.commit(&record.unwrap());
+        } else {
+            let mut bytes = [0; PAGE_SIZE];
+            tree.file.read_exact_at(&mut bytes, 0).unwrap();
+            tree.meta = Self::page_options().deserialize(&bytes).unwrap();
+        }
+        tree
+    }
+
+    // Applies `f` to the tree in memory only and returns the redo record
+    // that writes the pages it changed to the file, None if there are none
+    fn stage(&mut self, f: impl FnOnce(&mut Self)) -> Option<Vec<u8>> {
+        self.staged = Some(BTreeMap::new());
+        f(self);
+        let pages = self.staged.take().unwrap();
+        let mut record = Vec::with_capacity(pages.len() * (8 + PAGE_SIZE));
+        for (id, bytes) in pages {
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(&bytes);
+        }
+        (!record.is_empty()).then_some(record)
+    }
+
+    fn commit(&mut self, record: &[u8]) {
+        self.redo_log.append(record).unwrap();
+        if self.sync {
+            self.redo_log.sync().unwrap();
+        }
+        self.write_record(record);
+        if self.sync {
+            self.file.sync().unwrap();
+        }
+        self.redo_log.clear().unwrap();
+    }
+
+    // A record holds whole page images after their ids, so replaying it is
+    // idempotent and also finishes a batch that only partly reached the file
+    fn write_record(&self, record: &[u8]) {
+        for page in record.chunks(8 + PAGE_SIZE) {
+            let id = u64::from_le_bytes(page[..8].try_into().unwrap());
+            self.file
+                .write_all_at(&page[8..], id * PAGE_SIZE as u64)
+                .unwrap();
+        }
+    }
+
+    fn page_options() -> impl Options {
+        bincode::DefaultOptions::new().allow_trailing_bytes()
+    }
+
+    fn read_page(&self, id: u64) -> Page {
+        if let Some(bytes) = self.staged.as_ref().and_then(|staged| staged.get(&id)) {
+            return Self::page_options().deserialize(bytes).unwrap();
+        }
+        let mut bytes = [0; PAGE_SIZE];
+        self.file
+            .read_exact_at(&mut bytes, id * PAGE_SIZE as u64)
+            .unwrap();
+        Self::page_options().deserialize(&bytes).unwrap()
+    }
+
+    fn write_bytes(&mut self, id: u64, bytes: Vec<u8>) {
+        match &mut self.staged {
+            Some(staged) => {
+                staged.insert(id, bytes);
+            }
+            None => self
+                .file
+                .write_all_at(&bytes, id * PAGE_SIZE as u64)
+                .unwrap(),
+        }
+    }
+
+    fn write_page(&mut self, id: u64, page: &Page) {
+        let mut bytes = Self::page_options().serialize(page).unwrap();
+        assert!(bytes.len() <= PAGE_SIZE);
+        bytes.resize(PAGE_SIZE, 0);
+        self.write_bytes(id, bytes);
+    }
+
+    fn write_meta(&mut self) {
+        let mut bytes = Self::page_options().serialize(&self.meta).unwrap();
+        bytes.resize(PAGE_SIZE, 0);
+        self.write_bytes(0, bytes);
+    }
+
+    fn allocate(&mut self, page: &Page) -> u64 {
+        let id = match self.meta.free_head {
+            NO_PAGE => {
+                self.meta.page_count += 1;
+                self.meta.page_count - 1
+            }
+            id => {
+                let Page::Free { next } = self.read_page(id) else {
+                    panic!("page {} on the free list is in use", id);
+                };
+                self.meta.free_head = next;
+                id
+            }
+        };
+        self.write_page(id, page);
+        id
+    }
+
+    fn free(&mut self, id: u64) {
+        self.write_page(
+            id,
+            &Page::Free {
+                next: self.meta.free_head,
+            },
+        );
+        self.meta.free_head = id;
+    }
+
+    pub fn len(&self) -> usize {
+        self.meta.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.meta.len == 0
+    }
+
+    fn apply(&mut self, batch: &WriteBatch) {
+        for op in batch.ops() {
+            match *op {
+                BatchOp::Set {
+                    key,
+                    value,
+                    expires_at,
+                } => self.insert(key, value, expires_at),
+                BatchOp::Remove { key } => self.remove_key(key),
+                BatchOp::Merge { key, operand } => self.merge_key(key, operand),
+            }
+        }
+    }
+
+    fn merge_key(&mut self, key: u64, operand: u64) {
+        let current = self
+            .get_entry(key)
+            .filter(|(_, _, expires_at)| !self.clock.is_expired(*expires_at));
+        let value = self
+            .merge_operator
+            .full_merge(current.map(|(_, value, _)| value), operand);
+        self.insert(
+            key,
+            value,
+            current.and_then(|(_, _, expires_at)| expires_at),
+        );
+    }
+
+    fn get_entry(&self, key: u64) -> Option<Entry> {
+        let mut id = self.meta.root;
+        loop {
+            match self.read_page(id) {
+                Page::Internal { keys, children } => {
+                    id = children[keys.partition_point(|&k| k <= key)];
+                }
+                Page::Leaf { entries, .. } => {
+                    let pos = entries.binary_search_by_key(&key, |e| e.0).ok()?;
+                    return Some(entries[pos]);
+                }
+                Page::Free { .. } => panic!("page {} is free", id),
+            }
+        }
+    }
+
+    fn insert(&mut self, key: u64, value: u64, expires_at: Option<u64>) {
+        if let Some((separator, right)) = self.insert_into(self.meta.root, (key, value, expires_at))
+        {
+            let root = Page::Internal {
+                keys: vec![separator],
+                children: vec![self.meta.root, right],
+            };
+            self.meta.root = self.allocate(&root);
+        }
+        self.write_meta();
+    }
+
+    // Returns the separator and the new right sibling if the page was split
+    fn insert_into(&mut self, id: u64, entry: Entry) -> Option<(u64, u64)> {
+        let mut page = self.read_page(id);
+        let split = match &mut page {
+            Page::Leaf { entries, next } => {
+                match entries.binary_search_by_key(&entry.0, |e| e.0) {
+                    Ok(pos) => entries[pos] = entry,
+                    Err(pos) => {
+                        entries.insert(pos, entry);
+                        self.meta.len += 1;
+                    }
+                }
+                if entries.len() > LEAF_CAPACITY {
+                    // Make room by dropping expired entries before splitting
+                    let before = entries.len();
+                    entries.retain(|(_, _, expires_at)| !self.clock.is_expired(*expires_at));
+                    self.meta.len -= (before - entries.len()) as u64;
+                }
+                (entries.len() > LEAF_CAPACITY).then(|| {
+                    let right = Page::Leaf {
+                        entries: entries.split_off(entries.len() / 2),
+                        next: *next,
+                    };
+                    let Page::Leaf {
+                        entries: right_entries,
+                        ..
+                    } = &right
+                    else {
+                        unreachable!()
+                    };
+                    let separator = right_entries[0].0;
+                    *next = self.allocate(&right);
+                    (separator, *next)
+                })
+            }
+            Page::Internal { keys, children } => {
+                let pos = keys.partition_point(|&k| k <= entry.0);
+                let (separator, right) = self.insert_into(children[pos], entry)?;
+                keys.insert(pos, separator);
+                children.insert(pos + 1, right);
+                (children.len() > INTERNAL_CAPACITY).then(|| {
+                    let mid = keys.len() / 2;
+                    let right_keys = keys.split_off(mid + 1);
+                    let separator = keys.pop().unwrap();
+                    let right = Page::Internal {
+                        keys: right_keys,
+                        children: children.split_off(mid + 1),
+                    };
+                    (separator, self.allocate(&right))
+                })
+            }
+            Page::Free { .. } => panic!("page {} is free", id),
+        };
+        self.write_page(id, &page);
+        split
+    }
+
+    fn remove_key(&mut self, key: u64) {
+        if !self.remove_from(self.meta.root, key) {
+            return;
+        }
+        // The root goes away when its last two children were merged
+        if let Page::Internal { children, .. } = self.read_page(self.meta.root) {
+            if children.len() == 1 {
+                let old_root = self.meta.root;
+                self.meta.root = children[0];
+                self.free(old_root);
+            }
+        }
+        self.write_meta();
+    }
+
+    // Returns whether the key was there
+    fn remove_from(&mut self, id: u64, key: u64) -> bool {
+        let mut page = self.read_page(id);
+        match &mut page {
+            Page::Leaf { entries, .. } => {
+                let Ok(pos) = entries.binary_search_by_key(&key, |e| e.0) else {
+                    return false;
+                };
+                entries.remove(pos);
+                self.meta.len -= 1;
+            }
+            Page::Internal { keys, children } => {
+                let pos = keys.partition_point(|&k| k <= key);
+                if !self.remove_from(children[pos], key) {
+                    return false;
+                }
+                self.rebalance(keys, children, pos);
+            }
+            Page::Free { .. } => panic!("page {} is free", id),
+        }
+        self.write_page(id, &page);
+        true
+    }
+
+    // A child that is less than half full is merged with a sibling, or takes
+    // entries from it if both don't fit into one page
+    fn rebalance(&mut self, keys: &mut Vec<u64>, children: &mut Vec<u64>, pos: usize) {
+        let child = self.read_page(children[pos]);
+        if child.len() >= child.capacity() / 2 || children.len() < 2 {
+            return;
+        }
+        let left_pos = pos.saturating_sub(1).min(children.len() - 2);
+        let (left_id, right_id) = (children[left_pos], children[left_pos + 1]);
+        let left = self.read_page(left_id);
+        let right = self.read_page(right_id);
+        let separator = keys[left_pos];
+        let merged = left.len() + right.len() <= left.capacity();
+        match (left, right) {
+            (
+                Page::Leaf {
+                    entries: mut left_entries,
+                    ..
+                },
+                Page::Leaf {
+                    entries: right_entries,
+                    next,
+                },
+            ) => {
+                left_entries.extend(right_entries);
+                if merged {
+                    let left = Page::Leaf {
+                        entries: left_entries,
+                        next,
+                    };
+                    self.write_page(left_id, &left);
+                } else {
+                    let right_entries = left_entries.split_off(left_entries.len() / 2);
+                    keys[left_pos] = right_entries[0].0;
+                    let left = Page::Leaf {
+                        entries: left_entries,
+                        next: right_id,
+                    };
+                    let right = Page::Leaf {
+                        entries: right_entries,
+                        next,
+                    };
+                    self.write_page(left_id, &left);
+                    self.write_page(right_id, &right);
+                }
+            }
+            (
+                Page::Internal {
+                    keys: mut left_keys,
+                    children: mut left_children,
+                },
+                Page::Internal {
+                    keys: right_keys,
+                    children: right_children,
+                },
+            ) => {
+                left_keys.push(separator);
+                left_keys.extend(right_keys);
+                left_children.extend(right_children);
+                if merged {
+                    let left = Page::Internal {
+                        keys: left_keys,
+                        children: left_children,
+                    };
+                    self.write_page(left_id, &left);
+                } else {
+                    let mid = left_keys.len() / 2;
+                    let right_keys = left_keys.split_off(mid + 1);
+                    keys[left_pos] = left_keys.pop().unwrap();
+                    let right = Page::Internal {
+                        keys: right_keys,
+                        children: left_children.split_off(mid + 1),
+                    };
+                    let left = Page::Internal {
+                        keys: left_keys,
+                        children: left_children,
+                    };
+                    self.write_page(left_id, &left);
+                    self.write_page(right_id, &right);
+                }
+            }
+            _ => panic!("siblings {} and {} differ in kind", left_id, right_id),
+        }
+        if merged {
+            keys.remove(left_pos);
+            children.remove(left_pos + 1);
+            self.free(right_id);
+        }
+    }
+
+    // Live entries with keys in `range`, sorted by key
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> BPlusTreeIter<'_> {
+        let start = match range.start_bound() {
+            Bound::Included(&key) => key,
+            Bound::Excluded(&key) => key.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let excluded_start = matches!(range.start_bound(), Bound::Excluded(&u64::MAX));
+        let mut id = self.meta.root;
+        while let Page::Internal { keys, children } = self.read_page(id) {
+            id = children[keys.partition_point(|&k| k <= start)];
+        }
+        BPlusTreeIter {
+            tree: self,
+            entries: Vec::new(),
+            pos: 0,
+            next_leaf: if excluded_start { NO_PAGE } else { id },
+            start,
+            end: range.end_bound().cloned(),
+        }
+    }
+}
+
+// Walks the linked leaves, one page at a time
+pub struct BPlusTreeIter<'a> {
+    tree: &'a BPlusTree,
+    entries: Vec<Entry>,
+    pos: usize,
+    next_leaf: u64,
+    start: u64,
+    end: Bound<u64>,
+}
+
+impl BPlusTreeIter<'_> {
+    fn next_entry(&mut self) -> Option<Entry> {
+        loop {
+            if let Some(&(key, value, expires_at)) = self.entries.get(self.pos) {
+                self.pos += 1;
+                let past_end = match self.end {
+                    Bound::Included(end) => key > end,
+                    Bound::Excluded(end) => key >= end,
+                    Bound::Unbounded => false,
+                };
+                if past_end {
+                    self.next_leaf = NO_PAGE;
+                    self.entries.clear();
+                    return None;
+                }
+                if key >= self.start && !self.tree.clock.is_expired(expires_at) {
+                    return Some((key, value, expires_at));
+                }
+                continue;
+            }
+            if self.next_leaf == NO_PAGE {
+                return None;
+            }
+            let Page::Leaf { entries, next } = self.tree.read_page(self.next_leaf) else {
+                panic!("page {} is not a leaf", self.next_leaf);
+            };
+            self.entries = entries;
+            self.pos = 0;
+            self.next_leaf = next;
+        }
+    }
+}
+
+impl<'a> Iterator for BPlusTreeIter<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().map(|(key, value, _)| (key, value))
+    }
+}
+
+impl HashTable for BPlusTree {
+    fn set(&mut self, key: u64, value: u64) {
+        self.write(WriteBatch::new().set(key, value));
+    }
+
+    fn set_with_ttl(&mut self, key: u64, value: u64, ttl: Duration) {
+        let expires_at = self.clock.expires_at(ttl);
+        self.write(WriteBatch::new().set_with_expiry(key, value, expires_at));
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.get_entry(key)
+            .filter(|(_, _, expires_at)| !self.clock.is_expired(*expires_at))
+            .map(|(_, value, _)| value)
+    }
+
+    fn remove(&mut self, key: u64) {
+        self.write(WriteBatch::new().remove(key));
+    }
+
+    fn merge(&mut self, key: u64, operand: u64) {
+        self.write(WriteBatch::new().merge(key, operand));
+    }
+
+    // Even one set can split a leaf and its parents and changes the metadata
+    // page, so every batch is committed as images of the pages it touched
+    fn write(&mut self, batch: &WriteBatch) {
+        if let Some(record) = self.stage(|tree| tree.apply(batch)) {
+            self.commit(&record);
+        }
+    }
+
+    fn on_disk_size(&self) -> usize {
+        self.file.len().unwrap() as usize
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (u64, u64)> + '_> {
+        Box::new(self.range(..))
+    }
+
+    fn iter_with_expiry(&self) -> Box<dyn Iterator<Item = (u64, u64, Option<u64>)> + '_> {
+        let mut entries = self.range(..);
+        Box::new(std::iter::from_fn(move || entries.next_entry()))
+    }
+}
+
+impl Drop for BPlusTree {
+    fn drop(&mut self) {
+        self.file.sync().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::random::test_seed;
+    use crate::storage::MemStorage;
+    use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+    fn height(tree: &BPlusTree) -> usize {
+        let mut id = tree.meta.root;
+        let mut height = 1;
+        while let Page::Internal { children, .. } = tree.read_page(id) {
+            id = children[0];
+            height += 1;
+        }
+        height
+    }
+
+    #[test]
+    fn splits_merges_and_free_pages() {
+        let options = BPlusTreeOptions {
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        };
+        let mut keys = (0..20000u64).map(|key| key * 3).collect::<Vec<_>>();
+        keys.shuffle(&mut StdRng::seed_from_u64(test_seed()));
+        let mut tree = BPlusTree::new(&options);
+        for &key in &keys {
+            tree.set(key, key + 1);
+        }
+        assert_eq!(tree.len(), 20000);
+        assert_eq!(height(&tree), 3);
+        assert_eq!(tree.get(300), Some(301));
+        assert_eq!(tree.get(301), None);
+        assert_eq!(
+            tree.range(100..=112).collect::<Vec<_>>(),
+            vec![(102, 103), (105, 106), (108, 109), (111, 112)]
+        );
+        assert_eq!(
+            tree.range((Bound::Excluded(59994), Bound::Unbounded))
+                .collect::<Vec<_>>(),
+            vec![(59997, 59998)]
+        );
+
+        // Removing most keys merges pages, which go to the free list and are
+        // reused before the file grows again
+        for &key in &keys[..19900] {
+            tree.remove(key);
+        }
+        assert_eq!(tree.len(), 100);
+        assert!(height(&tree) < 3);
+        let pages = tree.meta.page_count;
+        for &key in &keys[..8000] {
+            tree.set(key, key);
+        }
+        assert_eq!(tree.meta.page_count, pages);
+        drop(tree);
+
+        let tree = BPlusTree::new(&options);
+        let mut expected = keys[..8000]
+            .iter()
+            .map(|&key| (key, key))
+            .chain(keys[19900..].iter().map(|&key| (key, key + 1)))
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        assert_eq!(tree.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn batches_expiry_and_merges() {
+        let clock = Arc::new(ManualClock::new(1000));
+        let options = BPlusTreeOptions {
+            filename: "btree_batches_expiry.bin".to_string(),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let _ = std::fs::remove_file(&options.filename);
+        let mut tree = BPlusTree::new(&options);
+        for key in 0..1000 {
+            tree.set_with_ttl(key, key, Duration::from_secs(10));
+        }
+        tree.merge(5, 1);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(tree.get(5), None);
+        tree.merge(5, 1);
+        assert_eq!(tree.get(5), Some(1));
+        assert_eq!(tree.iter().collect::<Vec<_>>(), vec![(5, 1)]);
+
+        // A batch logged right before a crash is written on the next open,
+        // and written once even if its pages had already reached the file
+        let mut batch = WriteBatch::new();
+        batch.set(2000, 1).merge(2000, 1).remove(5).merge(3000, 7);
+        let record = tree.stage(|tree| tree.apply(&batch)).unwrap();
+        tree.write_record(&record);
+        tree.redo_log.append(&record).unwrap();
+        drop(tree);
+        let tree = BPlusTree::new(&options);
+        assert_eq!(tree.iter().collect::<Vec<_>>(), vec![(2000, 2), (3000, 7)]);
+        std::fs::remove_file(&options.filename).unwrap();
+        std::fs::remove_file(format!("{}.redo", options.filename)).unwrap();
+    }
+
+    #[test]
+    fn conformance() {
+        let clock = Arc::new(ManualClock::default());
+        let options = BPlusTreeOptions {
+            filename: "btree_conformance.bin".to_string(),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let _ = std::fs::remove_file(&options.filename);
+        crate::conformance::run(|| BPlusTree::new(&options), &clock, 1);
+        std::fs::remove_file(&options.filename).unwrap();
+        std::fs::remove_file(format!("{}.redo", options.filename)).unwrap();
+    }
+
+    #[test]
+    fn crash_consistency() {
+        let open = |storage| {
+            BPlusTree::new(&BPlusTreeOptions {
+                storage,
+                sync: true,
+                ..Default::default()
+            })
+        };
+        crate::crash::run(open, &crate::crash::workload(7, 40));
+    }
+}
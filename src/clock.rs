@@ -0,0 +1,60 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Source of the current time in milliseconds since the Unix epoch, used to
+// decide whether entries with a time-to-live have expired
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> u64;
+
+    fn expires_at(&self, ttl: Duration) -> u64 {
+        self.now().saturating_add(ttl.as_millis() as u64)
+    }
+
+    fn is_expired(&self, expires_at: Option<u64>) -> bool {
+        expires_at.is_some_and(|expires_at| expires_at <= self.now())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+// Clock that only moves when told to
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock {
+            now: AtomicU64::new(now),
+        }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
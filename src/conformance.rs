@@ -0,0 +1,237 @@
+// Checks that any HashTable behaves like a HashMap. `open` must return the
+// table over the same storage every time, empty the first time, with the
+// default merge operator and `clock` as its clock. The checks run one after
+// the other on that table and compare it with a model of what it should hold.
+
+use crate::clock::{Clock, ManualClock};
+use crate::hash_table::HashTable;
+use crate::merge::MergeOperator;
+use crate::write_batch::WriteBatch;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::BTreeMap, time::Duration};
+
+pub type Model = BTreeMap<u64, u64>;
+
+pub fn run<T: HashTable, F: FnMut() -> T>(mut open: F, clock: &ManualClock, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut model = Model::new();
+    let mut table = open();
+    assert_matches(&table, &model);
+    check_edge_keys(&mut table, &mut model);
+    check_overwrites_and_removes(&mut table, &mut model);
+    check_random_ops(&mut table, &mut model, &mut rng, 5000);
+    check_expiry(&mut table, &mut model, clock);
+    let mut table = check_reopen(table, &mut open, &model);
+    check_on_disk_size(&mut table, &mut model, &mut rng);
+    check_reopen(table, &mut open, &model);
+}
+
+// Every key of the model and some around them read the same, and so do
+// get_many, iter and iter_with_expiry
+pub fn assert_matches<T: HashTable>(table: &T, model: &Model) {
+    let mut keys = model
+        .keys()
+        .flat_map(|&key| [key, key.wrapping_add(1)])
+        .chain([0, 1, u64::MAX - 1, u64::MAX])
+        .collect::<Vec<_>>();
+    keys.dedup();
+    for &key in &keys {
+        assert_eq!(table.get(key), model.get(&key).copied(), "key {}", key);
+    }
+    assert_eq!(
+        table.get_many(&keys),
+        keys.iter()
+            .map(|key| model.get(key).copied())
+            .collect::<Vec<_>>()
+    );
+    let mut entries = table.iter().collect::<Vec<_>>();
+    entries.sort_unstable();
+    assert_eq!(
+        entries,
+        model.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>()
+    );
+    let mut entries = table
+        .iter_with_expiry()
+        .map(|(key, value, _)| (key, value))
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+    assert_eq!(
+        entries,
+        model.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>()
+    );
+}
+
+pub fn check_edge_keys<T: HashTable>(table: &mut T, model: &mut Model) {
+    for key in [0, 1, u64::MAX - 1, u64::MAX] {
+        table.set(key, key);
+        model.insert(key, key);
+    }
+    table.set(u64::MAX, 0);
+    model.insert(u64::MAX, 0);
+    table.set(0, u64::MAX);
+    model.insert(0, u64::MAX);
+    assert_matches(table, model);
+    table.remove(1);
+    model.remove(&1);
+    table.merge(u64::MAX - 1, u64::MAX);
+    model.insert(u64::MAX - 1, u64::MAX);
+    assert_matches(table, model);
+}
+
+pub fn check_overwrites_and_removes<T: HashTable>(table: &mut T, model: &mut Model) {
+    for round in 0..3 {
+        for key in 100..200 {
+            table.set(key, key * round);
+            model.insert(key, key * round);
+        }
+    }
+    for key in (100..200).step_by(3) {
+        table.remove(key);
+        model.remove(&key);
+    }
+    // Removing twice or removing what was never there changes nothing
+    table.remove(100);
+    table.remove(1 << 40);
+    for key in (100..200).step_by(6) {
+        table.set(key, 1);
+        model.insert(key, 1);
+    }
+    assert_matches(table, model);
+}
+
+// Sets, removes, merges and batches of them on a small key space, so most
+// operations hit keys that were written before
+pub fn check_random_ops<T: HashTable>(
+    table: &mut T,
+    model: &mut Model,
+    rng: &mut StdRng,
+    ops: usize,
+) {
+    let merge_operator = MergeOperator::default();
+    let keys = 1000..1000 + ops as u64 / 4;
+    for i in 0..ops {
+        let key = rng.gen_range(keys.clone());
+        match rng.gen_range(0..10) {
+            0..=4 => {
+                let value = rng.gen::<u64>();
+                table.set(key, value);
+                model.insert(key, value);
+            }
+            5..=6 => {
+                table.remove(key);
+                model.remove(&key);
+            }
+            7..=8 => {
+                let operand = rng.gen_range(0..1000);
+                table.merge(key, operand);
+                let value = merge_operator.full_merge(model.get(&key).copied(), operand);
+                model.insert(key, value);
+            }
+            _ => {
+                let mut batch = WriteBatch::new();
+                for _ in 0..rng.gen_range(1..10) {
+                    let key = rng.gen_range(keys.clone());
+                    if rng.gen_bool(0.7) {
+                        let value = rng.gen::<u64>();
+                        batch.set(key, value);
+                        model.insert(key, value);
+                    } else {
+                        batch.remove(key);
+                        model.remove(&key);
+                    }
+                }
+                table.write(&batch);
+            }
+        }
+        if i % 1000 == 0 {
+            assert_eq!(table.get(key), model.get(&key).copied());
+        }
+    }
+    assert_matches(table, model);
+}
+
+// Entries with a time-to-live read like the others until the clock reaches
+// their expiry and like removed ones after. A merge keeps the expiry of the
+// value it replaces, or has none if that value had expired.
+pub fn check_expiry<T: HashTable>(table: &mut T, model: &mut Model, clock: &ManualClock) {
+    let merge_operator = MergeOperator::default();
+    let start = clock.now();
+    for key in 3000..3100 {
+        table.set_with_ttl(key, key, Duration::from_secs(10 + key % 2 * 10));
+        model.insert(key, key);
+    }
+    let mut batch = WriteBatch::new();
+    for key in 3100..3110 {
+        batch.set_with_expiry(key, key, clock.expires_at(Duration::from_secs(10)));
+        model.insert(key, key);
+    }
+    table.write(&batch);
+    for key in 3000..3010 {
+        table.merge(key, 1);
+        model.insert(key, merge_operator.full_merge(Some(key), 1));
+    }
+    // Setting without a time-to-live keeps the entry for good
+    for key in 3010..3020 {
+        table.set(key, 1);
+        model.insert(key, 1);
+    }
+    assert_matches(table, model);
+    for (key, _, expires_at) in table.iter_with_expiry() {
+        let expected = match key {
+            3000..=3009 | 3020..=3099 => Some(start + 10_000 + key % 2 * 10_000),
+            3100..=3109 => Some(start + 10_000),
+            _ => None,
+        };
+        assert_eq!(expires_at, expected, "key {}", key);
+    }
+
+    clock.advance(Duration::from_secs(10));
+    model.retain(|&key, _| {
+        !(3000..3110).contains(&key) || (3010..3020).contains(&key) || key < 3100 && key % 2 == 1
+    });
+    assert_matches(table, model);
+    table.merge(3000, 5);
+    model.insert(3000, merge_operator.full_merge(None, 5));
+    table.set_with_ttl(3002, 2, Duration::from_secs(10));
+    model.insert(3002, 2);
+    assert_matches(table, model);
+
+    clock.advance(Duration::from_secs(10));
+    model.retain(|&key, _| !(3001..3110).contains(&key) || (3010..3020).contains(&key));
+    assert_matches(table, model);
+}
+
+pub fn check_reopen<T: HashTable, F: FnMut() -> T>(table: T, open: &mut F, model: &Model) -> T {
+    drop(table);
+    let table = open();
+    assert_matches(&table, model);
+    table
+}
+
+// Writes grow the size on disk, reads never change it, and the live entries,
+// random and so hardly compressible, take at least the 8 bytes of their value
+pub fn check_on_disk_size<T: HashTable>(table: &mut T, model: &mut Model, rng: &mut StdRng) {
+    let before = table.on_disk_size();
+    for _ in 0..2000 {
+        let key = rng.gen::<u64>();
+        let value = rng.gen::<u64>();
+        table.set(key, value);
+        model.insert(key, value);
+    }
+    let size = table.on_disk_size();
+    assert!(
+        size > before,
+        "{} bytes, {} before the writes",
+        size,
+        before
+    );
+    assert!(
+        size >= 8 * model.len(),
+        "{} bytes for {} entries",
+        size,
+        model.len()
+    );
+    assert_matches(table, model);
+    table.get_many(&model.keys().copied().collect::<Vec<_>>());
+    assert_eq!(table.on_disk_size(), size);
+}
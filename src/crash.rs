@@ -0,0 +1,235 @@
+// Crash simulation. A workload of batches runs once on a RecordingStorage,
+// then every prefix of the recorded trace is turned into what a disk could
+// hold after a crash right there, and the table reopened on it must hold
+// every batch that was acknowledged and nothing that was never written.
+// Batches of one operation are run as a set, remove or merge instead.
+//
+// A crash after n operations of the trace leaves either:
+// - only what was synced, files and directories, as when nothing reached the
+//   disk on its own
+// - the files that were created, renamed or removed, with what was synced
+//   of them, as when only the directories did
+// - all n operations, as when everything did
+// - all n operations and the first half of the next one if it is a write
+// The batch that was in flight may be there or not, but not in part.
+
+use crate::conformance::{self, Model};
+use crate::hash_table::HashTable;
+use crate::merge::MergeOperator;
+use crate::storage::{self, MemStorage, OpenMode, RecordingStorage, Storage, TraceOp};
+use crate::write_batch::{BatchOp, WriteBatch};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Clone, Default)]
+struct FileImage {
+    data: Vec<u8>,
+    synced: Vec<u8>,
+}
+
+#[derive(Clone, Default)]
+struct Disk {
+    paths: HashMap<String, u64>,
+    // What the directories held when they were last synced
+    synced_paths: HashMap<String, u64>,
+    files: HashMap<u64, FileImage>,
+}
+
+impl Disk {
+    fn apply(&mut self, op: &TraceOp) {
+        match op {
+            TraceOp::Create { file, path } => {
+                self.paths.insert(path.clone(), *file);
+                self.files.insert(*file, FileImage::default());
+            }
+            TraceOp::Write {
+                file,
+                offset,
+                bytes,
+            } => {
+                let data = &mut self.files.get_mut(file).unwrap().data;
+                let (start, end) = (*offset as usize, *offset as usize + bytes.len());
+                if data.len() < end {
+                    data.resize(end, 0);
+                }
+                data[start..end].copy_from_slice(bytes);
+            }
+            TraceOp::SetLen { file, len } => {
+                let data = &mut self.files.get_mut(file).unwrap().data;
+                data.resize(*len as usize, 0);
+            }
+            TraceOp::Sync { file } => {
+                let image = self.files.get_mut(file).unwrap();
+                image.synced = image.data.clone();
+            }
+            TraceOp::Rename { from, to } => {
+                let file = self.paths.remove(from).unwrap();
+                self.paths.insert(to.clone(), file);
+            }
+            TraceOp::Remove { path } => {
+                self.paths.remove(path);
+            }
+            TraceOp::SyncDir { dir } => {
+                self.synced_paths
+                    .retain(|path, _| storage::parent_dir(path) != dir);
+                for (path, file) in &self.paths {
+                    if storage::parent_dir(path) == dir {
+                        self.synced_paths.insert(path.clone(), *file);
+                    }
+                }
+            }
+        }
+    }
+
+    fn storage(&self, synced_dirs: bool, synced_files: bool) -> Arc<MemStorage> {
+        let storage = MemStorage::new();
+        let paths = if synced_dirs {
+            &self.synced_paths
+        } else {
+            &self.paths
+        };
+        for (path, file) in paths {
+            let image = &self.files[file];
+            let bytes = if synced_files {
+                &image.synced
+            } else {
+                &image.data
+            };
+            let file = storage.open(path, OpenMode::CreateNew).unwrap();
+            file.write_all_at(bytes, 0).unwrap();
+        }
+        Arc::new(storage)
+    }
+
+    fn torn(&self, op: &TraceOp) -> Option<Arc<MemStorage>> {
+        let TraceOp::Write {
+            file,
+            offset,
+            bytes,
+        } = op
+        else {
+            return None;
+        };
+        let mut disk = self.clone();
+        disk.apply(&TraceOp::Write {
+            file: *file,
+            offset: *offset,
+            bytes: bytes[..bytes.len() / 2].to_vec(),
+        });
+        Some(disk.storage(false, false))
+    }
+}
+
+// Batches of sets, removes and merges of a few keys, so they keep hitting
+// each other
+pub fn workload(seed: u64, batches: usize) -> Vec<WriteBatch> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..batches)
+        .map(|_| {
+            let mut batch = WriteBatch::new();
+            for _ in 0..rng.gen_range(1..=4) {
+                let key = rng.gen_range(0..32);
+                match rng.gen_range(0..4) {
+                    0 | 1 => batch.set(key, rng.gen()),
+                    2 => batch.remove(key),
+                    _ => batch.merge(key, rng.gen_range(0..100)),
+                };
+            }
+            batch
+        })
+        .collect()
+}
+
+fn apply(model: &mut Model, batch: &WriteBatch) {
+    let merge_operator = MergeOperator::default();
+    for op in batch.ops() {
+        match *op {
+            BatchOp::Set { key, value, .. } => {
+                model.insert(key, value);
+            }
+            BatchOp::Remove { key } => {
+                model.remove(&key);
+            }
+            BatchOp::Merge { key, operand } => {
+                let value = merge_operator.full_merge(model.get(&key).copied(), operand);
+                model.insert(key, value);
+            }
+        }
+    }
+}
+
+// `open` must return the table over the given storage, empty the first time,
+// with the default merge operator and durable batches
+pub fn run<T: HashTable, F: FnMut(Arc<dyn Storage>) -> T>(mut open: F, batches: &[WriteBatch]) {
+    let recording = Arc::new(RecordingStorage::new());
+    let mut table = open(recording.clone());
+    let mut models = vec![Model::new()];
+    // Length of the trace when each batch was acknowledged
+    let mut acked_at = Vec::new();
+    for batch in batches {
+        match batch.ops() {
+            [BatchOp::Set {
+                key,
+                value,
+                expires_at: None,
+            }] => table.set(*key, *value),
+            [BatchOp::Remove { key }] => table.remove(*key),
+            [BatchOp::Merge { key, operand }] => table.merge(*key, *operand),
+            _ => table.write(batch),
+        }
+        acked_at.push(recording.trace_len());
+        let mut model = models.last().unwrap().clone();
+        apply(&mut model, batch);
+        models.push(model);
+    }
+    drop(table);
+
+    let trace = recording.trace();
+    let mut disk = Disk::default();
+    for crash_at in 0..=trace.len() {
+        let acked = acked_at.iter().filter(|&&at| at <= crash_at).count();
+        let allowed = &models[acked..models.len().min(acked + 2)];
+        let mut storages = vec![
+            disk.storage(true, true),
+            disk.storage(false, true),
+            disk.storage(false, false),
+        ];
+        if let Some(op) = trace.get(crash_at) {
+            storages.extend(disk.torn(op));
+            disk.apply(op);
+        }
+        for storage in storages {
+            check_recovery(&mut open, storage, allowed, crash_at);
+        }
+    }
+}
+
+fn check_recovery<T: HashTable, F: FnMut(Arc<dyn Storage>) -> T>(
+    open: &mut F,
+    storage: Arc<MemStorage>,
+    allowed: &[Model],
+    crash_at: usize,
+) {
+    let mut table = open(storage.clone());
+    let mut entries = table.iter().collect::<Vec<_>>();
+    entries.sort_unstable();
+    let mut model = allowed
+        .iter()
+        .find(|model| {
+            entries
+                .iter()
+                .copied()
+                .eq(model.iter().map(|(&k, &v)| (k, v)))
+        })
+        .unwrap_or_else(|| panic!("crash after {} operations left {:?}", crash_at, entries))
+        .clone();
+    conformance::assert_matches(&table, &model);
+
+    // The recovered table takes new writes and keeps them
+    let mut batch = WriteBatch::new();
+    batch.set(u64::MAX, crash_at as u64);
+    table.write(&batch);
+    model.insert(u64::MAX, crash_at as u64);
+    drop(table);
+    conformance::assert_matches(&open(storage), &model);
+}
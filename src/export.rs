@@ -0,0 +1,501 @@
+// Streaming export of a table's entries to portable formats, and import of
+// them into any table. Entries that expire carry the time they expire at, in
+// milliseconds since the Unix epoch like the clocks of the tables. Imports go
+// through write batches, and batches of keys that come in increasing order,
+// as in dumps of ordered tables, are written as sorted ones.
+
+use crate::hash_table::HashTable;
+use crate::write_batch::WriteBatch;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    str::FromStr,
+};
+
+const MAGIC: &[u8; 8] = b"hastyex2";
+// Dumps without expiry times, which are still read
+const MAGIC_V1: &[u8; 8] = b"hastyex1";
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    // "key value" lines, "key value expires_at" for entries that expire
+    Text,
+    // {"key":1,"value":2} lines, with "expires_at" for entries that expire
+    JsonLines,
+    // A "key,value,expires_at" header, then a line per entry, with an empty
+    // expires_at for entries that don't expire
+    Csv,
+    // A header, then bincode (key, value, expires_at) with varint integers
+    Binary,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Text, Format::JsonLines, Format::Csv, Format::Binary];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::JsonLines => "jsonl",
+            Format::Csv => "csv",
+            Format::Binary => "bin",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.name() == s)
+            .ok_or_else(|| format!("unknown format {}, expected text, jsonl, csv or bin", s))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    key: u64,
+    value: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Writes every entry of the table and returns how many there were
+pub fn export<W: Write>(table: &dyn HashTable, format: Format, out: W) -> io::Result<usize> {
+    let mut writer = EntryWriter::new(out, format)?;
+    for (key, value, expires_at) in table.iter_with_expiry() {
+        writer.write(key, value, expires_at)?;
+    }
+    writer.finish()
+}
+
+pub struct EntryWriter<W: Write> {
+    out: W,
+    format: Format,
+    written: usize,
+}
+
+impl<W: Write> EntryWriter<W> {
+    pub fn new(mut out: W, format: Format) -> io::Result<Self> {
+        match format {
+            Format::Csv => out.write_all(b"key,value,expires_at\n")?,
+            Format::Binary => out.write_all(MAGIC)?,
+            Format::Text | Format::JsonLines => {}
+        }
+        Ok(EntryWriter {
+            out,
+            format,
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, key: u64, value: u64, expires_at: Option<u64>) -> io::Result<()> {
+        match (self.format, expires_at) {
+            (Format::Text, None) => writeln!(self.out, "{} {}", key, value)?,
+            (Format::Text, Some(expires_at)) => {
+                writeln!(self.out, "{} {} {}", key, value, expires_at)?
+            }
+            (Format::JsonLines, _) => {
+                let entry = JsonEntry {
+                    key,
+                    value,
+                    expires_at,
+                };
+                serde_json::to_writer(&mut self.out, &entry)?;
+                self.out.write_all(b"\n")?;
+            }
+            (Format::Csv, None) => writeln!(self.out, "{},{},", key, value)?,
+            (Format::Csv, Some(expires_at)) => {
+                writeln!(self.out, "{},{},{}", key, value, expires_at)?
+            }
+            (Format::Binary, _) => options()
+                .serialize_into(&mut self.out, &(key, value, expires_at))
+                .map_err(|e| io::Error::other(e.to_string()))?,
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    // Flushes the output and returns how many entries were written
+    pub fn finish(mut self) -> io::Result<usize> {
+        self.out.flush()?;
+        Ok(self.written)
+    }
+}
+
+// The entries of an export in order. Malformed entries are errors that name
+// the line, or the byte offset in a binary dump.
+pub struct EntryReader<R: BufRead> {
+    input: R,
+    format: Format,
+    // False for dumps from before expiry times were exported
+    with_expiry: bool,
+    line: String,
+    // Lines read so far, bytes for binary dumps
+    position: u64,
+}
+
+impl<R: BufRead> EntryReader<R> {
+    pub fn new(input: R, format: Format) -> io::Result<Self> {
+        let mut reader = EntryReader {
+            input,
+            format,
+            with_expiry: true,
+            line: String::new(),
+            position: 0,
+        };
+        match format {
+            Format::Binary => {
+                let mut magic = [0; 8];
+                reader.input.read_exact(&mut magic)?;
+                if &magic != MAGIC && &magic != MAGIC_V1 {
+                    return Err(invalid("not a binary dump".to_string()));
+                }
+                reader.with_expiry = &magic == MAGIC;
+                reader.position = MAGIC.len() as u64;
+            }
+            Format::Csv => {
+                if reader.next_line()? {
+                    match reader.line.trim() {
+                        "key,value,expires_at" => {}
+                        "key,value" => reader.with_expiry = false,
+                        header => {
+                            return Err(invalid(format!(
+                                "line 1: expected the header \"key,value,expires_at\", got {:?}",
+                                header
+                            )))
+                        }
+                    }
+                }
+            }
+            Format::Text | Format::JsonLines => {}
+        }
+        Ok(reader)
+    }
+
+    // Reads the next line that isn't blank, false at the end
+    fn next_line(&mut self) -> io::Result<bool> {
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(false);
+            }
+            self.position += 1;
+            if !self.line.trim().is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+
+    fn parse_line(&self) -> Result<(u64, u64, Option<u64>), String> {
+        let line = self.line.trim();
+        let fields = match self.format {
+            Format::JsonLines => {
+                let entry = serde_json::from_str::<JsonEntry>(line).map_err(|e| e.to_string())?;
+                return Ok((entry.key, entry.value, entry.expires_at));
+            }
+            Format::Csv => line.split(',').map(str::trim).collect::<Vec<_>>(),
+            _ => line.split_whitespace().collect(),
+        };
+        let expected = || match self.format {
+            Format::Csv if self.with_expiry => {
+                format!("expected \"key,value,expires_at\", got {:?}", line)
+            }
+            Format::Csv => format!("expected \"key,value\", got {:?}", line),
+            _ => format!("expected \"key value [expires_at]\", got {:?}", line),
+        };
+        let (key, value, expires_at) = match (self.format, &fields[..]) {
+            (Format::Text, &[key, value]) => (key, value, None),
+            (Format::Text, &[key, value, expires_at]) => (key, value, Some(expires_at)),
+            (Format::Csv, &[key, value]) if !self.with_expiry => (key, value, None),
+            (Format::Csv, &[key, value, ""]) if self.with_expiry => (key, value, None),
+            (Format::Csv, &[key, value, expires_at]) if self.with_expiry => {
+                (key, value, Some(expires_at))
+            }
+            _ => return Err(expected()),
+        };
+        let parse = |field: &str| field.parse::<u64>().map_err(|_| expected());
+        Ok((
+            parse(key)?,
+            parse(value)?,
+            expires_at.map(parse).transpose()?,
+        ))
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<(u64, u64, Option<u64>)>> {
+        if self.format == Format::Binary {
+            if self.input.fill_buf()?.is_empty() {
+                return Ok(None);
+            }
+            let mut counted = CountingReader {
+                input: &mut self.input,
+                read: 0,
+            };
+            let entry = if self.with_expiry {
+                options().deserialize_from(&mut counted)
+            } else {
+                options()
+                    .deserialize_from::<_, (u64, u64)>(&mut counted)
+                    .map(|(key, value)| (key, value, None))
+            }
+            .map_err(|e| invalid(format!("at {}: {}", self.position, e)))?;
+            self.position += counted.read;
+            return Ok(Some(entry));
+        }
+        if !self.next_line()? {
+            return Ok(None);
+        }
+        self.parse_line()
+            .map(Some)
+            .map_err(|message| invalid(format!("line {}: {}", self.position, message)))
+    }
+}
+
+impl<R: BufRead> Iterator for EntryReader<R> {
+    type Item = io::Result<(u64, u64, Option<u64>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+struct CountingReader<'a, R> {
+    input: &'a mut R,
+    read: u64,
+}
+
+impl<R: io::Read> io::Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.input.read(buf)?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+// Sets every entry of the input in the table and returns how many there were,
+// with the expiry it had. The entries before a malformed one are kept.
+pub fn import<R: BufRead>(
+    input: R,
+    format: Format,
+    table: &mut dyn HashTable,
+) -> io::Result<usize> {
+    let mut batch = WriteBatch::new();
+    let mut sorted = true;
+    let write = |table: &mut dyn HashTable, batch: &WriteBatch, sorted: bool| {
+        if sorted {
+            table.write_sorted(batch);
+        } else {
+            table.write(batch);
+        }
+    };
+    let mut imported = 0;
+    for entry in EntryReader::new(input, format)? {
+        let (key, value, expires_at) = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                write(table, &batch, sorted);
+                return Err(err);
+            }
+        };
+        sorted &= batch.ops().last().is_none_or(|op| op.key() < key);
+        match expires_at {
+            Some(expires_at) => batch.set_with_expiry(key, value, expires_at),
+            None => batch.set(key, value),
+        };
+        imported += 1;
+        if batch.len() == BATCH_SIZE {
+            write(table, &batch, sorted);
+            batch.clear();
+            sorted = true;
+        }
+    }
+    write(table, &batch, sorted);
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::linear_probing::{LPHashTable, LPHashTableOptions};
+    use crate::lsmt::{LSMTree, LSMTreeOptions};
+    use crate::storage::MemStorage;
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    fn entries(table: &dyn HashTable) -> HashMap<u64, (u64, Option<u64>)> {
+        table
+            .iter_with_expiry()
+            .map(|(key, value, expires_at)| (key, (value, expires_at)))
+            .collect()
+    }
+
+    #[test]
+    fn export_and_import() {
+        let clock = Arc::new(ManualClock::new(1000));
+        let mut lp = LPHashTable::new(&LPHashTableOptions {
+            clock: clock.clone(),
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        });
+        for key in 0..2500 {
+            if key % 10 == 0 {
+                lp.set_with_ttl(key * 7919, u64::MAX - key, Duration::from_secs(3600));
+            } else {
+                lp.set(key * 7919, u64::MAX - key);
+            }
+        }
+        let expected = entries(&lp);
+        for format in Format::ALL {
+            let mut dump = Vec::new();
+            assert_eq!(export(&lp, format, &mut dump).unwrap(), 2500);
+            let mut lsm = LSMTree::new(&LSMTreeOptions {
+                clock: clock.clone(),
+                storage: Arc::new(MemStorage::new()),
+                ..Default::default()
+            });
+            assert_eq!(
+                import(&dump[..], format, &mut lsm).unwrap(),
+                2500,
+                "{}",
+                format
+            );
+            assert_eq!(entries(&lsm), expected, "{}", format);
+            if format == Format::Binary {
+                // Varint keys take 5 bytes, values 9 and expiry times 5, plus
+                // a byte that tells whether there is one
+                assert!(dump.len() <= 8 + 2500 * 15 + 250 * 5, "{}", dump.len());
+            }
+        }
+    }
+
+    #[test]
+    fn sorted_import() {
+        let clock = Arc::new(ManualClock::new(1000));
+        let options = LSMTreeOptions {
+            clock: clock.clone(),
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        };
+        let mut lsm = LSMTree::new(&options);
+        for key in 0..2500 {
+            lsm.set_with_ttl(key, key, Duration::from_secs(key));
+        }
+        let mut dump = Vec::new();
+        export(&lsm, Format::Text, &mut dump).unwrap();
+
+        // The dump of an LSM tree is sorted, so it is loaded as disktables
+        // without going through the write-ahead log. Key 0 expired when it
+        // was set and isn't in it.
+        let mut loaded = LSMTree::new(&LSMTreeOptions {
+            dir: "sorted_import".to_string(),
+            ..options
+        });
+        loaded.set(3000, 1);
+        assert_eq!(import(&dump[..], Format::Text, &mut loaded).unwrap(), 2499);
+        let stats = loaded.stats();
+        assert_eq!((stats.memtable_entries, stats.wal_bytes), (0, 0));
+        assert_eq!(
+            stats.disktables.iter().map(|d| d.entries).sum::<usize>(),
+            2500
+        );
+        assert_eq!(loaded.get(3000), Some(1));
+        assert_eq!(entries(&loaded).len(), 2500);
+        let expected = entries(&lsm);
+        assert!(entries(&loaded)
+            .into_iter()
+            .all(|(key, entry)| key == 3000 || expected[&key] == entry));
+        clock.advance(Duration::from_secs(2000));
+        assert_eq!(loaded.get(2000), None);
+        assert_eq!(loaded.get(2001), Some(2001));
+    }
+
+    #[test]
+    fn malformed_input() {
+        let mut lp = LPHashTable::new(&LPHashTableOptions {
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        });
+        let err = import(&b"1 2\n\n3 x\n4 5\n"[..], Format::Text, &mut lp).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3: expected \"key value [expires_at]\", got \"3 x\""
+        );
+        // The entries before the error are in
+        assert_eq!(lp.get(1), Some(2));
+        assert_eq!(lp.get(4), None);
+
+        let err = import(&b"key,value\n1,2,3\n"[..], Format::Csv, &mut lp).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: expected \"key,value\", got \"1,2,3\""
+        );
+        assert!(import(&b"1,2\n"[..], Format::Csv, &mut lp).is_err());
+        let err = import(&b"{\"key\":1}\n"[..], Format::JsonLines, &mut lp).unwrap_err();
+        assert!(err.to_string().starts_with("line 1: missing field `value`"));
+
+        let mut dump = Vec::new();
+        let mut writer = EntryWriter::new(&mut dump, Format::Binary).unwrap();
+        writer.write(1, 1 << 40, None).unwrap();
+        writer.finish().unwrap();
+        let torn = EntryReader::new(&dump[..dump.len() - 1], Format::Binary).unwrap();
+        assert!(torn
+            .last()
+            .unwrap()
+            .unwrap_err()
+            .to_string()
+            .starts_with("at 8:"));
+        assert!(EntryReader::new(&b"1 2\n"[..], Format::Binary).is_err());
+        let err = import(&b"key,value,expires_at\n1,2\n"[..], Format::Csv, &mut lp).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: expected \"key,value,expires_at\", got \"1,2\""
+        );
+    }
+
+    #[test]
+    fn dumps_without_expiry() {
+        let mut binary = MAGIC_V1.to_vec();
+        for entry in [(1u64, 2u64), (3, 1 << 40)] {
+            options().serialize_into(&mut binary, &entry).unwrap();
+        }
+        let dumps: [(&[u8], Format); 4] = [
+            (b"1 2\n3 1099511627776\n", Format::Text),
+            (
+                b"{\"key\":1,\"value\":2}\n{\"key\":3,\"value\":1099511627776}\n",
+                Format::JsonLines,
+            ),
+            (b"key,value\n1,2\n3,1099511627776\n", Format::Csv),
+            (&binary, Format::Binary),
+        ];
+        for (dump, format) in dumps {
+            let entries = EntryReader::new(dump, format)
+                .unwrap()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(
+                entries,
+                vec![(1, 2, None), (3, 1 << 40, None)],
+                "{}",
+                format
+            );
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// Reports of the offline integrity checks, LPHashTable::fsck and
+// LSMTree::fsck
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub path: String,
+    // Byte offset in the file, None when it's about the file as a whole
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at {}: {}", self.path, offset, self.message),
+            None => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    // Entries that were read and checked
+    pub entries: usize,
+    pub problems: Vec<Problem>,
+    // What the repair mode did about them
+    pub repairs: Vec<String>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(crate) fn problem(&mut self, path: &str, offset: Option<u64>, message: String) {
+        self.problems.push(Problem {
+            path: path.to_string(),
+            offset,
+            message,
+        });
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} entries checked, {} problems",
+            self.entries,
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        for repair in &self.repairs {
+            writeln!(f, "repaired: {}", repair)?;
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,40 @@
+use crate::write_batch::WriteBatch;
+use std::time::Duration;
+
 pub trait HashTable {
     fn set(&mut self, key: u64, value: u64);
+    // The entry disappears once `ttl` has passed on the table's clock
+    fn set_with_ttl(&mut self, key: u64, value: u64, ttl: Duration);
     fn get(&self, key: u64) -> Option<u64>;
+    fn get_many(&self, keys: &[u64]) -> Vec<Option<u64>> {
+        keys.iter().map(|&key| self.get(key)).collect()
+    }
     fn remove(&mut self, key: u64);
+    // Folds `operand` into the value with the table's merge operator, the
+    // merged value keeps the expiry of the one it replaces
+    fn merge(&mut self, key: u64, operand: u64);
+    fn write(&mut self, batch: &WriteBatch);
+    // A batch of distinct keys in increasing order, which tables that keep
+    // sorted files can write as a file of its own
+    fn write_sorted(&mut self, batch: &WriteBatch) {
+        self.write(batch);
+    }
+    fn on_disk_size(&self) -> usize;
+    // Every live (key, value) pair once, in no particular order
+    fn iter(&self) -> Box<dyn Iterator<Item = (u64, u64)> + '_> {
+        Box::new(self.iter_with_expiry().map(|(key, value, _)| (key, value)))
+    }
+    // The same with the time each entry expires at, if it does
+    fn iter_with_expiry(&self) -> Box<dyn Iterator<Item = (u64, u64, Option<u64>)> + '_>;
+}
+
+// Tables that can be shared between threads
+pub trait ConcurrentHashTable: Send + Sync {
+    fn set(&self, key: u64, value: u64);
+    fn set_with_ttl(&self, key: u64, value: u64, ttl: Duration);
+    fn get(&self, key: u64) -> Option<u64>;
+    fn remove(&self, key: u64);
+    fn merge(&self, key: u64, operand: u64);
+    fn write(&self, batch: &WriteBatch);
     fn on_disk_size(&self) -> usize;
 }
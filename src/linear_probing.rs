@@ -1,31 +1,132 @@
+mod fsck;
+mod striped;
+
+use crate::clock::{Clock, SystemClock};
 use crate::hash_table::HashTable;
+use crate::merge::MergeOperator;
+use crate::record_log::RecordLog;
+use crate::storage::{self, FsStorage, OpenMode, Storage, StorageFile};
+use crate::write_batch::{BatchOp, WriteBatch};
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
-use std::io::prelude::*;
-use std::os::unix::prelude::FileExt;
-use std::{fs::OpenOptions, mem::size_of};
+use std::mem::size_of;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Duration;
+pub use striped::{StripedLPHashTable, StripedLPHashTableOptions};
+
+// A table file is a header and then the slots. The baseline format, version
+// 1, had 17 byte slots without expiry and no header, its files are migrated
+// when they're opened.
+const MAGIC: &[u8; 8] = b"hastylpt";
+const VERSION: u32 = 2;
+pub const HEADER_SIZE: usize = MAGIC.len() + size_of::<u32>() + size_of::<u32>();
+const V1_SLOT_SIZE: usize = 1 + size_of::<u64>() + size_of::<u64>();
 
 pub struct LPHashTable {
-    file: std::fs::File,
+    file: Box<dyn StorageFile>,
     capacity: usize,
     len: usize,
     load_factor: f64,
     used_capacity: usize,
     block_size: usize,
+    redo_log: RecordLog,
+    sync: bool,
+    // Slot writes of the batch being applied, which go to the redo log
+    // before they reach the file. Every change to the slots is staged.
+    staged: Option<Staged>,
+    merge_operator: MergeOperator,
+    clock: Arc<dyn Clock>,
+    // The file never grows, new keys evict old ones instead
+    bounded: bool,
+    hand: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Staged {
+    slots: BTreeMap<u64, Vec<u8>>,
+    // Slots past the end of the file are empty, the file grows when the
+    // batch is written
+    file_len: u64,
+}
+
+// The outcome of a batch: the slots it wrote and the table size after it.
+// Replaying it is idempotent, so it also finishes a batch that only partly
+// reached the file before a crash.
+#[derive(Serialize, Deserialize)]
+struct RedoRecord {
+    len: usize,
+    used_capacity: usize,
+    slots: Vec<(u64, Vec<u8>)>,
 }
 
+#[derive(Clone, Debug)]
 pub struct LPHashTableOptions {
     pub filename: String,
+    pub merge_operator: MergeOperator,
+    pub clock: Arc<dyn Clock>,
+    // Turns the table into a cache of at most this many bytes on disk
+    pub max_file_size: Option<usize>,
+    // Slots of a new table and of every block it grows by, a power of 2
+    pub block_size: usize,
+    pub storage: Arc<dyn Storage>,
+    // fsync the redo log and the file after every write
+    pub sync: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LPCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+impl Default for LPHashTableOptions {
+    fn default() -> Self {
+        LPHashTableOptions {
+            filename: "lp.bin".to_string(),
+            merge_operator: MergeOperator::default(),
+            clock: Arc::new(SystemClock),
+            max_file_size: None,
+            block_size: LPHashTable::initial_capacity(),
+            storage: Arc::new(FsStorage),
+            sync: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LPHashTableStats {
+    pub capacity: usize,
+    pub used_capacity: usize,
+    pub len: usize,
+    // The table grows once the load goes over this
+    pub load_factor: f64,
+    // probe_lengths[n] entries sit n slots after their home slot
+    pub probe_lengths: Vec<usize>,
 }
 
+impl LPHashTableStats {
+    pub fn load(&self) -> f64 {
+        self.len as f64 / self.used_capacity as f64
+    }
+}
+
+// (key, value, expiry time in milliseconds since the Unix epoch, CLOCK
+// reference bit of the bounded mode)
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
-pub struct LPHashTableEntry(pub Option<(u64, u64)>);
+pub struct LPHashTableEntry(pub Option<(u64, u64, Option<u64>, bool)>);
 
 impl LPHashTableEntry {
     pub const fn bin_size() -> usize {
-        size_of::<u64>() + size_of::<u64>() + 1
+        1 + size_of::<u64>() + size_of::<u64>() + 1 + size_of::<u64>() + 1
     }
 
     pub fn serialize(&self) -> bincode::Result<Vec<u8>> {
@@ -46,70 +147,214 @@ impl LPHashTableEntry {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(LPHashTableEntry::bin_size() as u32).to_le_bytes());
+    header
+}
 
-    #[test]
-    fn entry() {
-        let test_entries = vec![
-            LPHashTableEntry(None),
-            LPHashTableEntry(Some((0, 0))),
-            LPHashTableEntry(Some((1, 1))),
-            LPHashTableEntry(Some((u64::MAX, u64::MAX))),
-        ];
-        for entry in test_entries {
-            let bytes = entry.serialize().unwrap();
-            assert_eq!(bytes.len(), LPHashTableEntry::bin_size());
-            let entry2 = LPHashTableEntry::deserialize(&bytes).unwrap();
-            assert_eq!(entry, entry2);
+fn check_header(bytes: &[u8]) -> Result<(), String> {
+    if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+        return Err("not a table file".to_string());
+    }
+    let field = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+    let (version, slot_size) = (field(MAGIC.len()), field(MAGIC.len() + 4));
+    if version != VERSION || slot_size as usize != LPHashTableEntry::bin_size() {
+        return Err(format!(
+            "unsupported version {} with {} byte slots",
+            version, slot_size
+        ));
+    }
+    Ok(())
+}
+
+// The entries of a version 1 file, None if it isn't one
+fn read_v1(bytes: &[u8]) -> Option<Vec<(u64, u64)>> {
+    if bytes.is_empty() || !bytes.len().is_multiple_of(V1_SLOT_SIZE) {
+        return None;
+    }
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
+    let mut entries = Vec::new();
+    for slot in bytes.chunks_exact(V1_SLOT_SIZE) {
+        if let Some(entry) = options.deserialize::<Option<(u64, u64)>>(slot).ok()? {
+            entries.push(entry);
         }
     }
+    Some(entries)
 }
 
 impl LPHashTable {
     pub fn new(options: &LPHashTableOptions) -> Self {
-        let file_exists = std::path::Path::new(&options.filename).exists();
-        if file_exists {
-            println!("File already exists");
+        assert!(options.block_size.is_power_of_two());
+        let storage = &*options.storage;
+        let file_exists = storage.exists(&options.filename);
+        if !file_exists {
+            let slots = match options.max_file_size {
+                Some(size) => {
+                    (size.saturating_sub(HEADER_SIZE) / LPHashTableEntry::bin_size()).max(2)
+                }
+                None => options.block_size,
+            };
+            let mut bytes = header();
+            bytes.extend(LPHashTableEntry(None).serialize().unwrap().repeat(slots));
+            // A crash can't leave a table without all its slots behind
+            storage::write_atomically(storage, &options.filename, &bytes).unwrap();
         }
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&options.filename)
-            .unwrap();
-        let mut capacity;
+        let file = storage.open(&options.filename, OpenMode::Existing).unwrap();
+        let mut header = vec![0; HEADER_SIZE.min(file.len().unwrap() as usize)];
+        file.read_exact_at(&mut header, 0).unwrap();
+        if let Err(err) = check_header(&header) {
+            drop(file);
+            Self::migrate_v1(options).unwrap_or_else(|| panic!("{}: {}", options.filename, err));
+            return Self::new(options);
+        }
+        let used_capacity =
+            (file.len().unwrap() as usize - HEADER_SIZE) / LPHashTableEntry::bin_size();
         let mut len = 0usize;
-        if !file_exists {
-            capacity = 1;
-            while capacity * LPHashTableEntry::bin_size() < 2 * 1024 * 1024 {
-                capacity *= 2;
-            } // capacity is a power of 2
-            for _ in 0..capacity {
-                let entry = LPHashTableEntry(None);
-                let bytes = entry.serialize().unwrap();
-                file.write_all(&bytes).unwrap();
-            }
-        } else {
-            debug_assert!(false);
-            capacity = file.metadata().unwrap().len() as usize / LPHashTableEntry::bin_size();
-            for pos in 0..capacity {
-                if let Some(_) =
-                    Self::read_pos(&file, (pos * LPHashTableEntry::bin_size()) as u64).0
+        if file_exists {
+            for pos in 0..used_capacity {
+                if Self::read_pos(&*file, (pos * LPHashTableEntry::bin_size()) as u64)
+                    .0
+                    .is_some()
                 {
                     len += 1;
                 }
             }
         }
 
-        LPHashTable {
+        let (redo_log, records) =
+            RecordLog::open(storage, &format!("{}.redo", options.filename)).unwrap();
+        let mut table = LPHashTable {
             file,
-            capacity,
+            // used_capacity grows by block_size steps and capacity doubles
+            // whenever it is reached, so it is the next power of 2
+            capacity: used_capacity.next_power_of_two(),
             len,
             load_factor: 0.5,
-            used_capacity: capacity,
-            block_size: capacity,
+            used_capacity,
+            block_size: options.block_size,
+            redo_log,
+            sync: options.sync,
+            staged: None,
+            merge_operator: options.merge_operator.clone(),
+            clock: options.clock.clone(),
+            bounded: options.max_file_size.is_some(),
+            hand: 0,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        };
+        if !records.is_empty() {
+            for record in records {
+                table.write_record(&record);
+            }
+            table.file.sync().unwrap();
+            table.redo_log.clear().unwrap();
+        }
+        table
+    }
+
+    // Rebuilds a version 1 file in the current format, in place
+    fn migrate_v1(options: &LPHashTableOptions) -> Option<()> {
+        let storage = &*options.storage;
+        let entries = read_v1(&storage::read_file(storage, &options.filename).ok()?)?;
+        let migrated = format!("{}.migrate", options.filename);
+        for stale in [migrated.clone(), format!("{}.redo", migrated)] {
+            if storage.exists(&stale) {
+                storage.remove(&stale).unwrap();
+            }
+        }
+        {
+            let mut table = LPHashTable::new(&LPHashTableOptions {
+                filename: migrated.clone(),
+                ..options.clone()
+            });
+            for chunk in entries.chunks(1000) {
+                let mut batch = WriteBatch::new();
+                for &(key, value) in chunk {
+                    batch.set(key, value);
+                }
+                table.write(&batch);
+            }
+        }
+        storage.remove(&format!("{}.redo", migrated)).unwrap();
+        storage.rename(&migrated, &options.filename).unwrap();
+        storage
+            .sync_dir(storage::parent_dir(&options.filename))
+            .unwrap();
+        Some(())
+    }
+
+    // Smallest power of 2 that takes at least 2 MiB on disk
+    const fn initial_capacity() -> usize {
+        let mut capacity = 1;
+        while capacity * LPHashTableEntry::bin_size() < 2 * 1024 * 1024 {
+            capacity *= 2;
+        }
+        capacity
+    }
+
+    // Applies the ops to the table in memory only and returns the redo record
+    // that writes them to the file
+    fn stage<'a>(&mut self, ops: impl IntoIterator<Item = &'a BatchOp>) -> Vec<u8> {
+        let record = self.stage_record(ops);
+        bincode::DefaultOptions::new().serialize(&record).unwrap()
+    }
+
+    fn stage_record<'a>(&mut self, ops: impl IntoIterator<Item = &'a BatchOp>) -> RedoRecord {
+        self.staged = Some(Staged {
+            slots: BTreeMap::new(),
+            file_len: (self.used_capacity * LPHashTableEntry::bin_size()) as u64,
+        });
+        for op in ops {
+            self.apply_op(op);
+        }
+        let staged = self.staged.take().unwrap();
+        RedoRecord {
+            len: self.len,
+            used_capacity: self.used_capacity,
+            slots: staged.slots.into_iter().collect(),
+        }
+    }
+
+    fn write_record(&mut self, record: &[u8]) {
+        self.apply_record(bincode::DefaultOptions::new().deserialize(record).unwrap());
+    }
+
+    fn apply_record(&mut self, record: RedoRecord) {
+        let step = LPHashTableEntry::bin_size();
+        let file_slots = (self.file.len().unwrap() as usize - HEADER_SIZE) / step;
+        if file_slots < record.used_capacity {
+            let empty_block = LPHashTableEntry(None)
+                .serialize()
+                .unwrap()
+                .repeat(record.used_capacity - file_slots);
+            self.file
+                .write_all_at(&empty_block, (HEADER_SIZE + file_slots * step) as u64)
+                .unwrap();
+        }
+        for (pos, bytes) in record.slots {
+            self.file
+                .write_all_at(&bytes, HEADER_SIZE as u64 + pos)
+                .unwrap();
+        }
+        self.len = record.len;
+        self.used_capacity = record.used_capacity;
+        self.capacity = record.used_capacity.next_power_of_two();
+    }
+
+    fn apply_op(&mut self, op: &BatchOp) {
+        match *op {
+            BatchOp::Set {
+                key,
+                value,
+                expires_at,
+            } => self.insert(key, value, expires_at),
+            BatchOp::Remove { key } => self.remove_key(key),
+            BatchOp::Merge { key, operand } => self.merge_key(key, operand),
         }
     }
 
@@ -119,34 +364,57 @@ impl LPHashTable {
         hasher.finish()
     }
 
-    fn read_pos(file: &std::fs::File, pos: u64) -> LPHashTableEntry {
-        let capacity = file.metadata().unwrap().len() as usize / LPHashTableEntry::bin_size();
-        debug_assert!(pos < capacity as u64 * LPHashTableEntry::bin_size() as u64);
+    // Positions are offsets of slots after the header
+    fn read_pos(file: &dyn StorageFile, pos: u64) -> LPHashTableEntry {
+        debug_assert!(HEADER_SIZE as u64 + pos < file.len().unwrap());
         debug_assert_eq!(pos % LPHashTableEntry::bin_size() as u64, 0);
         let mut bytes = [0; LPHashTableEntry::bin_size()];
-        file.read_at(&mut bytes, pos).unwrap();
+        file.read_exact_at(&mut bytes, HEADER_SIZE as u64 + pos)
+            .unwrap();
         LPHashTableEntry::deserialize(&bytes).unwrap()
     }
 
-    fn key_to_pos(&self, key: u64) -> u64 {
-        let cell_num = Self::hash(key) % self.capacity as u64;
-        if cell_num < self.used_capacity as u64 {
-            cell_num * LPHashTableEntry::bin_size() as u64
+    fn read_slot(&self, pos: u64) -> LPHashTableEntry {
+        if let Some(staged) = &self.staged {
+            if let Some(bytes) = staged.slots.get(&pos) {
+                return LPHashTableEntry::deserialize(bytes).unwrap();
+            }
+            if pos >= staged.file_len {
+                return LPHashTableEntry(None);
+            }
+        }
+        Self::read_pos(&*self.file, pos)
+    }
+
+    fn write_slot(&mut self, pos: u64, bytes: Vec<u8>) {
+        self.staged.as_mut().unwrap().slots.insert(pos, bytes);
+    }
+
+    // Slot that the probe sequence of `key` starts at
+    fn home_slot(key: u64, capacity: usize, used_capacity: usize) -> usize {
+        let cell_num = (Self::hash(key) % capacity as u64) as usize;
+        if cell_num < used_capacity {
+            cell_num
         } else {
-            (cell_num - (self.capacity / 2) as u64) * LPHashTableEntry::bin_size() as u64
+            cell_num - capacity / 2
         }
     }
 
+    fn key_to_pos(&self, key: u64) -> u64 {
+        let slot = Self::home_slot(key, self.capacity, self.used_capacity);
+        (slot * LPHashTableEntry::bin_size()) as u64
+    }
+
     fn read_key(&self, key: u64) -> (u64, LPHashTableEntry) {
         let mut pos = self.key_to_pos(key);
         let mut cur_entry;
         loop {
-            cur_entry = Self::read_pos(&self.file, pos);
+            cur_entry = self.read_slot(pos);
             match cur_entry {
                 LPHashTableEntry(None) => {
                     break;
                 }
-                LPHashTableEntry(Some((cur_key, _))) => {
+                LPHashTableEntry(Some((cur_key, _, _, _))) => {
                     if cur_key == key {
                         break;
                     }
@@ -161,96 +429,594 @@ impl LPHashTable {
     }
 
     fn resize_if_needed(&mut self) {
-        if (self.len as f64 / self.used_capacity as f64) < self.load_factor {
+        if self.bounded || (self.len as f64 / self.used_capacity as f64) < self.load_factor {
             return;
         }
         if self.used_capacity == self.capacity {
             self.capacity *= 2;
         }
 
-        for _ in 0..self.block_size {
-            let empty_entry_bytes = LPHashTableEntry(None).serialize().unwrap();
-            self.file.write_all(&empty_entry_bytes).unwrap();
+        // Keys of the split region may belong to the appended block now. The
+        // cluster running on from the region and the one that wrapped around
+        // to the start of the table are reinserted too, as the table end
+        // moves and holes in them would cut probe sequences short.
+        let step = LPHashTableEntry::bin_size() as u64;
+        let table_size = self.used_capacity as u64 * step;
+        let start = (self.used_capacity - self.capacity / 2) as u64 * step;
+        let mut moved = BTreeMap::new();
+        for (first, region) in [(start, self.block_size), (0, 0)] {
+            let mut pos = first;
+            for steps in 0..self.used_capacity {
+                match self.read_slot(pos).0 {
+                    None if steps >= region => break,
+                    None => {}
+                    Some(entry) => {
+                        moved.insert(pos, entry);
+                    }
+                }
+                pos = (pos + step) % table_size;
+            }
         }
 
-        let start = self.used_capacity - self.capacity / 2;
-
+        // The appended block reads as empty until the batch is written
         self.used_capacity += self.block_size;
-        for pos in start..(start + self.block_size) {
-            let pos = pos * LPHashTableEntry::bin_size();
-            if let Some((key, value)) = Self::read_pos(&self.file, pos as u64).0 {
-                let new_pos = self.key_to_pos(key);
-                if new_pos == pos as u64 {
-                    continue;
+
+        let empty_entry_bytes = LPHashTableEntry(None).serialize().unwrap();
+        for &pos in moved.keys() {
+            self.write_slot(pos, empty_entry_bytes.clone());
+        }
+        self.len -= moved.len();
+        for (key, value, expires_at, _) in moved.into_values() {
+            // Expired entries are dropped on the way
+            if !self.clock.is_expired(expires_at) {
+                self.place(key, value, expires_at);
+            }
+        }
+    }
+
+    // Read-modify-write of the slot the key is in or will be put in
+    fn merge_key(&mut self, key: u64, operand: u64) {
+        self.reclaim_expired(key);
+        self.make_room(key);
+        let (pos, pos_entry) = self.read_key(key);
+        let (value, expires_at, referenced) = match pos_entry.0 {
+            Some((_, value, expires_at, referenced)) => (Some(value), expires_at, referenced),
+            None => {
+                self.len += 1;
+                (None, None, false)
+            }
+        };
+        let value = self.merge_operator.full_merge(value, operand);
+        let bytes = LPHashTableEntry(Some((key, value, expires_at, referenced)))
+            .serialize()
+            .unwrap();
+        self.write_slot(pos, bytes);
+
+        self.resize_if_needed();
+    }
+
+    fn remove_key(&mut self, key: u64) {
+        self.reclaim_expired(key);
+        let (pos, pos_entry) = self.read_key(key);
+        if pos_entry != LPHashTableEntry(None) {
+            self.remove_at(pos);
+        }
+    }
+
+    fn place(&mut self, key: u64, value: u64, expires_at: Option<u64>) {
+        let entry = LPHashTableEntry(Some((key, value, expires_at, false)));
+        let bytes = entry.serialize().unwrap();
+        let (pos, pos_entry) = self.read_key(key);
+        if pos_entry == LPHashTableEntry(None) {
+            self.len += 1;
+        }
+        self.write_slot(pos, bytes);
+    }
+
+    fn insert(&mut self, key: u64, value: u64, expires_at: Option<u64>) {
+        self.reclaim_expired(key);
+        self.make_room(key);
+        self.place(key, value, expires_at);
+        self.resize_if_needed();
+    }
+
+    // A new key in a full bounded table takes the place of an evicted one
+    fn make_room(&mut self, key: u64) {
+        if !self.bounded
+            || ((self.len + 1) as f64 / self.used_capacity as f64) <= self.load_factor
+            || self.read_key(key).1 .0.is_some()
+        {
+            return;
+        }
+        self.evict();
+    }
+
+    // CLOCK: the hand sweeps the slots, clearing reference bits, and evicts
+    // the first entry that wasn't used since the last sweep or has expired
+    fn evict(&mut self) {
+        let step = LPHashTableEntry::bin_size() as u64;
+        loop {
+            let pos = self.hand as u64 * step;
+            match self.read_slot(pos).0 {
+                None => {}
+                Some((key, value, expires_at, true)) if !self.clock.is_expired(expires_at) => {
+                    let bytes = LPHashTableEntry(Some((key, value, expires_at, false)))
+                        .serialize()
+                        .unwrap();
+                    self.write_slot(pos, bytes);
+                }
+                Some(_) => {
+                    // The hand stays, an entry of the cluster may move into the hole
+                    self.remove_at(pos);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    return;
                 }
+            }
+            self.hand = (self.hand + 1) % self.used_capacity;
+        }
+    }
 
-                let mut pos = pos;
-                loop {
-                    let cur_entry = Self::read_pos(&self.file, pos as u64);
-                    match cur_entry {
-                        LPHashTableEntry(None) => {
-                            debug_assert!(false);
-                        }
-                        LPHashTableEntry(Some((cur_key, _))) => {
-                            if cur_key == key {
-                                break;
-                            }
-                        }
+    // Reads every slot, a chunk at a time
+    pub fn stats(&self) -> LPHashTableStats {
+        let bin_size = LPHashTableEntry::bin_size();
+        let mut probe_lengths = Vec::new();
+        let mut chunk = Vec::new();
+        for start in (0..self.used_capacity).step_by(LPHashTableIter::CHUNK_SLOTS) {
+            let slots = LPHashTableIter::CHUNK_SLOTS.min(self.used_capacity - start);
+            chunk.resize(slots * bin_size, 0);
+            self.file
+                .read_exact_at(&mut chunk, (HEADER_SIZE + start * bin_size) as u64)
+                .unwrap();
+            for (i, bytes) in chunk.chunks(bin_size).enumerate() {
+                if let LPHashTableEntry(Some((key, ..))) =
+                    LPHashTableEntry::deserialize(bytes).unwrap()
+                {
+                    let home = self.key_to_pos(key) as usize / bin_size;
+                    let probe_length = (start + i + self.used_capacity - home) % self.used_capacity;
+                    if probe_lengths.len() <= probe_length {
+                        probe_lengths.resize(probe_length + 1, 0);
                     }
-                    pos += LPHashTableEntry::bin_size();
-                    if pos >= self.used_capacity * LPHashTableEntry::bin_size() {
-                        pos = 0;
+                    probe_lengths[probe_length] += 1;
+                }
+            }
+        }
+        LPHashTableStats {
+            capacity: self.capacity,
+            used_capacity: self.used_capacity,
+            len: self.len,
+            load_factor: self.load_factor,
+            probe_lengths,
+        }
+    }
+
+    pub fn cache_stats(&self) -> LPCacheStats {
+        LPCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    // Removes the expired entries on the probe sequence of `key`, so writers
+    // clean up after the entries they run into
+    fn reclaim_expired(&mut self, key: u64) {
+        let step = LPHashTableEntry::bin_size() as u64;
+        let mut pos = self.key_to_pos(key);
+        loop {
+            match self.read_slot(pos).0 {
+                None => break,
+                Some((_, _, expires_at, _)) if self.clock.is_expired(expires_at) => {
+                    self.remove_at(pos)
+                }
+                Some(_) => pos = (pos + step) % (self.used_capacity as u64 * step),
+            }
+        }
+    }
+
+    // Backward shift deletion: pull the following entries of the cluster
+    // into the hole, so that no probe sequence runs into an empty slot
+    fn remove_at(&mut self, mut hole: u64) {
+        self.len -= 1;
+        let step = LPHashTableEntry::bin_size() as u64;
+        let table_size = self.used_capacity as u64 * step;
+        let mut pos = hole;
+        loop {
+            pos = (pos + step) % table_size;
+            let entry = self.read_slot(pos);
+            let Some((cur_key, _, _, _)) = entry.0 else {
+                break;
+            };
+            // The entry can't move if its home is cyclically in (hole, pos]
+            let home = self.key_to_pos(cur_key);
+            if (pos + table_size - home) % table_size >= (pos + table_size - hole) % table_size {
+                let bytes = entry.serialize().unwrap();
+                self.write_slot(hole, bytes);
+                hole = pos;
+            }
+        }
+        let bytes = LPHashTableEntry(None).serialize().unwrap();
+        self.write_slot(hole, bytes);
+    }
+}
+
+// Scans the slots in file order, a chunk at a time
+pub struct LPHashTableIter<'a> {
+    table: &'a LPHashTable,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    next_slot: usize,
+}
+
+impl<'a> LPHashTableIter<'a> {
+    const CHUNK_SLOTS: usize = 4096;
+}
+
+impl<'a> Iterator for LPHashTableIter<'a> {
+    type Item = (u64, u64, Option<u64>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.chunk_pos < self.chunk.len() {
+                let bytes =
+                    &self.chunk[self.chunk_pos..self.chunk_pos + LPHashTableEntry::bin_size()];
+                self.chunk_pos += LPHashTableEntry::bin_size();
+                if let LPHashTableEntry(Some((key, value, expires_at, _))) =
+                    LPHashTableEntry::deserialize(bytes).unwrap()
+                {
+                    if !self.table.clock.is_expired(expires_at) {
+                        return Some((key, value, expires_at));
                     }
                 }
-                let empty_entry_bytes = LPHashTableEntry(None).serialize().unwrap();
-                self.file
-                    .write_all_at(&empty_entry_bytes, pos as u64)
-                    .unwrap();
-                self.len -= 1;
-                self.set(key, value);
+                continue;
+            }
+            if self.next_slot == self.table.used_capacity {
+                return None;
             }
+            let slots = Self::CHUNK_SLOTS.min(self.table.used_capacity - self.next_slot);
+            self.chunk.resize(slots * LPHashTableEntry::bin_size(), 0);
+            self.table
+                .file
+                .read_exact_at(
+                    &mut self.chunk,
+                    (HEADER_SIZE + self.next_slot * LPHashTableEntry::bin_size()) as u64,
+                )
+                .unwrap();
+            self.chunk_pos = 0;
+            self.next_slot += slots;
         }
     }
 }
 
 impl HashTable for LPHashTable {
     fn set(&mut self, key: u64, value: u64) {
-        let entry = LPHashTableEntry(Some((key, value)));
-        let bytes = entry.serialize().unwrap();
-        let (pos, pos_entry) = self.read_key(key);
-        if pos_entry == LPHashTableEntry(None) {
-            self.len += 1;
-        }
-        self.file.write_all_at(&bytes, pos).unwrap();
+        self.write(WriteBatch::new().set(key, value));
+    }
 
-        self.resize_if_needed();
+    fn set_with_ttl(&mut self, key: u64, value: u64, ttl: Duration) {
+        let expires_at = self.clock.expires_at(ttl);
+        self.write(WriteBatch::new().set_with_expiry(key, value, expires_at));
     }
 
+    // A hit in a bounded table sets the reference bit of the entry, which
+    // saves it from the next sweep of the CLOCK hand
     fn get(&self, key: u64) -> Option<u64> {
-        let (_, entry) = self.read_key(key);
+        let (pos, entry) = self.read_key(key);
         match entry {
-            LPHashTableEntry(None) => None,
-            LPHashTableEntry(Some((_, value))) => Some(value),
+            LPHashTableEntry(Some((_, value, expires_at, referenced)))
+                if !self.clock.is_expired(expires_at) =>
+            {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                if self.bounded && !referenced {
+                    let bytes = LPHashTableEntry(Some((key, value, expires_at, true)))
+                        .serialize()
+                        .unwrap();
+                    self.file
+                        .write_all_at(&bytes, HEADER_SIZE as u64 + pos)
+                        .unwrap();
+                }
+                Some(value)
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    // Probing the keys in slot order makes the reads sequential
+    fn get_many(&self, keys: &[u64]) -> Vec<Option<u64>> {
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| self.key_to_pos(keys[i]));
+        let mut values = vec![None; keys.len()];
+        for i in order {
+            values[i] = self.get(keys[i]);
         }
+        values
     }
 
     fn remove(&mut self, key: u64) {
-        let (pos, pos_entry) = self.read_key(key);
-        if pos_entry != LPHashTableEntry(None) {
-            self.len -= 1;
-            let entry = LPHashTableEntry(None);
-            let bytes = entry.serialize().unwrap();
-            self.file.write_all_at(&bytes, pos).unwrap();
+        self.write(WriteBatch::new().remove(key));
+    }
+
+    fn merge(&mut self, key: u64, operand: u64) {
+        self.write(WriteBatch::new().merge(key, operand));
+    }
+
+    // Slots that take more than one write of the file, those of a batch or
+    // of a resize, go through the redo log, so they all reach the file or
+    // none of them. Without sync, a change of one slot in place is a single
+    // write that a crash of the process can't tear.
+    fn write(&mut self, batch: &WriteBatch) {
+        let used_capacity = self.used_capacity;
+        let record = self.stage_record(batch.ops());
+        if !self.sync && record.slots.len() <= 1 && record.used_capacity == used_capacity {
+            self.apply_record(record);
+            return;
+        }
+        let bytes = bincode::DefaultOptions::new().serialize(&record).unwrap();
+        self.redo_log.append(&bytes).unwrap();
+        if self.sync {
+            self.redo_log.sync().unwrap();
         }
+        self.apply_record(record);
+        if self.sync {
+            self.file.sync().unwrap();
+        }
+        self.redo_log.clear().unwrap();
     }
 
     fn on_disk_size(&self) -> usize {
-        self.file.metadata().unwrap().len() as usize
+        self.file.len().unwrap() as usize
+    }
+
+    fn iter_with_expiry(&self) -> Box<dyn Iterator<Item = (u64, u64, Option<u64>)> + '_> {
+        Box::new(LPHashTableIter {
+            table: self,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            next_slot: 0,
+        })
     }
 }
 
 impl Drop for LPHashTable {
     fn drop(&mut self) {
-        self.file.sync_all().unwrap();
+        self.file.sync().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::storage::MemStorage;
+
+    #[test]
+    fn entry() {
+        let test_entries = vec![
+            LPHashTableEntry(None),
+            LPHashTableEntry(Some((0, 0, None, false))),
+            LPHashTableEntry(Some((1, 1, Some(1), true))),
+            LPHashTableEntry(Some((u64::MAX, u64::MAX, Some(u64::MAX), true))),
+        ];
+        for entry in test_entries {
+            let bytes = entry.serialize().unwrap();
+            assert_eq!(bytes.len(), LPHashTableEntry::bin_size());
+            let entry2 = LPHashTableEntry::deserialize(&bytes).unwrap();
+            assert_eq!(entry, entry2);
+        }
+    }
+
+    #[test]
+    fn version_1_files() {
+        let storage = Arc::new(MemStorage::new());
+        let options = LPHashTableOptions {
+            filename: "v1.bin".to_string(),
+            storage: storage.clone(),
+            ..Default::default()
+        };
+        let v1_options = bincode::DefaultOptions::new().with_fixint_encoding();
+        let mut bytes = Vec::new();
+        for slot in 0..4096u64 {
+            let entry = (slot % 3 == 0).then_some((slot, slot * 2));
+            let mut slot_bytes = v1_options.serialize(&entry).unwrap();
+            slot_bytes.resize(V1_SLOT_SIZE, 0);
+            bytes.extend(slot_bytes);
+        }
+        assert_eq!(bytes.len(), 4096 * V1_SLOT_SIZE);
+        storage::write_atomically(&*storage, "v1.bin", &bytes).unwrap();
+
+        let table = LPHashTable::new(&options);
+        assert_eq!(table.len, 1366);
+        for key in 0..4096 {
+            assert_eq!(table.get(key), (key % 3 == 0).then_some(key * 2));
+        }
+        drop(table);
+        let migrated = storage::read_file(&*storage, "v1.bin").unwrap();
+        assert_eq!(check_header(&migrated), Ok(()));
+        assert!(!storage.exists("v1.bin.migrate"));
+
+        // Anything else is refused
+        storage::write_atomically(&*storage, "v1.bin", b"not a table").unwrap();
+        let opened =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| LPHashTable::new(&options)));
+        assert!(opened.is_err());
+        assert_eq!(
+            storage::read_file(&*storage, "v1.bin").unwrap(),
+            b"not a table"
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let mut table = LPHashTable::new(&LPHashTableOptions {
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        });
+        for key in 0..1000 {
+            table.set(key, key);
+        }
+        table.remove(0);
+        let stats = table.stats();
+        assert_eq!(stats.len, 999);
+        assert_eq!(stats.probe_lengths.iter().sum::<usize>(), 999);
+        assert!(stats.probe_lengths[0] > 500);
+        assert!(stats.used_capacity <= stats.capacity);
+        assert!(stats.load() > 0.0 && stats.load() <= stats.load_factor);
+    }
+
+    #[test]
+    fn write_batch() {
+        let filename = "lp_write_batch.bin".to_string();
+        let options = LPHashTableOptions {
+            filename: filename.clone(),
+            ..Default::default()
+        };
+        {
+            let mut table = LPHashTable::new(&options);
+            let mut batch = WriteBatch::new();
+            for key in 0..1000 {
+                batch.set(key, key + 1);
+            }
+            for key in (0..1000).step_by(2) {
+                batch.remove(key);
+            }
+            table.write(&batch);
+            assert_eq!(table.len, 500);
+        }
+        {
+            // A batch logged right before a crash is applied on the next open
+            let mut table = LPHashTable::new(&options);
+            let mut batch = WriteBatch::new();
+            batch.set(1, 0).remove(3).set(2000, 1990).merge(2000, 10);
+            let record = table.stage(batch.ops());
+            drop(table);
+            let (mut redo_log, _) =
+                RecordLog::open(&FsStorage, &format!("{}.redo", filename)).unwrap();
+            redo_log.append(&record).unwrap();
+        }
+        let table = LPHashTable::new(&options);
+        assert_eq!(table.redo_log.size(), 0);
+        assert_eq!(table.len, 500);
+        for key in 0..1000 {
+            let expected = match key {
+                1 => Some(0),
+                3 => None,
+                _ if key % 2 == 1 => Some(key + 1),
+                _ => None,
+            };
+            assert_eq!(table.get(key), expected);
+        }
+        assert_eq!(table.get(2000), Some(2000));
+        let mut entries = table.iter().collect::<Vec<_>>();
+        entries.sort_unstable();
+        let expected = (0..1000)
+            .filter(|key| key % 2 == 1 && *key != 3)
+            .map(|key| (key, if key == 1 { 0 } else { key + 1 }))
+            .chain([(2000, 2000)])
+            .collect::<Vec<_>>();
+        assert_eq!(entries, expected);
+        let keys = [2000, 3, 1, 5, 2000, 1 << 40];
+        assert_eq!(
+            table.get_many(&keys),
+            keys.iter().map(|&key| table.get(key)).collect::<Vec<_>>()
+        );
+        std::fs::remove_file(&filename).unwrap();
+        std::fs::remove_file(format!("{}.redo", filename)).unwrap();
+    }
+
+    #[test]
+    fn expiry_and_rehash() {
+        let clock = Arc::new(ManualClock::new(1000));
+        let options = LPHashTableOptions {
+            clock: clock.clone(),
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        };
+        let mut table = LPHashTable::new(&options);
+        let ttl = Duration::from_secs(10);
+        for key in 0..30000 {
+            table.set_with_ttl(key, key, ttl);
+        }
+        table.set_with_ttl(u64::MAX, 1, ttl);
+        table.merge(u64::MAX, 1);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(table.get(29999), Some(29999));
+        assert_eq!(table.get(u64::MAX), Some(2));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.get(u64::MAX), None);
+        table.merge(u64::MAX, 1);
+
+        // Enough to resize a few times, which drops the expired entries that
+        // are moved and must keep every other one reachable
+        let capacity = table.used_capacity;
+        for key in 30000..130000 {
+            table.set(key, key);
+        }
+        assert!(table.used_capacity > capacity);
+        assert!(table.len < 130000);
+        for key in 0..130000 {
+            assert_eq!(table.get(key), (key >= 30000).then_some(key));
+        }
+        assert_eq!(table.get(u64::MAX), Some(1));
+        assert_eq!(table.iter().count(), 100001);
+    }
+
+    #[test]
+    fn crash_consistency() {
+        // Blocks of 8 slots, so the workload resizes the table a few times
+        let open = |storage| {
+            LPHashTable::new(&LPHashTableOptions {
+                filename: "lp.bin".to_string(),
+                block_size: 8,
+                storage,
+                sync: true,
+                ..Default::default()
+            })
+        };
+        crate::crash::run(open, &crate::crash::workload(3, 40));
+    }
+
+    #[test]
+    fn bounded_cache() {
+        let options = LPHashTableOptions {
+            filename: "lp_bounded_cache.bin".to_string(),
+            max_file_size: Some(HEADER_SIZE + 1000 * LPHashTableEntry::bin_size()),
+            ..Default::default()
+        };
+        let mut table = LPHashTable::new(&options);
+        let size = table.on_disk_size();
+        assert_eq!(size, HEADER_SIZE + 1000 * LPHashTableEntry::bin_size());
+        for key in 0..500 {
+            table.set(key, key);
+        }
+        for key in 0..250 {
+            assert_eq!(table.get(key), Some(key));
+        }
+        assert_eq!(table.get(1000), None);
+
+        // Keys used since the hand last passed them get a second chance
+        for key in 500..750 {
+            table.set(key, key);
+        }
+        table.merge(0, 1);
+        assert_eq!(table.len, 500);
+        assert_eq!(table.on_disk_size(), size);
+        for key in 1..250 {
+            assert_eq!(table.get(key), Some(key));
+        }
+        assert_eq!(table.get(0), Some(1));
+        assert_eq!(
+            table.cache_stats(),
+            LPCacheStats {
+                hits: 500,
+                misses: 1,
+                evictions: 250
+            }
+        );
+        assert_eq!(table.iter().count(), 500);
+        std::fs::remove_file(&options.filename).unwrap();
+        std::fs::remove_file(format!("{}.redo", options.filename)).unwrap();
     }
 }
@@ -0,0 +1,364 @@
+// Offline check of a table file with its redo log, and the rebuild of the
+// repair mode
+
+use super::{
+    check_header, LPHashTable, LPHashTableEntry, LPHashTableOptions, RedoRecord, HEADER_SIZE,
+};
+use crate::fsck::FsckReport;
+use crate::hash_table::HashTable;
+use crate::record_log;
+use crate::storage::{self, OpenMode};
+use crate::write_batch::WriteBatch;
+use bincode::Options;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    Empty,
+    Entry {
+        key: u64,
+        value: u64,
+        expires_at: Option<u64>,
+    },
+    Corrupt,
+}
+
+fn decode(bytes: &[u8]) -> Result<Slot, bincode::Error> {
+    Ok(match LPHashTableEntry::deserialize(bytes)? {
+        LPHashTableEntry(None) => Slot::Empty,
+        LPHashTableEntry(Some((key, value, expires_at, _))) => Slot::Entry {
+            key,
+            value,
+            expires_at,
+        },
+    })
+}
+
+impl LPHashTable {
+    // Checks the table as it would be after opening it: every entry can be
+    // found from its home slot, no key is in two slots and the len of the
+    // last redo record matches. The table must not be open. The repair mode
+    // rebuilds the table from the entries it can decode and keeps the old
+    // file at `<filename>.corrupt` and its redo log at
+    // `<filename>.redo.corrupt`.
+    pub fn fsck(options: &LPHashTableOptions, repair: bool) -> FsckReport {
+        let mut report = FsckReport::default();
+        let storage = &*options.storage;
+        let path = &options.filename;
+        let step = LPHashTableEntry::bin_size();
+        let bytes = match storage::read_file(storage, path) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                report.problem(path, None, format!("can't be read: {}", err));
+                return report;
+            }
+        };
+        if let Err(err) = check_header(&bytes) {
+            report.problem(path, Some(0), err);
+            return report;
+        }
+        // Offsets in the file of slots and of the positions in redo records
+        let offset = |slot: usize| (HEADER_SIZE + slot * step) as u64;
+        let slot_bytes = &bytes[HEADER_SIZE..];
+        if slot_bytes.len() % step != 0 {
+            report.problem(
+                path,
+                Some(offset(slot_bytes.len() / step)),
+                format!("{} bytes after the last slot", slot_bytes.len() % step),
+            );
+        }
+        let mut slots = Vec::with_capacity(slot_bytes.len() / step);
+        for (i, slot_bytes) in slot_bytes.chunks_exact(step).enumerate() {
+            slots.push(decode(slot_bytes).unwrap_or_else(|err| {
+                report.problem(path, Some(offset(i)), format!("undecodable slot: {}", err));
+                Slot::Corrupt
+            }));
+        }
+
+        // Opening the table replays the redo log over the file
+        let redo_path = format!("{}.redo", path);
+        let mut last_record = None;
+        if let Ok(file) = storage.open(&redo_path, OpenMode::Existing) {
+            let (records, _) = record_log::read_records(&*file).unwrap();
+            for (offset, record) in records {
+                let Ok(record) = bincode::DefaultOptions::new().deserialize::<RedoRecord>(&record)
+                else {
+                    report.problem(
+                        &redo_path,
+                        Some(offset),
+                        "undecodable redo record".to_string(),
+                    );
+                    break;
+                };
+                if slots.len() < record.used_capacity {
+                    slots.resize(record.used_capacity, Slot::Empty);
+                }
+                for (pos, slot_bytes) in &record.slots {
+                    let slot = *pos as usize / step;
+                    if !(*pos as usize).is_multiple_of(step) || slot >= slots.len() {
+                        report.problem(
+                            &redo_path,
+                            Some(offset),
+                            format!(
+                                "slot at {} is outside of the table",
+                                HEADER_SIZE as u64 + pos
+                            ),
+                        );
+                        continue;
+                    }
+                    slots[slot] = decode(slot_bytes).unwrap_or_else(|err| {
+                        report.problem(
+                            &redo_path,
+                            Some(offset),
+                            format!("undecodable slot at {}: {}", HEADER_SIZE as u64 + pos, err),
+                        );
+                        Slot::Corrupt
+                    });
+                }
+                last_record = Some((offset, record.len));
+            }
+        }
+
+        let used_capacity = slots.len();
+        let capacity = used_capacity.next_power_of_two();
+        if !slots.contains(&Slot::Empty) {
+            report.problem(
+                path,
+                None,
+                "no empty slot, probes for missing keys never end".to_string(),
+            );
+        }
+        // The slot of every key that a probe finds first
+        let mut found = HashMap::new();
+        for (slot, entry) in slots.iter().enumerate() {
+            let Slot::Entry { key, .. } = *entry else {
+                continue;
+            };
+            report.entries += 1;
+            let home = Self::home_slot(key, capacity, used_capacity);
+            let distance = (slot + used_capacity - home) % used_capacity;
+            let mut probe = home;
+            while probe != slot {
+                if slots[probe] == Slot::Empty || slots[probe] == Slot::Corrupt {
+                    report.problem(
+                        path,
+                        Some(offset(slot)),
+                        format!(
+                            "key {} can't be reached from its home slot at {}, the probe stops at {}",
+                            key,
+                            offset(home),
+                            offset(probe)
+                        ),
+                    );
+                    break;
+                }
+                probe = (probe + 1) % used_capacity;
+            }
+            match found.get(&key) {
+                Some(&(other, other_distance)) => {
+                    report.problem(
+                        path,
+                        Some(offset(slot)),
+                        format!("key {} is also at {}", key, offset(other)),
+                    );
+                    // Of two copies, the one closer to home is found first
+                    if distance < other_distance {
+                        found.insert(key, (slot, distance));
+                    }
+                }
+                None => {
+                    found.insert(key, (slot, distance));
+                }
+            }
+        }
+        if let Some((record_offset, len)) = last_record {
+            if len != report.entries {
+                report.problem(
+                    &redo_path,
+                    Some(record_offset),
+                    format!(
+                        "the last redo record says len {} but {} slots are taken",
+                        len, report.entries
+                    ),
+                );
+            }
+        }
+
+        if repair && !report.is_ok() {
+            let repaired_path = format!("{}.repair", path);
+            for stale in [repaired_path.clone(), format!("{}.redo", repaired_path)] {
+                if storage.exists(&stale) {
+                    storage.remove(&stale).unwrap();
+                }
+            }
+            {
+                let mut table = LPHashTable::new(&LPHashTableOptions {
+                    filename: repaired_path.clone(),
+                    ..options.clone()
+                });
+                let mut batch = WriteBatch::new();
+                for &(slot, _) in found.values() {
+                    let Slot::Entry {
+                        key,
+                        value,
+                        expires_at,
+                    } = slots[slot]
+                    else {
+                        unreachable!()
+                    };
+                    match expires_at {
+                        Some(expires_at) => batch.set_with_expiry(key, value, expires_at),
+                        None => batch.set(key, value),
+                    };
+                    if batch.len() == 1000 {
+                        table.write(&batch);
+                        batch.clear();
+                    }
+                }
+                table.write(&batch);
+            }
+            storage.remove(&format!("{}.redo", repaired_path)).unwrap();
+            let corrupt_path = format!("{}.corrupt", path);
+            storage::write_atomically(storage, &corrupt_path, &bytes).unwrap();
+            // The rebuilt table has what the redo log held, and replaying the
+            // log over it would write slots of the old layout, so the log is
+            // moved away before the rebuilt table takes its place
+            if storage.exists(&redo_path) {
+                storage
+                    .rename(&redo_path, &format!("{}.corrupt", redo_path))
+                    .unwrap();
+                storage.sync_dir(storage::parent_dir(path)).unwrap();
+            }
+            storage.rename(&repaired_path, path).unwrap();
+            storage.sync_dir(storage::parent_dir(path)).unwrap();
+            report.repairs.push(format!(
+                "rebuilt {} with {} entries, the old file is at {}",
+                path,
+                found.len(),
+                corrupt_path
+            ));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemStorage, Storage};
+    use std::sync::Arc;
+
+    #[test]
+    fn check_and_repair() {
+        let storage = Arc::new(MemStorage::new());
+        let options = LPHashTableOptions {
+            filename: "fsck.bin".to_string(),
+            block_size: 4096,
+            storage: storage.clone(),
+            ..Default::default()
+        };
+        {
+            let mut table = LPHashTable::new(&options);
+            for key in 0..1000 {
+                table.set(key, key + 1);
+            }
+        }
+        let report = LPHashTable::fsck(&options, false);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.entries, 1000);
+        // A batch that was logged but never reached the file
+        let redo = {
+            let mut table = LPHashTable::new(&options);
+            let record = table.stage(WriteBatch::new().set(5000, 5001).ops());
+            table.redo_log.append(&record).unwrap();
+            storage::read_file(&*storage, "fsck.bin.redo").unwrap()
+        };
+
+        let file = storage.open("fsck.bin", OpenMode::Existing).unwrap();
+        let step = LPHashTableEntry::bin_size();
+        let slots = (0..4096)
+            .map(|slot| {
+                let mut bytes = vec![0; step];
+                file.read_exact_at(&mut bytes, (HEADER_SIZE + slot * step) as u64)
+                    .unwrap();
+                decode(&bytes).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let key_at = |slot: usize| match slots[slot] {
+            Slot::Entry { key, .. } => Some(key),
+            _ => None,
+        };
+        let write_slot = |slot: usize, entry: LPHashTableEntry| {
+            let bytes = entry.serialize().unwrap();
+            file.write_all_at(&bytes, (HEADER_SIZE + slot * step) as u64)
+                .unwrap();
+        };
+        // An entry right before an empty slot gets a copy in it
+        let copied = (0..4095)
+            .find(|&slot| key_at(slot).is_some() && key_at(slot + 1).is_none())
+            .unwrap();
+        let copy = key_at(copied).unwrap();
+        write_slot(
+            copied + 1,
+            LPHashTableEntry(Some((copy, copy + 1, None, false))),
+        );
+        // The slot in front of an entry that isn't in its home slot is wiped
+        let wiped = (0..4095)
+            .find(|&slot| {
+                key_at(slot + 1).is_some_and(|key| LPHashTable::home_slot(key, 4096, 4096) <= slot)
+            })
+            .unwrap();
+        write_slot(wiped, LPHashTableEntry(None));
+        // And another entry is garbage
+        let garbage = (wiped + 2..4096)
+            .find(|&slot| key_at(slot).is_some() && slot != copied)
+            .unwrap();
+        file.write_all_at(&[7], (HEADER_SIZE + garbage * step) as u64)
+            .unwrap();
+        drop(file);
+
+        let report = LPHashTable::fsck(&options, true);
+        let messages = report
+            .problems
+            .iter()
+            .map(|problem| problem.to_string())
+            .collect::<Vec<_>>();
+        assert!(messages.contains(&format!(
+            "fsck.bin at {}: key {} is also at {}",
+            HEADER_SIZE + (copied + 1) * step,
+            copy,
+            HEADER_SIZE + copied * step
+        )));
+        assert!(messages.iter().any(|message| {
+            message.starts_with(&format!(
+                "fsck.bin at {}: key",
+                HEADER_SIZE + (wiped + 1) * step
+            )) && message.ends_with(&format!(
+                "the probe stops at {}",
+                HEADER_SIZE + wiped * step
+            ))
+        }));
+        let undecodable = format!(
+            "fsck.bin at {}: undecodable slot",
+            HEADER_SIZE + garbage * step
+        );
+        assert!(messages
+            .iter()
+            .any(|message| message.starts_with(&undecodable)));
+        assert_eq!(report.repairs.len(), 1);
+
+        assert!(LPHashTable::fsck(&options, false).is_ok());
+        assert!(storage.exists("fsck.bin.corrupt"));
+        assert_eq!(
+            storage::read_file(&*storage, "fsck.bin.redo.corrupt").unwrap(),
+            redo
+        );
+        assert!(!storage.exists("fsck.bin.redo"));
+        let table = LPHashTable::new(&options);
+        // The entries of the wiped and the garbage slot are gone, the logged
+        // one is in
+        assert_eq!(table.get(5000), Some(5001));
+        assert_eq!(table.iter().count(), 999);
+        assert!(table.iter().all(|(key, value)| value == key + 1));
+    }
+}
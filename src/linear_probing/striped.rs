@@ -0,0 +1,276 @@
+use super::{LPCacheStats, LPHashTable, LPHashTableOptions};
+use crate::clock::Clock;
+use crate::hash_table::{ConcurrentHashTable, HashTable};
+use crate::merge::MergeOperator;
+use crate::record_log::RecordLog;
+use crate::storage::Storage;
+use crate::write_batch::WriteBatch;
+use bincode::Options;
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+use std::time::Duration;
+
+pub struct StripedLPHashTableOptions {
+    pub filename: String,
+    pub stripes: usize,
+    pub merge_operator: MergeOperator,
+    pub clock: Arc<dyn Clock>,
+    // Split evenly between the stripes
+    pub max_file_size: Option<usize>,
+    pub storage: Arc<dyn Storage>,
+    // fsync the redo logs and the files after every write
+    pub sync: bool,
+}
+
+// Keys are spread over independent tables, one file and one lock each, so
+// operations on different stripes run in parallel and readers of a stripe only
+// wait for its writers
+type Locked<'a> = Vec<(usize, RwLockWriteGuard<'a, LPHashTable>)>;
+// Redo records of the stripes a batch writes to
+type StripeRecords = Vec<(usize, Vec<u8>)>;
+
+pub struct StripedLPHashTable {
+    stripes: Vec<RwLock<LPHashTable>>,
+    // Batches can span stripes, so they get a redo log of their own
+    redo_log: Mutex<RecordLog>,
+    sync: bool,
+}
+
+impl StripedLPHashTable {
+    pub fn new(options: &StripedLPHashTableOptions) -> Self {
+        let stripes = (0..options.stripes)
+            .map(|stripe| {
+                RwLock::new(LPHashTable::new(&LPHashTableOptions {
+                    filename: Self::stripe_filename(&options.filename, stripe),
+                    merge_operator: options.merge_operator.clone(),
+                    clock: options.clock.clone(),
+                    max_file_size: options.max_file_size.map(|size| size / options.stripes),
+                    storage: options.storage.clone(),
+                    sync: options.sync,
+                    ..Default::default()
+                }))
+            })
+            .collect();
+        let (redo_log, records) =
+            RecordLog::open(&*options.storage, &format!("{}.redo", options.filename)).unwrap();
+        let table = StripedLPHashTable {
+            stripes,
+            redo_log: Mutex::new(redo_log),
+            sync: options.sync,
+        };
+        if !records.is_empty() {
+            for record in records {
+                let stripe_records: StripeRecords =
+                    bincode::DefaultOptions::new().deserialize(&record).unwrap();
+                for (stripe, record) in stripe_records {
+                    table.stripes[stripe].write().unwrap().write_record(&record);
+                }
+            }
+            for stripe in &table.stripes {
+                stripe.read().unwrap().file.sync().unwrap();
+            }
+            table.redo_log.lock().unwrap().clear().unwrap();
+        }
+        table
+    }
+
+    pub fn stripe_filename(filename: &str, stripe: usize) -> String {
+        format!("{}.{}", filename, stripe)
+    }
+
+    fn stripe_index(&self, key: u64) -> usize {
+        // The low bits of the hash pick the slot inside the stripe
+        ((LPHashTable::hash(key) >> 32) % self.stripes.len() as u64) as usize
+    }
+
+    fn stripe(&self, key: u64) -> &RwLock<LPHashTable> {
+        &self.stripes[self.stripe_index(key)]
+    }
+
+    // Locks the stripes the batch writes to and stages their part of it.
+    // Stripes are locked in index order, so batches can't deadlock each other.
+    fn stage(&self, batch: &WriteBatch) -> (Locked<'_>, StripeRecords) {
+        let mut stripes = batch
+            .ops()
+            .iter()
+            .map(|op| self.stripe_index(op.key()))
+            .collect::<Vec<_>>();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let mut locked = stripes
+            .iter()
+            .map(|&stripe| (stripe, self.stripes[stripe].write().unwrap()))
+            .collect::<Vec<_>>();
+        let stripe_records = locked
+            .iter_mut()
+            .map(|(stripe, table)| {
+                let ops = batch
+                    .ops()
+                    .iter()
+                    .filter(|op| self.stripe_index(op.key()) == *stripe);
+                (*stripe, table.stage(ops))
+            })
+            .collect();
+        (locked, stripe_records)
+    }
+
+    pub fn cache_stats(&self) -> LPCacheStats {
+        self.stripes
+            .iter()
+            .map(|stripe| stripe.read().unwrap().cache_stats())
+            .fold(LPCacheStats::default(), |total, stats| LPCacheStats {
+                hits: total.hits + stats.hits,
+                misses: total.misses + stats.misses,
+                evictions: total.evictions + stats.evictions,
+            })
+    }
+}
+
+impl ConcurrentHashTable for StripedLPHashTable {
+    fn set(&self, key: u64, value: u64) {
+        self.stripe(key).write().unwrap().set(key, value);
+    }
+
+    fn set_with_ttl(&self, key: u64, value: u64, ttl: Duration) {
+        self.stripe(key)
+            .write()
+            .unwrap()
+            .set_with_ttl(key, value, ttl);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.stripe(key).read().unwrap().get(key)
+    }
+
+    fn remove(&self, key: u64) {
+        self.stripe(key).write().unwrap().remove(key);
+    }
+
+    fn merge(&self, key: u64, operand: u64) {
+        self.stripe(key).write().unwrap().merge(key, operand);
+    }
+
+    // The redo record holds the one of every stripe the batch writes to
+    fn write(&self, batch: &WriteBatch) {
+        let mut redo_log = self.redo_log.lock().unwrap();
+        let (mut locked, stripe_records) = self.stage(batch);
+        let record = bincode::DefaultOptions::new()
+            .serialize(&stripe_records)
+            .unwrap();
+        redo_log.append(&record).unwrap();
+        if self.sync {
+            redo_log.sync().unwrap();
+        }
+        for ((_, table), (_, record)) in locked.iter_mut().zip(&stripe_records) {
+            table.write_record(record);
+        }
+        if self.sync {
+            for (_, table) in &locked {
+                table.file.sync().unwrap();
+            }
+        }
+        redo_log.clear().unwrap();
+    }
+
+    fn on_disk_size(&self) -> usize {
+        self.stripes
+            .iter()
+            .map(|stripe| stripe.read().unwrap().on_disk_size())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::storage::FsStorage;
+    use std::thread;
+
+    #[test]
+    fn parallel_stripes() {
+        let options = StripedLPHashTableOptions {
+            filename: "lp_parallel_stripes.bin".to_string(),
+            stripes: 4,
+            merge_operator: MergeOperator::saturating_add(),
+            clock: Arc::new(SystemClock),
+            max_file_size: None,
+            storage: Arc::new(FsStorage),
+            sync: false,
+        };
+        let remove_files = || {
+            for stripe in 0..options.stripes {
+                let filename = StripedLPHashTable::stripe_filename(&options.filename, stripe);
+                let _ = std::fs::remove_file(&filename);
+                let _ = std::fs::remove_file(format!("{}.redo", filename));
+            }
+            let _ = std::fs::remove_file(format!("{}.redo", options.filename));
+        };
+        remove_files();
+        let table = StripedLPHashTable::new(&options);
+        const THREADS: u64 = 4;
+        const KEYS: u64 = 20000;
+        thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let table = &table;
+                scope.spawn(move || {
+                    for key in (thread..KEYS).step_by(THREADS as usize) {
+                        table.set(key, key);
+                        if key % 3 == 0 {
+                            table.remove(key);
+                        }
+                    }
+                    let mut batch = WriteBatch::new();
+                    for key in (thread..100).step_by(THREADS as usize) {
+                        batch.set(key, key + 1);
+                    }
+                    table.write(&batch);
+                    for _ in 0..100 {
+                        table.merge(KEYS, 1);
+                    }
+                });
+            }
+            for _ in 0..2 {
+                let table = &table;
+                scope.spawn(move || {
+                    for key in 0..KEYS {
+                        if let Some(value) = table.get(key) {
+                            assert!(value == key || value == key + 1);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(table.get(KEYS), Some(THREADS * 100));
+        for key in 0..KEYS {
+            let expected = match key {
+                _ if key < 100 => Some(key + 1),
+                _ if key % 3 == 0 => None,
+                _ => Some(key),
+            };
+            assert_eq!(table.get(key), expected);
+        }
+        drop(table);
+        {
+            // A batch logged right before a crash is applied on the next open
+            let table = StripedLPHashTable::new(&options);
+            let mut batch = WriteBatch::new();
+            for key in 0..100 {
+                batch.remove(key);
+            }
+            let (locked, stripe_records) = table.stage(&batch);
+            drop(locked);
+            drop(table);
+            let (mut redo_log, _) =
+                RecordLog::open(&FsStorage, &format!("{}.redo", options.filename)).unwrap();
+            let record = bincode::DefaultOptions::new()
+                .serialize(&stripe_records)
+                .unwrap();
+            redo_log.append(&record).unwrap();
+        }
+        let table = StripedLPHashTable::new(&options);
+        assert_eq!(table.get(50), None);
+        assert_eq!(table.get(101), Some(101));
+        drop(table);
+        remove_files();
+    }
+}
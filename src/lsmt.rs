@@ -1,75 +1,117 @@
+mod cache;
+mod concurrent;
+mod format;
+mod fsck;
+mod iter;
+
+use crate::clock::{Clock, SystemClock};
 use crate::hash_table::HashTable;
-use bincode::{
-    config::{FixintEncoding, WithOtherIntEncoding, WithOtherTrailing},
-    DefaultOptions, Options,
-};
+use crate::merge::MergeOperator;
+use crate::random::{Random, ThreadRandom};
+use crate::record_log::RecordLog;
+use crate::storage::{self, FsStorage, OpenMode, Storage, StorageFile};
+use crate::write_batch::{BatchOp, WriteBatch};
+use bincode::Options;
+use cache::CachedBlock;
+pub use cache::{BlockCache, BlockCacheStats};
+pub use concurrent::ConcurrentLSMTree;
+pub use format::Compression;
+use format::{BlockBuilder, BlockHandle, BloomFilter, Footer, IndexEntry};
+use iter::{EntryIter, MergingIter, Resolver, VisibleIter};
 use once_cell::sync::Lazy;
 use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{self, OpenOptions},
-    io::Write,
-    mem::size_of,
-    os::unix::prelude::FileExt,
-    sync::Mutex,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+// Times are in milliseconds since the Unix epoch
+#[derive(Clone, PartialEq, Debug)]
 enum DisktableEntry {
-    Insert { rev: u64, key: u64, value: u64 },
-    Delete { rev: u64, key: u64 },
+    Insert {
+        rev: u64,
+        key: u64,
+        value: u64,
+        expires_at: Option<u64>,
+    },
+    Delete {
+        rev: u64,
+        key: u64,
+    },
+    // Operand for the merge operator, applied to the older versions on read.
+    // If the value below it had expired by `written_at`, it starts afresh.
+    Merge {
+        rev: u64,
+        key: u64,
+        operand: u64,
+        written_at: u64,
+    },
 }
 
 impl DisktableEntry {
     fn get_key(&self) -> u64 {
         match self {
-            DisktableEntry::Insert {
-                rev: _,
-                key,
-                value: _,
-            } => *key,
-            DisktableEntry::Delete { rev: _, key } => *key,
+            DisktableEntry::Insert { key, .. }
+            | DisktableEntry::Delete { key, .. }
+            | DisktableEntry::Merge { key, .. } => *key,
         }
     }
 
     fn get_rev(&self) -> u64 {
         match self {
-            DisktableEntry::Insert {
-                rev,
-                key: _,
-                value: _,
-            } => *rev,
-            DisktableEntry::Delete { rev, key: _ } => *rev,
+            DisktableEntry::Insert { rev, .. }
+            | DisktableEntry::Delete { rev, .. }
+            | DisktableEntry::Merge { rev, .. } => *rev,
         }
     }
+}
 
-    fn serialize(&self) -> bincode::Result<Vec<u8>> {
-        let opts = DefaultOptions::new().allow_trailing_bytes();
-        opts.serialize(&self).map(|mut v| {
-            v.resize(Self::bin_size(), 0);
-            v
-        })
-    }
-
-    fn deserialize(bytes: &[u8]) -> bincode::Result<Self> {
-        let opts = DefaultOptions::new().allow_trailing_bytes();
-        opts.deserialize(bytes)
-    }
+#[derive(Clone, Debug)]
+pub struct DisktableOptions {
+    pub block_size: usize,
+    pub compression: Compression,
+    pub bloom_bits_per_key: usize,
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub pin_index_and_filter: bool,
+}
 
-    // WARNING
-    const fn bin_size() -> usize {
-        28 as usize
+impl Default for DisktableOptions {
+    fn default() -> Self {
+        DisktableOptions {
+            block_size: 4096,
+            compression: Compression::Lz4,
+            bloom_bits_per_key: 10,
+            block_cache: Some(Arc::new(BlockCache::new(8 * 1024 * 1024))),
+            pin_index_and_filter: true,
+        }
     }
 }
 
 struct Disktable {
-    file: fs::File,
+    file: Box<dyn StorageFile>,
+    storage: Arc<dyn Storage>,
+    path: String,
+    id: u64,
     size: usize,
+    footer: Footer,
+    // Where the footer starts, every block ends before it
+    blocks_end: u64,
+    cache: Option<Arc<BlockCache>>,
+    // Only kept when pinned, otherwise they are read through the cache
+    index: Option<Arc<Vec<IndexEntry>>>,
+    filter: Option<Arc<BloomFilter>>,
 }
 
 struct DisktableIter<'a> {
     disktable: &'a Disktable,
+    index: Arc<Vec<IndexEntry>>,
+    block: usize,
+    entries: Arc<Vec<DisktableEntry>>,
     pos: usize,
 }
 
@@ -77,128 +119,169 @@ impl<'a> Iterator for DisktableIter<'a> {
     type Item = DisktableEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let step = DisktableEntry::bin_size() as usize;
-        if self.pos == self.disktable.len() * step {
-            return None;
+        loop {
+            if let Some(entry) = self.entries.get(self.pos) {
+                self.pos += 1;
+                return Some(entry.clone());
+            }
+            if self.block == self.index.len() {
+                return None;
+            }
+            self.entries = self.disktable.read_block(self.index[self.block].handle);
+            self.block += 1;
+            self.pos = 0;
+        }
+    }
+}
+
+struct DisktableWriter<'a> {
+    file: Box<dyn StorageFile>,
+    storage: &'a Arc<dyn Storage>,
+    path: String,
+    options: &'a DisktableOptions,
+    block: BlockBuilder,
+    offset: u64,
+    index: Vec<IndexEntry>,
+    keys: Vec<u64>,
+    size: usize,
+}
+
+impl<'a> DisktableWriter<'a> {
+    fn add(&mut self, entry: &DisktableEntry) {
+        if self.keys.last() != Some(&entry.get_key()) {
+            self.keys.push(entry.get_key());
+        }
+        self.block.add(entry);
+        self.size += 1;
+        if self.block.size_estimate() >= self.options.block_size {
+            self.flush_block();
+        }
+    }
+
+    fn write_block(&mut self, raw: Vec<u8>, compression: Compression) -> BlockHandle {
+        let stored = format::seal_block(raw, compression);
+        self.file.write_all_at(&stored, self.offset).unwrap();
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: stored.len() as u64,
+        };
+        self.offset += stored.len() as u64;
+        handle
+    }
+
+    fn flush_block(&mut self) {
+        if self.block.is_empty() {
+            return;
         }
-        let res = self.disktable.read_pos(self.pos);
-        self.pos += step;
-        Some(res)
+        let last_key = self.block.last_key();
+        let raw = self.block.finish();
+        let handle = self.write_block(raw, self.options.compression);
+        self.index.push(IndexEntry { last_key, handle });
+    }
+
+    fn finish(mut self) -> Disktable {
+        self.flush_block();
+        let filter = BloomFilter::build(&self.keys, self.options.bloom_bits_per_key);
+        let filter_handle = self.write_block(filter.encode(), Compression::None);
+        let index_handle = self.write_block(format::encode_index(&self.index), Compression::None);
+        let footer = Footer {
+            filter: filter_handle,
+            index: index_handle,
+            entries: self.size as u64,
+        };
+        self.file
+            .write_all_at(&footer.encode(), self.offset)
+            .unwrap();
+        self.file.sync().unwrap();
+
+        Disktable::open(self.path, self.options, self.storage.clone()).unwrap()
     }
 }
 
 struct DisktableRepository {
-    used_filenames: HashSet<String>,
-    last_rev: u64,
+    // Paths, so that trees in different directories draw names independently
+    used_paths: HashSet<String>,
 }
 
 impl DisktableRepository {
     const FILENAME_LEN: usize = 12;
-    fn generate_filename(&mut self) -> String {
-        rand::thread_rng()
+
+    fn is_disktable(filename: &str) -> bool {
+        filename.len() == Self::FILENAME_LEN && filename.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    fn generate_filename(&mut self, random: &dyn Random) -> String {
+        random
+            .rng()
             .sample_iter(&Alphanumeric)
             .take(Self::FILENAME_LEN)
             .map(char::from)
             .collect()
     }
 
-    fn create_file(&mut self) -> fs::File {
-        let mut filename = self.generate_filename();
-        while self.used_filenames.contains(&filename) {
-            filename = self.generate_filename();
+    fn create_file(&mut self, options: &LSMTreeOptions) -> (Box<dyn StorageFile>, String) {
+        loop {
+            let filename = self.generate_filename(&*options.random);
+            let path = format!("{}/{}", options.dir, filename);
+            if self.used_paths.contains(&path) {
+                continue;
+            }
+            // Files of earlier runs are not in used_paths
+            let file = match options.storage.open(&path, OpenMode::CreateNew) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => panic!("{}", err),
+            };
+            self.used_paths.insert(path.clone());
+            return (file, path);
         }
-
-        self.used_filenames.insert(filename.clone());
-
-        fs::create_dir_all("lsmt").unwrap();
-
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("lsmt/{}", filename))
-            .unwrap()
     }
 
-    fn from_memtable(&mut self, memtable: Memtable) -> Disktable {
-        self.last_rev += 1;
-        let rev = self.last_rev;
-        let mut entries = memtable
-            .into_iter()
-            .map(|(k, v)| {
-                if let Some(val) = v {
-                    DisktableEntry::Insert {
-                        rev,
-                        key: k,
-                        value: val,
-                    }
-                } else {
-                    DisktableEntry::Delete { rev, key: k }
-                }
-            })
-            .collect::<Vec<DisktableEntry>>();
-        entries.sort_unstable_by(|entry1, entry2| entry1.get_key().cmp(&entry2.get_key()));
-        self.from_iter(entries.into_iter())
+    fn write_memtable(&mut self, memtable: Memtable, options: &LSMTreeOptions) -> Disktable {
+        self.write_entries(memtable.into_values(), options)
     }
 
-    fn from_iter<T: IntoIterator<Item = DisktableEntry>>(&mut self, iter: T) -> Disktable {
-        let mut size = 0;
-        let mut file = self.create_file();
+    fn write_entries<T: IntoIterator<Item = DisktableEntry>>(
+        &mut self,
+        iter: T,
+        options: &LSMTreeOptions,
+    ) -> Disktable {
+        let (file, path) = self.create_file(options);
+        let mut writer = DisktableWriter {
+            file,
+            storage: &options.storage,
+            path,
+            options: &options.disktable,
+            block: BlockBuilder::new(),
+            offset: 0,
+            index: Vec::new(),
+            keys: Vec::new(),
+            size: 0,
+        };
         for entry in iter {
-            let bytes = entry.serialize().unwrap();
-            file.write_all(&bytes).unwrap();
-            size += 1;
+            writer.add(&entry);
         }
-
-        Disktable { file, size }
+        writer.finish()
     }
 
-    fn merge(&mut self, dtable1: Disktable, dtable2: Disktable) -> Disktable {
-        let mut pos1 = 0;
-        let mut pos2 = 0;
-        let mut merged = Vec::with_capacity(dtable1.len() + dtable2.len());
-        while pos1 < dtable1.len() && pos2 < dtable2.len() {
-            let v1 = dtable1.read_pos(pos1);
-            let v2 = dtable2.read_pos(pos2);
-            match v1.get_key().cmp(&v2.get_key()) {
-                std::cmp::Ordering::Less => {
-                    merged.push(v1);
-                    pos1 += 1;
-                }
-                std::cmp::Ordering::Greater => {
-                    merged.push(v2);
-                    pos2 += 1;
-                }
-                std::cmp::Ordering::Equal => {
-                    match v1.get_rev().cmp(&v2.get_rev()) {
-                        std::cmp::Ordering::Less => {
-                            merged.push(v1);
-                        }
-                        std::cmp::Ordering::Greater => {
-                            merged.push(v2);
-                        }
-                        std::cmp::Ordering::Equal => unreachable!(),
-                    }
-                    pos1 += 1;
-                    pos2 += 1;
-                }
-            }
-        }
-        while pos1 < dtable1.len() {
-            merged.push(dtable1.read_pos(pos1));
-        }
-        while pos2 < dtable2.len() {
-            merged.push(dtable2.read_pos(pos2));
-        }
-
-        self.from_iter(merged.into_iter())
+    fn merge(
+        &mut self,
+        older: &Disktable,
+        newer: &Disktable,
+        options: &LSMTreeOptions,
+        snapshots: BTreeSet<u64>,
+        bottom: bool,
+    ) -> Disktable {
+        let merged = MergingIter::new(vec![Box::new(older.iter()), Box::new(newer.iter())]);
+        let resolver = Resolver::new(options);
+        let entries = iter::compact_versions(merged, snapshots, bottom, resolver);
+        self.write_entries(entries, options)
     }
 }
 
 static DISKTABLE_REPOSITORY: Lazy<Mutex<DisktableRepository>> = Lazy::new(|| {
     Mutex::new(DisktableRepository {
-        used_filenames: HashSet::new(),
-        last_rev: 0,
+        used_paths: HashSet::new(),
     })
 });
 
@@ -206,180 +289,1264 @@ impl<'a> Disktable {
     fn iter(&'a self) -> DisktableIter<'a> {
         DisktableIter {
             disktable: self,
+            index: self.index(),
+            block: 0,
+            entries: Arc::new(Vec::new()),
+            pos: 0,
+        }
+    }
+
+    fn iter_from(&'a self, key: u64) -> impl Iterator<Item = DisktableEntry> + 'a {
+        let index = self.index();
+        let block = index.partition_point(|entry| entry.last_key < key);
+        DisktableIter {
+            disktable: self,
+            index,
+            block,
+            entries: Arc::new(Vec::new()),
             pos: 0,
         }
+        .skip_while(move |entry| entry.get_key() < key)
     }
 }
 
 impl Disktable {
-    fn get(&self, key: u64) -> Option<u64> {
-        for read in self.iter() {
-            if read.get_key() != key {
-                continue;
-            }
-            match read {
-                DisktableEntry::Insert {
-                    rev: _,
-                    key,
-                    value: _,
-                } => return Some(key),
-                DisktableEntry::Delete { rev: _, key: _ } => return None,
-            }
+    fn open(
+        path: String,
+        options: &DisktableOptions,
+        storage: Arc<dyn Storage>,
+    ) -> io::Result<Self> {
+        let file = storage.open(&path, OpenMode::Existing)?;
+        let file_size = file.len()?;
+        if file_size < format::FOOTER_SIZE as u64 {
+            return Err(format::corrupted(format!(
+                "{} is too short to be a disktable",
+                path
+            )));
+        }
+        let mut footer = [0; format::FOOTER_SIZE];
+        file.read_exact_at(&mut footer, file_size - format::FOOTER_SIZE as u64)?;
+        let footer = Footer::decode(&footer)?;
+        let blocks_end = file_size - format::FOOTER_SIZE as u64;
+        footer.filter.check(blocks_end)?;
+        footer.index.check(blocks_end)?;
+
+        let cache = options.block_cache.clone();
+        let mut disktable = Disktable {
+            file,
+            storage,
+            path,
+            id: cache.as_ref().map_or(0, |cache| cache.new_table_id()),
+            size: footer.entries as usize,
+            footer,
+            blocks_end,
+            cache,
+            index: None,
+            filter: None,
+        };
+        if disktable.cache.is_none() || options.pin_index_and_filter {
+            disktable.index = Some(disktable.load_index(true)?);
+            disktable.filter = Some(disktable.load_filter(true)?);
+        }
+        Ok(disktable)
+    }
+
+    fn read_raw(&self, handle: BlockHandle) -> io::Result<Vec<u8>> {
+        handle.check(self.blocks_end)?;
+        let mut stored = vec![0; handle.size as usize];
+        self.file.read_exact_at(&mut stored, handle.offset)?;
+        format::open_block(&stored)
+    }
+
+    fn load<F>(&self, handle: BlockHandle, pinned: bool, decode: F) -> io::Result<CachedBlock>
+    where
+        F: FnOnce(&[u8]) -> io::Result<CachedBlock>,
+    {
+        let Some(cache) = &self.cache else {
+            return decode(&self.read_raw(handle)?);
+        };
+        let key = (self.id, handle.offset);
+        if let Some(block) = cache.lookup(key) {
+            return Ok(block);
+        }
+        let raw = self.read_raw(handle)?;
+        let block = decode(&raw)?;
+        cache.insert(key, block.clone(), raw.len(), pinned);
+        Ok(block)
+    }
+
+    fn load_index(&self, pinned: bool) -> io::Result<Arc<Vec<IndexEntry>>> {
+        let block = self.load(self.footer.index, pinned, |raw| {
+            Ok(CachedBlock::Index(Arc::new(format::decode_index(raw)?)))
+        })?;
+        match block {
+            CachedBlock::Index(index) => Ok(index),
+            _ => unreachable!(),
+        }
+    }
+
+    fn load_filter(&self, pinned: bool) -> io::Result<Arc<BloomFilter>> {
+        let block = self.load(self.footer.filter, pinned, |raw| {
+            Ok(CachedBlock::Filter(Arc::new(BloomFilter::decode(raw)?)))
+        })?;
+        match block {
+            CachedBlock::Filter(filter) => Ok(filter),
+            _ => unreachable!(),
+        }
+    }
+
+    fn index(&self) -> Arc<Vec<IndexEntry>> {
+        match &self.index {
+            Some(index) => index.clone(),
+            None => self.load_index(false).unwrap(),
+        }
+    }
+
+    fn filter(&self) -> Arc<BloomFilter> {
+        match &self.filter {
+            Some(filter) => filter.clone(),
+            None => self.load_filter(false).unwrap(),
+        }
+    }
+
+    fn read_block(&self, handle: BlockHandle) -> Arc<Vec<DisktableEntry>> {
+        let block = self
+            .load(handle, false, |raw| {
+                Ok(CachedBlock::Data(Arc::new(format::decode_block(raw)?)))
+            })
+            .unwrap();
+        match block {
+            CachedBlock::Data(entries) => entries,
+            _ => unreachable!(),
         }
-        None
     }
 
-    fn read_pos(&self, pos: usize) -> DisktableEntry {
-        let mut bytes = [0; DisktableEntry::bin_size()];
-        self.file.read_at(&mut bytes, pos as u64).unwrap();
-        DisktableEntry::deserialize(&bytes).unwrap()
+    // Versions of `key` that are not newer than `rev`, newest first
+    fn versions(&self, key: u64, rev: u64) -> impl Iterator<Item = DisktableEntry> + '_ {
+        self.filter()
+            .may_contain(key)
+            .then(|| {
+                self.iter_from(key)
+                    .take_while(move |entry| entry.get_key() == key)
+                    .filter(move |entry| entry.get_rev() <= rev)
+            })
+            .into_iter()
+            .flatten()
+    }
+
+    fn get(&self, key: u64, rev: u64) -> Option<DisktableEntry> {
+        self.versions(key, rev).next()
+    }
+
+    // get for each of the sorted `keys`, reading every block at most once
+    fn get_sorted(&self, keys: &[u64], rev: u64) -> Vec<Option<DisktableEntry>> {
+        let filter = self.filter();
+        let index = self.index();
+        let mut block = usize::MAX;
+        let mut entries = Arc::new(Vec::new());
+        keys.iter()
+            .map(|&key| {
+                if !filter.may_contain(key) {
+                    return None;
+                }
+                // Versions of a key can continue in the next block
+                let mut next = index.partition_point(|entry| entry.last_key < key);
+                while next < index.len() {
+                    if block != next {
+                        entries = self.read_block(index[next].handle);
+                        block = next;
+                    }
+                    let start = entries.partition_point(|entry| entry.get_key() < key);
+                    if let Some(entry) = entries[start..]
+                        .iter()
+                        .take_while(|entry| entry.get_key() == key)
+                        .find(|entry| entry.get_rev() <= rev)
+                    {
+                        return Some(entry.clone());
+                    }
+                    if entries.last().map(DisktableEntry::get_key) != Some(key) {
+                        return None;
+                    }
+                    next += 1;
+                }
+                None
+            })
+            .collect()
     }
 
     fn on_disk_size(&self) -> usize {
-        self.file.metadata().unwrap().len() as usize
+        self.file.len().unwrap() as usize
     }
 
     fn len(&self) -> usize {
         self.size
     }
+
+    fn filename(&self) -> String {
+        let path = std::path::Path::new(&self.path);
+        path.file_name().unwrap().to_str().unwrap().to_string()
+    }
+
+    fn remove(self) {
+        self.storage.remove(&self.path).unwrap();
+    }
+}
+
+impl Drop for Disktable {
+    fn drop(&mut self) {
+        if let Some(cache) = &self.cache {
+            cache.erase_table(self.id);
+        }
+    }
+}
+
+// Every version of a key is kept under (key, Reverse(rev)), so versions of the
+// same key are ordered newest first like they are in disktables
+type Memtable = BTreeMap<(u64, Reverse<u64>), DisktableEntry>;
+
+fn batch_entry(op: &BatchOp, rev: u64, time: u64) -> DisktableEntry {
+    match *op {
+        BatchOp::Set {
+            key,
+            value,
+            expires_at,
+        } => DisktableEntry::Insert {
+            rev,
+            key,
+            value,
+            expires_at,
+        },
+        BatchOp::Remove { key } => DisktableEntry::Delete { rev, key },
+        BatchOp::Merge { key, operand } => DisktableEntry::Merge {
+            rev,
+            key,
+            operand,
+            written_at: time,
+        },
+    }
+}
+
+// A write-ahead log record: the revision of the first operation of the batch,
+// the time it was written at and the batch itself
+type WalRecord = (u64, u64, WriteBatch);
+
+// Revisions of the live snapshots with their reference counts
+type SnapshotList = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+const MANIFEST_FILENAME: &str = "MANIFEST";
+const WAL_FILENAME: &str = "wal";
+
+// Disktables of the tree, oldest first, and the last revision they contain.
+// It is replaced atomically by renaming a new version over it.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    flushed_rev: u64,
+    disktables: Vec<String>,
 }
 
-type Memtable = HashMap<u64, Option<u64>>;
+impl Manifest {
+    // None for a directory without a manifest. Any other failure is an
+    // error, a tree must never be opened from a manifest it couldn't read
+    fn read(storage: &dyn Storage, dir: &str) -> io::Result<Option<Self>> {
+        let bytes = match storage::read_file(storage, &format!("{}/{}", dir, MANIFEST_FILENAME)) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        bincode::DefaultOptions::new()
+            .deserialize(&bytes)
+            .map(Some)
+            .map_err(|err| format::corrupted(format!("undecodable: {}", err)))
+    }
 
-impl From<Memtable> for Disktable {
-    fn from(memtable: Memtable) -> Self {
-        DISKTABLE_REPOSITORY.lock().unwrap().from_memtable(memtable)
+    fn write(&self, storage: &dyn Storage, dir: &str) {
+        let bytes = bincode::DefaultOptions::new().serialize(self).unwrap();
+        storage::write_atomically(storage, &format!("{}/{}", dir, MANIFEST_FILENAME), &bytes)
+            .unwrap();
     }
 }
 
-impl FromIterator<DisktableEntry> for Disktable {
-    fn from_iter<T: IntoIterator<Item = DisktableEntry>>(iter: T) -> Self {
-        DISKTABLE_REPOSITORY.lock().unwrap().from_iter(iter)
+#[derive(Clone, Debug)]
+pub struct LSMTreeOptions {
+    pub dir: String,
+    pub memtable_capacity: usize,
+    // fsync the write-ahead log after every write
+    pub sync_wal: bool,
+    pub disktable: DisktableOptions,
+    pub merge_operator: MergeOperator,
+    pub clock: Arc<dyn Clock>,
+    pub storage: Arc<dyn Storage>,
+    pub random: Arc<dyn Random>,
+}
+
+impl Default for LSMTreeOptions {
+    fn default() -> Self {
+        LSMTreeOptions {
+            dir: "lsmt".to_string(),
+            memtable_capacity: 1000,
+            sync_wal: false,
+            disktable: DisktableOptions::default(),
+            merge_operator: MergeOperator::default(),
+            clock: Arc::new(SystemClock),
+            storage: Arc::new(FsStorage),
+            random: Arc::new(ThreadRandom),
+        }
     }
 }
 
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisktableStats {
+    pub filename: String,
+    // Versions of keys, tombstones and merge operands included
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LSMTreeStats {
+    pub memtable_entries: usize,
+    pub wal_bytes: usize,
+    // Oldest first
+    pub disktables: Vec<DisktableStats>,
+}
+
 pub struct LSMTree {
     memtable: Memtable,
     disktables: Vec<Disktable>,
-    mem_sz_threshold: usize,
-    disktable_num: usize,
+    options: LSMTreeOptions,
+    last_rev: u64,
+    flushed_rev: u64,
+    snapshots: SnapshotList,
+    wal: RecordLog,
+}
+
+pub struct Snapshot {
+    rev: u64,
+    snapshots: SnapshotList,
+}
+
+impl Snapshot {
+    pub fn rev(&self) -> u64 {
+        self.rev
+    }
+
+    pub fn get(&self, tree: &LSMTree, key: u64) -> Option<u64> {
+        assert!(Arc::ptr_eq(&self.snapshots, &tree.snapshots));
+        tree.get_at(key, self.rev)
+    }
+
+    pub fn range<'a, R: RangeBounds<u64>>(
+        &self,
+        tree: &'a LSMTree,
+        range: R,
+    ) -> impl Iterator<Item = (u64, u64)> + 'a {
+        assert!(Arc::ptr_eq(&self.snapshots, &tree.snapshots));
+        tree.range_at(range, self.rev)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        let count = snapshots.get_mut(&self.rev).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            snapshots.remove(&self.rev);
+        }
+    }
+}
+
+// What is left of a tree in its directory: the disktables of the manifest and
+// the write-ahead log records that were not flushed yet
+struct Recovered {
+    flushed_rev: u64,
+    disktables: Vec<Disktable>,
+    wal: RecordLog,
+    records: Vec<WalRecord>,
+}
+
+fn recover(options: &LSMTreeOptions) -> Recovered {
+    let dir = &options.dir;
+    let storage = &*options.storage;
+    storage.create_dir_all(dir).unwrap();
+    let manifest = match Manifest::read(storage, dir) {
+        Ok(Some(manifest)) => manifest,
+        // A new tree writes its manifest before its first disktable, so
+        // disktables without one are what is left of a tree that lost it
+        Ok(None) => {
+            let filenames = storage.read_dir(dir).unwrap();
+            if filenames
+                .iter()
+                .any(|filename| DisktableRepository::is_disktable(filename))
+            {
+                panic!(
+                    "{} has disktables but no manifest, `hasty {} fsck --repair` rebuilds it",
+                    dir, dir
+                );
+            }
+            let manifest = Manifest::default();
+            manifest.write(storage, dir);
+            manifest
+        }
+        Err(err) => panic!(
+            "can't read the manifest of {}: {}, `hasty {} fsck --repair` rebuilds it",
+            dir, err, dir
+        ),
+    };
+
+    // Leftovers of flushes and compactions interrupted by a crash
+    for filename in storage.read_dir(dir).unwrap() {
+        let is_disktable = DisktableRepository::is_disktable(&filename);
+        let is_tmp = filename == format!("{}.tmp", MANIFEST_FILENAME);
+        if (is_disktable || is_tmp) && !manifest.disktables.contains(&filename) {
+            storage.remove(&format!("{}/{}", dir, filename)).unwrap();
+        }
+    }
+
+    let disktables = manifest
+        .disktables
+        .iter()
+        .map(|filename| {
+            Disktable::open(
+                format!("{}/{}", dir, filename),
+                &options.disktable,
+                options.storage.clone(),
+            )
+            .unwrap()
+        })
+        .collect();
+    let (wal, records) = RecordLog::open(storage, &format!("{}/{}", dir, WAL_FILENAME)).unwrap();
+    let records = records
+        .iter()
+        .map(|record| bincode::DefaultOptions::new().deserialize(record).unwrap())
+        // The log may still hold records that were already flushed
+        .filter(|(rev, _, _): &WalRecord| *rev > manifest.flushed_rev)
+        .collect();
+    Recovered {
+        flushed_rev: manifest.flushed_rev,
+        disktables,
+        wal,
+        records,
+    }
 }
 
 impl LSMTree {
-    fn new(memtable_capacity: usize) -> Self {
-        LSMTree {
+    pub fn new(options: &LSMTreeOptions) -> Self {
+        let recovered = recover(options);
+        let mut tree = LSMTree {
             memtable: Memtable::new(),
-            disktables: Vec::new(),
-            mem_sz_threshold: memtable_capacity,
-            disktable_num: 0,
+            disktables: recovered.disktables,
+            options: options.clone(),
+            last_rev: recovered.flushed_rev,
+            flushed_rev: recovered.flushed_rev,
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            wal: recovered.wal,
+        };
+        for (rev, time, batch) in recovered.records {
+            tree.apply(rev, time, &batch);
+        }
+        tree.flush_on_threshold();
+        tree
+    }
+
+    pub fn block_cache(&self) -> Option<&Arc<BlockCache>> {
+        self.options.disktable.block_cache.as_ref()
+    }
+
+    pub fn stats(&self) -> LSMTreeStats {
+        LSMTreeStats {
+            memtable_entries: self.memtable.len(),
+            wal_bytes: self.wal.size(),
+            disktables: self
+                .disktables
+                .iter()
+                .map(|disktable| DisktableStats {
+                    filename: disktable.filename(),
+                    entries: disktable.len(),
+                    bytes: disktable.on_disk_size(),
+                })
+                .collect(),
         }
     }
+
+    // Revision of the latest mutation, every set and remove gets the next one
+    pub fn last_rev(&self) -> u64 {
+        self.last_rev
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        *self
+            .snapshots
+            .lock()
+            .unwrap()
+            .entry(self.last_rev)
+            .or_insert(0) += 1;
+        Snapshot {
+            rev: self.last_rev,
+            snapshots: self.snapshots.clone(),
+        }
+    }
+
+    pub fn range<R: RangeBounds<u64>>(&self, range: R) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.range_at(range, u64::MAX)
+    }
+
+    // Revision of the latest set or remove of `key` that is still on record
+    pub fn key_rev(&self, key: u64) -> Option<u64> {
+        self.get_entry(key, u64::MAX).map(|entry| entry.get_rev())
+    }
+
+    fn get_entry(&self, key: u64, rev: u64) -> Option<DisktableEntry> {
+        if let Some(entry) = self.memtable_get(key, rev) {
+            return Some(entry);
+        }
+        self.disktables
+            .iter()
+            .rev()
+            .find_map(|disktable| disktable.get(key, rev))
+    }
+
+    fn memtable_versions(&self, key: u64, rev: u64) -> impl Iterator<Item = DisktableEntry> + '_ {
+        self.memtable
+            .range((key, Reverse(rev))..=(key, Reverse(0)))
+            .map(|(_, entry)| entry.clone())
+    }
+
+    fn memtable_get(&self, key: u64, rev: u64) -> Option<DisktableEntry> {
+        self.memtable_versions(key, rev).next()
+    }
+
+    fn get_at(&self, key: u64, rev: u64) -> Option<u64> {
+        let versions = self.memtable_versions(key, rev).chain(
+            self.disktables
+                .iter()
+                .rev()
+                .flat_map(move |disktable| disktable.versions(key, rev)),
+        );
+        Resolver::new(&self.options).resolve(versions)
+    }
+
+    fn range_at<R: RangeBounds<u64>>(
+        &self,
+        range: R,
+        rev: u64,
+    ) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.entries_at(range, rev)
+            .map(|(key, value, _)| (key, value))
+    }
+
+    fn entries_at<R: RangeBounds<u64>>(
+        &self,
+        range: R,
+        rev: u64,
+    ) -> impl Iterator<Item = (u64, u64, Option<u64>)> + '_ {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => Some(start),
+            Bound::Excluded(&start) => start.checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let end = range.end_bound().cloned();
+        let mut sources: Vec<EntryIter> = Vec::new();
+        if let Some(start) = start {
+            sources.push(Box::new(
+                self.memtable
+                    .range((start, Reverse(u64::MAX))..)
+                    .map(|(_, entry)| entry.clone()),
+            ));
+            for disktable in &self.disktables {
+                sources.push(Box::new(disktable.iter_from(start)));
+            }
+        }
+        let resolver = Resolver::new(&self.options);
+        VisibleIter::new(MergingIter::new(sources), rev, resolver).take_while(move |(key, ..)| {
+            match end {
+                Bound::Included(end) => *key <= end,
+                Bound::Excluded(end) => *key < end,
+                Bound::Unbounded => true,
+            }
+        })
+    }
+
+    // Applies the batch to the memtable, its operations get consecutive
+    // revisions starting with `rev`
+    fn apply(&mut self, rev: u64, time: u64, batch: &WriteBatch) {
+        for (i, op) in batch.ops().iter().enumerate() {
+            self.insert(batch_entry(op, rev + i as u64, time));
+        }
+        self.last_rev = self.last_rev.max(rev + batch.len() as u64 - 1);
+    }
+
+    fn insert(&mut self, entry: DisktableEntry) {
+        let key = entry.get_key();
+        self.memtable.insert((key, Reverse(entry.get_rev())), entry);
+
+        let bounds = (key, Reverse(u64::MAX))..=(key, Reverse(0));
+        if self.memtable.range(bounds.clone()).nth(1).is_some() {
+            let mut versions = self
+                .memtable
+                .range(bounds)
+                .map(|(_, entry)| entry.clone())
+                .collect::<Vec<_>>();
+            for entry in &versions {
+                self.memtable.remove(&(key, Reverse(entry.get_rev())));
+            }
+            iter::retain_versions(
+                &mut versions,
+                &self.live_snapshots(),
+                false,
+                &Resolver::new(&self.options),
+            );
+            for entry in versions {
+                self.memtable.insert((key, Reverse(entry.get_rev())), entry);
+            }
+        }
+    }
+
+    fn live_snapshots(&self) -> BTreeSet<u64> {
+        self.snapshots.lock().unwrap().keys().copied().collect()
+    }
 }
 
 impl HashTable for LSMTree {
     fn set(&mut self, key: u64, value: u64) {
-        self.memtable.insert(key, Some(value));
-        self.flush_on_threshold();
+        self.write(WriteBatch::new().set(key, value));
+    }
+
+    fn set_with_ttl(&mut self, key: u64, value: u64, ttl: Duration) {
+        let expires_at = self.options.clock.expires_at(ttl);
+        self.write(WriteBatch::new().set_with_expiry(key, value, expires_at));
     }
 
     fn get(&self, key: u64) -> Option<u64> {
-        if let Some(value) = self.memtable.get(&key) {
-            return *value;
-        }
+        self.get_at(key, u64::MAX)
+    }
+
+    // The sorted keys that are not in the memtable go through every disktable
+    // in one pass, newest disktable first
+    fn get_many(&self, keys: &[u64]) -> Vec<Option<u64>> {
+        let mut sorted = keys.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+        let mut found = sorted
+            .iter()
+            .map(|&key| self.memtable_get(key, u64::MAX))
+            .collect::<Vec<_>>();
         for disktable in self.disktables.iter().rev() {
-            if let Some(value) = disktable.get(key) {
-                return Some(value);
+            let pending = (0..sorted.len())
+                .filter(|&i| found[i].is_none())
+                .collect::<Vec<_>>();
+            if pending.is_empty() {
+                break;
+            }
+            let pending_keys = pending.iter().map(|&i| sorted[i]).collect::<Vec<_>>();
+            for (i, entry) in pending
+                .into_iter()
+                .zip(disktable.get_sorted(&pending_keys, u64::MAX))
+            {
+                found[i] = entry;
             }
         }
-        None
+        keys.iter()
+            .map(|key| match &found[sorted.binary_search(key).unwrap()] {
+                Some(DisktableEntry::Merge { .. }) => self.get(*key),
+                Some(entry) => Resolver::new(&self.options).resolve(std::iter::once(entry.clone())),
+                None => None,
+            })
+            .collect()
     }
 
     fn on_disk_size(&self) -> usize {
         self.disktables
             .iter()
             .map(|disktable| disktable.on_disk_size())
-            .sum()
+            .sum::<usize>()
+            + self.wal.size()
     }
 
     fn remove(&mut self, key: u64) {
-        self.memtable.insert(key, None);
+        self.write(WriteBatch::new().remove(key));
+    }
+
+    fn merge(&mut self, key: u64, operand: u64) {
+        self.write(WriteBatch::new().merge(key, operand));
+    }
+
+    // Sorted by key
+    fn iter(&self) -> Box<dyn Iterator<Item = (u64, u64)> + '_> {
+        Box::new(self.range(..))
+    }
+
+    fn iter_with_expiry(&self) -> Box<dyn Iterator<Item = (u64, u64, Option<u64>)> + '_> {
+        Box::new(self.entries_at(.., u64::MAX))
+    }
+
+    // The whole batch is a single write-ahead log record, so after a crash
+    // either all of it or nothing is recovered
+    fn write(&mut self, batch: &WriteBatch) {
+        if batch.is_empty() {
+            return;
+        }
+        let rev = self.last_rev + 1;
+        let time = self.options.clock.now();
+        let record = bincode::DefaultOptions::new()
+            .serialize(&(rev, time, batch))
+            .unwrap();
+        self.wal.append(&record).unwrap();
+        if self.options.sync_wal {
+            self.wal.sync().unwrap();
+        }
+        self.apply(rev, time, batch);
         self.flush_on_threshold();
     }
+
+    // The batch skips the write-ahead log and the memtable and becomes the
+    // newest disktable, after what the memtable holds
+    fn write_sorted(&mut self, batch: &WriteBatch) {
+        if batch.is_empty() {
+            return;
+        }
+        assert!(batch
+            .ops()
+            .windows(2)
+            .all(|ops| ops[0].key() < ops[1].key()));
+        if !self.memtable.is_empty() {
+            self.flush();
+        }
+        let rev = self.last_rev + 1;
+        let time = self.options.clock.now();
+        let entries = batch
+            .ops()
+            .iter()
+            .enumerate()
+            .map(|(i, op)| batch_entry(op, rev + i as u64, time));
+        let disktable = DISKTABLE_REPOSITORY
+            .lock()
+            .unwrap()
+            .write_entries(entries, &self.options);
+        self.disktables.push(disktable);
+        self.last_rev = rev + batch.len() as u64 - 1;
+        self.flushed_rev = self.last_rev;
+        self.write_manifest();
+        self.compact();
+    }
 }
 
 impl LSMTree {
     fn flush_on_threshold(&mut self) {
-        if self.memtable.len() >= self.mem_sz_threshold {
-            let disktable = Disktable::from(self.memtable.clone());
-            self.disktables.push(disktable);
-            self.memtable.clear();
-            self.disktable_num += 1;
+        if self.memtable.len() >= self.options.memtable_capacity {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        let memtable = std::mem::take(&mut self.memtable);
+        let disktable = DISKTABLE_REPOSITORY
+            .lock()
+            .unwrap()
+            .write_memtable(memtable, &self.options);
+        self.disktables.push(disktable);
+        self.flushed_rev = self.last_rev;
+        self.write_manifest();
+        self.wal.clear().unwrap();
+        self.compact();
+    }
+
+    fn write_manifest(&self) {
+        let manifest = Manifest {
+            flushed_rev: self.flushed_rev,
+            disktables: self.disktables.iter().map(Disktable::filename).collect(),
+        };
+        manifest.write(&*self.options.storage, &self.options.dir);
+    }
+
+    // Size-tiered: merge the two newest disktables while the older one is
+    // not much bigger than the newer one. Versions that neither the latest
+    // state nor a live snapshot can see are dropped on the way.
+    fn compact(&mut self) {
+        while self.disktables.len() >= 2 {
+            let n = self.disktables.len();
+            if self.disktables[n - 2].len() > 2 * self.disktables[n - 1].len() {
+                break;
+            }
+            let newer = self.disktables.pop().unwrap();
+            let older = self.disktables.pop().unwrap();
+            let merged = DISKTABLE_REPOSITORY.lock().unwrap().merge(
+                &older,
+                &newer,
+                &self.options,
+                self.live_snapshots(),
+                self.disktables.is_empty(),
+            );
+            self.disktables.push(merged);
+            self.write_manifest();
+            older.remove();
+            newer.remove();
         }
     }
 }
 
+impl Drop for LSMTree {
+    fn drop(&mut self) {
+        self.wal.sync().unwrap();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+    use crate::random::{test_seed, SeededRandom};
+    use crate::storage::MemStorage;
+    use std::collections::HashMap;
+    use std::fs::{self, OpenOptions};
+
+    fn test_options(name: &str) -> LSMTreeOptions {
+        let dir = format!("lsmt/{}", name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        LSMTreeOptions {
+            dir,
+            random: Arc::new(SeededRandom::new(test_seed())),
+            ..Default::default()
+        }
+    }
 
     #[test]
-    fn entry_serde() {
-        {
-            let opts = DefaultOptions::new().allow_trailing_bytes();
-            let val = u64::max_value();
-            let serialized = opts
-                .serialize(&DisktableEntry::Insert {
-                    rev: val,
-                    key: val,
-                    value: val,
+    fn disktable_format() {
+        let entries = (0..1000u64)
+            .map(|key| {
+                if key % 3 == 0 {
+                    DisktableEntry::Delete { rev: 1, key }
+                } else {
+                    DisktableEntry::Insert {
+                        rev: 1,
+                        key,
+                        value: key * 2,
+                        expires_at: None,
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+        let caches = [
+            (None, true),
+            (Some(Arc::new(BlockCache::new(1 << 20))), true),
+            (Some(Arc::new(BlockCache::new(256))), false),
+        ];
+        let dir = test_options("disktable_format").dir;
+        for (block_cache, pin_index_and_filter) in caches {
+            let options = LSMTreeOptions {
+                dir: dir.clone(),
+                disktable: DisktableOptions {
+                    block_size: 64,
+                    block_cache,
+                    pin_index_and_filter,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let disktable = DISKTABLE_REPOSITORY
+                .lock()
+                .unwrap()
+                .write_entries(entries.clone(), &options);
+            assert!(disktable.index().len() > 1);
+            assert_eq!(disktable.len(), entries.len());
+            assert!(disktable.iter().eq(entries.clone().into_iter()));
+            assert_eq!(
+                disktable.get(500, u64::MAX),
+                Some(DisktableEntry::Insert {
+                    rev: 1,
+                    key: 500,
+                    value: 1000,
+                    expires_at: None,
                 })
-                .unwrap();
-            assert_eq!(DisktableEntry::bin_size(), serialized.len());
-        }
-        let tests = [
-            DisktableEntry::Insert {
-                rev: 0,
-                key: 124,
-                value: 421,
-            },
-            DisktableEntry::Insert {
-                rev: 2,
-                key: 0,
-                value: 9,
-            },
-            DisktableEntry::Insert {
-                rev: 1,
-                key: 1,
-                value: 21,
+            );
+            assert_eq!(
+                disktable.get(501, u64::MAX),
+                Some(DisktableEntry::Delete { rev: 1, key: 501 })
+            );
+            assert_eq!(disktable.get(1000, u64::MAX), None);
+            let keys = [0, 1, 2, 500, 501, 999, 1000];
+            assert_eq!(
+                disktable.get_sorted(&keys, u64::MAX),
+                keys.iter()
+                    .map(|&key| disktable.get(key, u64::MAX))
+                    .collect::<Vec<_>>()
+            );
+            disktable.remove();
+            if let Some(cache) = options.disktable.block_cache {
+                assert_eq!(cache.stats().usage, 0);
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shared_block_cache() {
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let options1 = LSMTreeOptions {
+            memtable_capacity: 100,
+            disktable: DisktableOptions {
+                block_cache: Some(cache.clone()),
+                ..Default::default()
             },
-            DisktableEntry::Delete { rev: 123, key: 9 },
-            DisktableEntry::Delete { rev: 13, key: 91 },
-        ];
+            ..test_options("shared_block_cache1")
+        };
+        let options2 = LSMTreeOptions {
+            dir: test_options("shared_block_cache2").dir,
+            ..options1.clone()
+        };
+        let mut tree1 = LSMTree::new(&options1);
+        let mut tree2 = LSMTree::new(&options2);
+        for key in 0..1000 {
+            tree1.set(key, key);
+            tree2.set(key, key + 1);
+        }
+        assert_eq!(cache.stats().pinned_usage, cache.stats().usage);
+
+        assert_eq!(tree1.get(1), Some(1));
+        let misses = cache.stats().misses;
+        assert_eq!(tree1.get(1), Some(1));
+        assert_eq!(tree2.get(1), Some(2));
+        assert_eq!(tree2.get(1), Some(2));
+        let stats = cache.stats();
+        assert_eq!(stats.misses, misses + 1);
+        assert!(stats.hits >= 2);
+        assert!(Arc::ptr_eq(tree1.block_cache().unwrap(), &cache));
+        drop((tree1, tree2));
+        fs::remove_dir_all(options1.dir).unwrap();
+        fs::remove_dir_all(options2.dir).unwrap();
+    }
+
+    #[test]
+    fn check_correctness() {
+        let clock = Arc::new(ManualClock::default());
+        let options = LSMTreeOptions {
+            memtable_capacity: 500,
+            clock: clock.clone(),
+            ..test_options("check_correctness")
+        };
+        crate::conformance::run(|| LSMTree::new(&options), &clock, 1);
+        fs::remove_dir_all(options.dir).unwrap();
+    }
+
+    #[test]
+    fn seeded_filenames() {
+        let seed = test_seed();
+        let storage = Arc::new(MemStorage::new());
+        let filenames = |dir: &str| {
+            let options = LSMTreeOptions {
+                dir: dir.to_string(),
+                memtable_capacity: 10,
+                storage: storage.clone(),
+                random: Arc::new(SeededRandom::new(seed)),
+                ..Default::default()
+            };
+            let mut tree = LSMTree::new(&options);
+            for key in 0..100 {
+                tree.set(key, key);
+            }
+            let mut filenames = storage.read_dir(dir).unwrap();
+            filenames.sort();
+            filenames
+        };
+        let first = filenames("first");
+        assert!(first.len() > 2);
+        assert_eq!(filenames("second"), first);
+    }
 
-        for test in tests {
-            let serialized = test.serialize().unwrap();
-            println!("{}", serialized.len());
-            let deserialized = DisktableEntry::deserialize(serialized.as_ref()).unwrap();
-            assert_eq!(test, deserialized);
+    #[test]
+    fn stats() {
+        let storage = Arc::new(MemStorage::new());
+        let mut tree = LSMTree::new(&LSMTreeOptions {
+            memtable_capacity: 10,
+            storage: storage.clone(),
+            ..Default::default()
+        });
+        for key in 0..25 {
+            tree.set(key, key);
         }
+        let stats = tree.stats();
+        assert_eq!(stats.memtable_entries, 5);
+        assert!(stats.wal_bytes > 0);
+        assert_eq!(
+            stats
+                .disktables
+                .iter()
+                .map(|d| d.entries)
+                .collect::<Vec<_>>(),
+            [20]
+        );
+        let disktable = &stats.disktables[0];
+        let path = format!("{}/{}", tree.options.dir, disktable.filename);
+        let file = storage.open(&path, OpenMode::Existing).unwrap();
+        assert_eq!(file.len().unwrap() as usize, disktable.bytes);
     }
 
+    #[test]
+    fn crash_consistency() {
+        // Small memtables, so the workload flushes and compacts
+        let open = |storage| {
+            LSMTree::new(&LSMTreeOptions {
+                dir: "lsmt".to_string(),
+                memtable_capacity: 8,
+                sync_wal: true,
+                storage,
+                ..Default::default()
+            })
+        };
+        crate::crash::run(open, &crate::crash::workload(5, 40));
+    }
 
     #[test]
-    fn check_correctness() {
-        let mut my_table = LSMTree::new(1e3 as usize);
-        let mut table = HashMap::new();
-        const ITERS: usize = 1e4 as usize;
-        let mut rng = rand::thread_rng();
-        for i in 0..ITERS {
-            let key = rng.gen::<u64>();
-            let value = rng.gen::<u64>();
-            my_table.set(key, value);
-            table.insert(key, value);
-        }
-        for _ in 0..ITERS {
-            let key = rng.gen::<u64>();
-            assert_eq!(my_table.get(key), table.get(&key).copied());
+    fn snapshots() {
+        let options = LSMTreeOptions {
+            memtable_capacity: 50,
+            ..test_options("snapshots")
+        };
+        let mut tree = LSMTree::new(&options);
+        let mut model = BTreeMap::new();
+        let mut rng = options.random.rng();
+        let mut snapshots = Vec::new();
+        for i in 0..5000 {
+            let key = rng.gen::<u64>() % 500;
+            if rng.gen_bool(0.3) {
+                tree.remove(key);
+                model.remove(&key);
+            } else {
+                tree.set(key, i);
+                model.insert(key, i);
+            }
+            if i % 1000 == 0 {
+                snapshots.push((tree.snapshot(), model.clone()));
+            }
+            if i == 2500 {
+                // Versions only this snapshot needed may be compacted away now
+                snapshots.remove(1);
+            }
+        }
+        assert_eq!(tree.last_rev(), 5000);
+        for (snapshot, model) in &snapshots {
+            for key in 0..500 {
+                assert_eq!(snapshot.get(&tree, key), model.get(&key).copied());
+            }
+            assert!(snapshot
+                .range(&tree, 100..=400)
+                .eq(model.range(100..=400).map(|(&k, &v)| (k, v))));
         }
+        assert!(tree.range(..).eq(model.iter().map(|(&k, &v)| (k, v))));
+        assert!(tree.iter().eq(model.iter().map(|(&k, &v)| (k, v))));
+        assert!(tree
+            .range((Bound::Excluded(10), Bound::Excluded(20)))
+            .eq(model.range(11..20).map(|(&k, &v)| (k, v))));
+        assert_eq!(
+            tree.range((Bound::Excluded(u64::MAX), Bound::Unbounded))
+                .count(),
+            0
+        );
+
+        let versions = |tree: &LSMTree| {
+            tree.disktables.iter().map(|d| d.len()).sum::<usize>() + tree.memtable.len()
+        };
+        let before = versions(&tree);
+        snapshots.clear();
+        for i in 0..5000 {
+            tree.set(rng.gen::<u64>() % 500, i);
+        }
+        assert!(versions(&tree) < before + 5000);
+        drop(tree);
+        fs::remove_dir_all(options.dir).unwrap();
+    }
+
+    #[test]
+    fn merge_counters() {
+        let options = LSMTreeOptions {
+            memtable_capacity: 50,
+            ..test_options("merge_counters")
+        };
+        let mut model = BTreeMap::new();
+        {
+            let mut tree = LSMTree::new(&options);
+            let mut rng = options.random.rng();
+            let mut snapshots = Vec::new();
+            for i in 0..3000 {
+                let key = rng.gen::<u64>() % 50;
+                match rng.gen_range(0..10) {
+                    0 => {
+                        tree.remove(key);
+                        model.remove(&key);
+                    }
+                    1 => {
+                        tree.set(key, i);
+                        model.insert(key, i);
+                    }
+                    _ => {
+                        tree.merge(key, i);
+                        *model.entry(key).or_insert(0) += i;
+                    }
+                }
+                if i % 700 == 0 {
+                    snapshots.push((tree.snapshot(), model.clone()));
+                }
+            }
+            for (snapshot, model) in &snapshots {
+                assert!(snapshot.range(&tree, ..).eq(model.clone().into_iter()));
+            }
+            assert!(tree.iter().eq(model.clone().into_iter()));
+            let keys = (0..60).collect::<Vec<_>>();
+            assert_eq!(
+                tree.get_many(&keys),
+                keys.iter()
+                    .map(|key| model.get(key).copied())
+                    .collect::<Vec<_>>()
+            );
+        }
+        let mut tree = LSMTree::new(&options);
+        for key in 0..50 {
+            assert_eq!(tree.get(key), model.get(&key).copied());
+        }
+        tree.set(100, u64::MAX - 1);
+        tree.merge(100, 5);
+        assert_eq!(tree.get(100), Some(u64::MAX));
+        drop(tree);
+        fs::remove_dir_all(options.dir).unwrap();
+    }
+
+    #[test]
+    fn expiry() {
+        let clock = Arc::new(ManualClock::new(1000));
+        let options = LSMTreeOptions {
+            memtable_capacity: 100,
+            clock: clock.clone(),
+            ..test_options("expiry")
+        };
+        let ttl = Duration::from_secs(60);
+        {
+            let mut tree = LSMTree::new(&options);
+            for key in 0..1000 {
+                if key % 2 == 0 {
+                    tree.set_with_ttl(key, key, ttl);
+                } else {
+                    tree.set(key, key);
+                }
+            }
+            tree.merge(0, 1);
+            clock.advance(ttl / 2);
+            assert_eq!(tree.get(0), Some(1));
+            assert_eq!(tree.get(2), Some(2));
+            assert_eq!(tree.get_many(&[2, 3]), vec![Some(2), Some(3)]);
+        }
+        clock.advance(ttl / 2);
+        let mut tree = LSMTree::new(&options);
+        assert_eq!(tree.get(0), None);
+        assert_eq!(tree.get(2), None);
+        assert_eq!(tree.get(3), Some(3));
+        assert_eq!(tree.get_many(&[2, 3]), vec![None, Some(3)]);
+        assert!(tree.iter().map(|(key, _)| key).eq((1..1000).step_by(2)));
+        tree.merge(0, 1);
+        assert_eq!(tree.get(0), Some(1));
+
+        // Flushes and compactions keep hiding them
+        for key in 1000..1500 {
+            tree.set(key, key);
+        }
+        assert!(tree.iter().map(|(key, _)| key).eq((1..1000)
+            .step_by(2)
+            .chain([0])
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .chain(1000..1500)));
+        drop(tree);
+        fs::remove_dir_all(options.dir).unwrap();
+    }
+
+    #[test]
+    fn write_batches_and_recovery() {
+        let options = LSMTreeOptions {
+            memtable_capacity: 100,
+            ..test_options("write_batches_and_recovery")
+        };
+        let mut model = HashMap::new();
+        {
+            let mut tree = LSMTree::new(&options);
+            for i in 0..260 {
+                let mut batch = WriteBatch::new();
+                batch.set(i, i).set(i + 1000, i).remove(i / 2);
+                tree.write(&batch);
+                model.insert(i, i);
+                model.insert(i + 1000, i);
+                model.remove(&(i / 2));
+            }
+            assert_eq!(tree.last_rev(), 780);
+            assert!(!tree.disktables.is_empty() && !tree.memtable.is_empty());
+        }
+        let wal_path = format!("{}/{}", options.dir, WAL_FILENAME);
+        let wal_size = fs::metadata(&wal_path).unwrap().len();
+        {
+            let mut tree = LSMTree::new(&options);
+            assert_eq!(tree.last_rev(), 780);
+            for key in 0..2000 {
+                assert_eq!(tree.get(key), model.get(&key).copied());
+            }
+            let mut batch = WriteBatch::new();
+            batch.set(5000, 1).set(5001, 1).remove(1000);
+            tree.write(&batch);
+            assert_eq!(tree.get(1000), None);
+        }
+
+        // A crash in the middle of writing the last batch
+        let wal = OpenOptions::new().write(true).open(&wal_path).unwrap();
+        assert!(wal.metadata().unwrap().len() > wal_size);
+        wal.set_len(wal.metadata().unwrap().len() - 1).unwrap();
+        let tree = LSMTree::new(&options);
+        assert_eq!(tree.last_rev(), 780);
+        assert_eq!(tree.get(5000), None);
+        assert_eq!(tree.get(5001), None);
+        assert_eq!(tree.get(1000), Some(0));
+        drop(tree);
+        fs::remove_dir_all(options.dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_manifest() {
+        let storage = Arc::new(MemStorage::new());
+        let options = LSMTreeOptions {
+            dir: "unreadable_manifest".to_string(),
+            memtable_capacity: 100,
+            storage: storage.clone(),
+            ..Default::default()
+        };
+        {
+            let mut tree = LSMTree::new(&options);
+            for key in 0..500 {
+                tree.set(key, key);
+            }
+        }
+        let disktables = || {
+            let mut filenames = storage.read_dir("unreadable_manifest").unwrap();
+            filenames.retain(|filename| DisktableRepository::is_disktable(filename));
+            filenames
+        };
+        let before = disktables();
+        assert!(!before.is_empty());
+
+        // A manifest that can't be decoded is refused, nothing is removed
+        let manifest_path = format!("unreadable_manifest/{}", MANIFEST_FILENAME);
+        storage::write_atomically(&*storage, &manifest_path, b"garbage").unwrap();
+        let opened = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            LSMTree::new(&options);
+        }));
+        assert!(opened.is_err());
+        assert_eq!(disktables(), before);
+
+        // Nor is a directory that lost its manifest opened empty
+        storage.remove(&manifest_path).unwrap();
+        let opened = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            LSMTree::new(&options);
+        }));
+        assert!(opened.is_err());
+        assert!(!storage.exists(&manifest_path));
+        assert_eq!(disktables(), before);
+        assert!(LSMTree::fsck(&options, true).repairs[0].starts_with("rebuilt the manifest"));
+        assert_eq!(LSMTree::new(&options).get(499), Some(499));
     }
 }
@@ -0,0 +1,255 @@
+use super::format::{BloomFilter, IndexEntry};
+use super::DisktableEntry;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[derive(Clone)]
+pub(super) enum CachedBlock {
+    Data(Arc<Vec<DisktableEntry>>),
+    Index(Arc<Vec<IndexEntry>>),
+    Filter(Arc<BloomFilter>),
+}
+
+// (disktable id, block offset)
+type BlockKey = (u64, u64);
+
+struct Slot {
+    key: BlockKey,
+    block: CachedBlock,
+    charge: usize,
+    referenced: bool,
+    pinned: bool,
+}
+
+struct CacheState {
+    slots: Vec<Option<Slot>>,
+    free_slots: Vec<usize>,
+    positions: HashMap<BlockKey, usize>,
+    hand: usize,
+    usage: usize,
+    pinned_usage: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub usage: usize,
+    pub pinned_usage: usize,
+    pub capacity: usize,
+}
+
+// CLOCK (second chance) cache of decoded disktable blocks. It can be shared
+// between any number of disktables and trees; pinned blocks are charged to
+// the capacity but never evicted until their disktable goes away.
+pub struct BlockCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+    next_table_id: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            state: Mutex::new(CacheState {
+                slots: Vec::new(),
+                free_slots: Vec::new(),
+                positions: HashMap::new(),
+                hand: 0,
+                usage: 0,
+                pinned_usage: 0,
+            }),
+            next_table_id: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn new_table_id(&self) -> u64 {
+        self.next_table_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(super) fn lookup(&self, key: BlockKey) -> Option<CachedBlock> {
+        let mut state = self.state.lock().unwrap();
+        let found = state.positions.get(&key).copied().map(|pos| {
+            let slot = state.slots[pos].as_mut().unwrap();
+            slot.referenced = true;
+            slot.block.clone()
+        });
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    pub(super) fn insert(&self, key: BlockKey, block: CachedBlock, charge: usize, pinned: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pos) = state.positions.remove(&key) {
+            Self::release(&mut state, pos);
+        }
+        if !pinned {
+            if state.pinned_usage + charge > self.capacity {
+                return;
+            }
+            self.evict(&mut state, charge);
+            if state.usage + charge > self.capacity {
+                return;
+            }
+        }
+        let slot = Slot {
+            key,
+            block,
+            charge,
+            referenced: false,
+            pinned,
+        };
+        let pos = match state.free_slots.pop() {
+            Some(pos) => {
+                state.slots[pos] = Some(slot);
+                pos
+            }
+            None => {
+                state.slots.push(Some(slot));
+                state.slots.len() - 1
+            }
+        };
+        state.positions.insert(key, pos);
+        state.usage += charge;
+        if pinned {
+            state.pinned_usage += charge;
+        }
+    }
+
+    pub(super) fn erase_table(&self, table_id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let positions = state
+            .positions
+            .iter()
+            .filter(|((id, _), _)| *id == table_id)
+            .map(|(key, pos)| (*key, *pos))
+            .collect::<Vec<_>>();
+        for (key, pos) in positions {
+            state.positions.remove(&key);
+            Self::release(&mut state, pos);
+        }
+    }
+
+    fn release(state: &mut CacheState, pos: usize) {
+        let slot = state.slots[pos].take().unwrap();
+        state.usage -= slot.charge;
+        if slot.pinned {
+            state.pinned_usage -= slot.charge;
+        }
+        state.free_slots.push(pos);
+    }
+
+    fn evict(&self, state: &mut CacheState, charge: usize) {
+        // Two full turns of the hand are enough to clear every reference bit
+        let mut steps = 2 * state.slots.len();
+        while state.usage + charge > self.capacity && steps > 0 {
+            steps -= 1;
+            let pos = state.hand;
+            state.hand = (state.hand + 1) % state.slots.len();
+            let Some(slot) = state.slots[pos].as_mut() else {
+                continue;
+            };
+            if slot.pinned {
+                continue;
+            }
+            if slot.referenced {
+                slot.referenced = false;
+                continue;
+            }
+            let key = slot.key;
+            state.positions.remove(&key);
+            Self::release(state, pos);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        let state = self.state.lock().unwrap();
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            usage: state.usage,
+            pinned_usage: state.pinned_usage,
+            capacity: self.capacity,
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_block(key: u64) -> CachedBlock {
+        CachedBlock::Data(Arc::new(vec![DisktableEntry::Delete { rev: 0, key }]))
+    }
+
+    #[test]
+    fn eviction_and_counters() {
+        let cache = BlockCache::new(100);
+        for offset in 0..10 {
+            cache.insert((0, offset), data_block(offset), 20, false);
+        }
+        let stats = cache.stats();
+        assert!(stats.usage <= 100);
+        assert_eq!(stats.evictions, 5);
+        assert!(cache.lookup((0, 9)).is_some());
+        assert!(cache.lookup((0, 0)).is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // The hand points at block 5 next, but it gets a second chance
+        assert!(cache.lookup((0, 5)).is_some());
+        cache.insert((0, 10), data_block(10), 20, false);
+        assert!(cache.lookup((0, 5)).is_some());
+        assert!(cache.lookup((0, 6)).is_none());
+
+        // Blocks larger than the whole cache are not kept
+        cache.insert((0, 11), data_block(11), 1000, false);
+        assert!(cache.lookup((0, 11)).is_none());
+    }
+
+    #[test]
+    fn pinned_blocks() {
+        let cache = BlockCache::new(50);
+        let table = cache.new_table_id();
+        cache.insert((table, 0), data_block(0), 40, true);
+        for offset in 1..10 {
+            cache.insert((table + 1, offset), data_block(offset), 10, false);
+        }
+        assert!(cache.lookup((table, 0)).is_some());
+        assert_eq!(cache.stats().pinned_usage, 40);
+
+        cache.erase_table(table);
+        let stats = cache.stats();
+        assert_eq!(stats.pinned_usage, 0);
+        assert_eq!(stats.usage, 10);
+        assert!(cache.lookup((table, 0)).is_none());
+    }
+}
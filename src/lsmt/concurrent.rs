@@ -0,0 +1,297 @@
+use super::{
+    batch_entry, iter::Resolver, recover, Disktable, DisktableEntry, LSMTreeOptions, Manifest,
+    DISKTABLE_REPOSITORY,
+};
+use crate::hash_table::ConcurrentHashTable;
+use crate::record_log::RecordLog;
+use crate::write_batch::WriteBatch;
+use bincode::Options;
+use crossbeam_skiplist::SkipMap;
+use std::{
+    cmp::Reverse,
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+// Readers take a reference to the current state and never block on writers.
+// Disktables are immutable and a flush or compaction publishes a new state, so
+// a reader keeps seeing the tables it started with even if they are compacted
+// away in the meantime.
+struct TreeState {
+    memtable: SkipMap<(u64, Reverse<u64>), DisktableEntry>,
+    disktables: Vec<Arc<Disktable>>,
+}
+
+struct Writer {
+    wal: RecordLog,
+    flushed_rev: u64,
+}
+
+// LSMTree that can be shared between threads. Writes are serialized, while
+// reads run in parallel with them and with each other.
+pub struct ConcurrentLSMTree {
+    state: RwLock<Arc<TreeState>>,
+    writer: Mutex<Writer>,
+    // Revisions up to this one are completely in the memtable or disktables
+    last_rev: AtomicU64,
+    options: LSMTreeOptions,
+}
+
+impl ConcurrentLSMTree {
+    pub fn new(options: &LSMTreeOptions) -> Self {
+        let recovered = recover(options);
+        let tree = ConcurrentLSMTree {
+            state: RwLock::new(Arc::new(TreeState {
+                memtable: SkipMap::new(),
+                disktables: recovered.disktables.into_iter().map(Arc::new).collect(),
+            })),
+            writer: Mutex::new(Writer {
+                wal: recovered.wal,
+                flushed_rev: recovered.flushed_rev,
+            }),
+            last_rev: AtomicU64::new(recovered.flushed_rev),
+            options: options.clone(),
+        };
+        let state = tree.state();
+        for (rev, time, batch) in recovered.records {
+            tree.apply(&state, rev, time, &batch);
+        }
+        tree.flush_on_threshold(&mut tree.writer.lock().unwrap());
+        tree
+    }
+
+    pub fn last_rev(&self) -> u64 {
+        self.last_rev.load(Ordering::Acquire)
+    }
+
+    fn state(&self) -> Arc<TreeState> {
+        self.state.read().unwrap().clone()
+    }
+
+    fn get_latest(&self, key: u64) -> Option<u64> {
+        // The state has to be taken first: its disktables never contain
+        // revisions newer than the last one published before it
+        let state = self.state();
+        let rev = self.last_rev();
+        let versions = state
+            .memtable
+            .range((key, Reverse(rev))..=(key, Reverse(0)))
+            .map(|entry| entry.value().clone())
+            .chain(
+                state
+                    .disktables
+                    .iter()
+                    .rev()
+                    .flat_map(|disktable| disktable.versions(key, rev)),
+            );
+        Resolver::new(&self.options).resolve(versions)
+    }
+
+    // Older versions stay in the memtable until it is flushed, since a reader
+    // may still be looking for them
+    fn apply(&self, state: &TreeState, rev: u64, time: u64, batch: &WriteBatch) {
+        for (i, op) in batch.ops().iter().enumerate() {
+            let rev = rev + i as u64;
+            state
+                .memtable
+                .insert((op.key(), Reverse(rev)), batch_entry(op, rev, time));
+        }
+        // Readers only look at the batch once all of it is in the memtable
+        self.last_rev
+            .fetch_max(rev + batch.len() as u64 - 1, Ordering::Release);
+    }
+
+    fn publish(&self, state: TreeState) {
+        *self.state.write().unwrap() = Arc::new(state);
+    }
+
+    fn flush_on_threshold(&self, writer: &mut Writer) {
+        let state = self.state();
+        if state.memtable.len() < self.options.memtable_capacity {
+            return;
+        }
+        let entries = state.memtable.iter().map(|entry| entry.value().clone());
+        let disktable = DISKTABLE_REPOSITORY
+            .lock()
+            .unwrap()
+            .write_entries(entries, &self.options);
+        let mut disktables = state.disktables.clone();
+        disktables.push(Arc::new(disktable));
+        writer.flushed_rev = self.last_rev();
+        self.write_manifest(writer, &disktables);
+        self.publish(TreeState {
+            memtable: SkipMap::new(),
+            disktables,
+        });
+        writer.wal.clear().unwrap();
+        self.compact(writer);
+    }
+
+    fn write_manifest(&self, writer: &Writer, disktables: &[Arc<Disktable>]) {
+        let manifest = Manifest {
+            flushed_rev: writer.flushed_rev,
+            disktables: disktables.iter().map(|d| d.filename()).collect(),
+        };
+        manifest.write(&*self.options.storage, &self.options.dir);
+    }
+
+    // Same policy as LSMTree::compact. Replaced disktables are unlinked right
+    // away, readers that still hold them keep reading the open files.
+    fn compact(&self, writer: &Writer) {
+        let mut disktables = self.state().disktables.clone();
+        while disktables.len() >= 2 {
+            let n = disktables.len();
+            if disktables[n - 2].len() > 2 * disktables[n - 1].len() {
+                break;
+            }
+            let newer = disktables.pop().unwrap();
+            let older = disktables.pop().unwrap();
+            let merged = DISKTABLE_REPOSITORY.lock().unwrap().merge(
+                &older,
+                &newer,
+                &self.options,
+                BTreeSet::new(),
+                disktables.is_empty(),
+            );
+            disktables.push(Arc::new(merged));
+            self.write_manifest(writer, &disktables);
+            self.publish(TreeState {
+                memtable: SkipMap::new(),
+                disktables: disktables.clone(),
+            });
+            older.storage.remove(&older.path).unwrap();
+            newer.storage.remove(&newer.path).unwrap();
+        }
+    }
+}
+
+impl ConcurrentHashTable for ConcurrentLSMTree {
+    fn set(&self, key: u64, value: u64) {
+        self.write(WriteBatch::new().set(key, value));
+    }
+
+    fn set_with_ttl(&self, key: u64, value: u64, ttl: Duration) {
+        let expires_at = self.options.clock.expires_at(ttl);
+        self.write(WriteBatch::new().set_with_expiry(key, value, expires_at));
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.get_latest(key)
+    }
+
+    fn remove(&self, key: u64) {
+        self.write(WriteBatch::new().remove(key));
+    }
+
+    fn merge(&self, key: u64, operand: u64) {
+        self.write(WriteBatch::new().merge(key, operand));
+    }
+
+    fn write(&self, batch: &WriteBatch) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        let rev = self.last_rev() + 1;
+        let time = self.options.clock.now();
+        let record = bincode::DefaultOptions::new()
+            .serialize(&(rev, time, batch))
+            .unwrap();
+        writer.wal.append(&record).unwrap();
+        if self.options.sync_wal {
+            writer.wal.sync().unwrap();
+        }
+        self.apply(&self.state(), rev, time, batch);
+        self.flush_on_threshold(&mut writer);
+    }
+
+    fn on_disk_size(&self) -> usize {
+        let writer = self.writer.lock().unwrap();
+        self.state()
+            .disktables
+            .iter()
+            .map(|disktable| disktable.on_disk_size())
+            .sum::<usize>()
+            + writer.wal.size()
+    }
+}
+
+impl Drop for ConcurrentLSMTree {
+    fn drop(&mut self) {
+        self.writer.lock().unwrap().wal.sync().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_table::HashTable;
+    use crate::lsmt::LSMTree;
+    use std::{fs, thread};
+
+    #[test]
+    fn parallel_readers_and_writers() {
+        let options = LSMTreeOptions {
+            dir: "lsmt/parallel_readers_and_writers".to_string(),
+            memtable_capacity: 500,
+            ..Default::default()
+        };
+        let _ = fs::remove_dir_all(&options.dir);
+        let tree = ConcurrentLSMTree::new(&options);
+        const WRITERS: u64 = 4;
+        const KEYS: u64 = 100;
+        const ROUNDS: u64 = 30;
+        thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let tree = &tree;
+                scope.spawn(move || {
+                    for round in 1..=ROUNDS {
+                        // Both halves of the batch are always seen together
+                        let mut batch = WriteBatch::new();
+                        for key in writer * KEYS..(writer + 1) * KEYS {
+                            batch.set(key, round).set(key + 1_000_000, round);
+                        }
+                        tree.write(&batch);
+                        tree.merge(u64::MAX, 1);
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let tree = &tree;
+                scope.spawn(move || {
+                    let mut seen = vec![0; (WRITERS * KEYS) as usize];
+                    for _ in 0..20 {
+                        for key in 0..WRITERS * KEYS {
+                            let value = tree.get(key).unwrap_or(0);
+                            // A later read never goes back to an older round
+                            assert!(value >= seen[key as usize]);
+                            seen[key as usize] = value;
+                            assert!(tree.get(key + 1_000_000).unwrap_or(0) >= value);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(tree.last_rev(), WRITERS * (KEYS * 2 + 1) * ROUNDS);
+        assert!(!tree.state().disktables.is_empty());
+        for key in 0..WRITERS * KEYS {
+            assert_eq!(tree.get(key), Some(ROUNDS));
+        }
+        assert_eq!(tree.get(u64::MAX), Some(WRITERS * ROUNDS));
+        tree.remove(0);
+        drop(tree);
+
+        // Both variants share the on-disk format
+        let tree = LSMTree::new(&options);
+        assert_eq!(tree.last_rev(), WRITERS * (KEYS * 2 + 1) * ROUNDS + 1);
+        assert_eq!(HashTable::get(&tree, u64::MAX), Some(WRITERS * ROUNDS));
+        assert_eq!(HashTable::get(&tree, 0), None);
+        assert_eq!(HashTable::get(&tree, 1), Some(ROUNDS));
+        drop(tree);
+        fs::remove_dir_all(options.dir).unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+// Disktable file layout (version 1):
+//
+//   [data block 0] ... [data block N-1] [filter block] [index block] [footer]
+//
+// Every block is followed by a 5 byte trailer: compression type (u8) and a
+// crc32 of the stored payload and the type byte. Data blocks hold entries
+// sorted by key with the key delta-encoded against the previous entry of the
+// same block. The index block maps the last key of every data block to its
+// handle, the filter block is a bloom filter over all keys and the fixed-size
+// footer points at both of them. The footer ends with a crc32 of its fields,
+// the version and the magic number.
+
+use super::DisktableEntry;
+use std::{io, mem::size_of};
+
+pub const MAGIC: u64 = 0x0062_6479_7473_6168; // "hastydb" in little endian
+pub const VERSION: u32 = 1;
+pub const FOOTER_SIZE: usize =
+    5 * size_of::<u64>() + size_of::<u32>() + size_of::<u32>() + size_of::<u64>();
+pub const BLOCK_TRAILER_SIZE: usize = 1 + size_of::<u32>();
+
+const TAG_INSERT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+const TAG_MERGE: u8 = 2;
+const TAG_EXPIRING_INSERT: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    fn to_byte(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            _ => Err(corrupted(format!("unknown compression type {}", byte))),
+        }
+    }
+}
+
+pub fn corrupted(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn get_varint(bytes: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| corrupted(format!("truncated varint at {}", *pos)))?;
+        *pos += 1;
+        if shift == 63 && byte > 1 {
+            return Err(corrupted(format!("varint overflow at {}", *pos - 1)));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    // Handles come from disk, they are checked before anything is allocated
+    pub fn check(&self, end: u64) -> io::Result<()> {
+        match self.offset.checked_add(self.size) {
+            Some(block_end) if block_end <= end => Ok(()),
+            _ => Err(corrupted(format!(
+                "block of {} bytes at {} runs past {}",
+                self.size, self.offset, end
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub last_key: u64,
+    pub handle: BlockHandle,
+}
+
+pub struct BlockBuilder {
+    buf: Vec<u8>,
+    last_key: u64,
+    len: usize,
+}
+
+impl BlockBuilder {
+    pub fn new() -> Self {
+        BlockBuilder {
+            buf: Vec::new(),
+            last_key: 0,
+            len: 0,
+        }
+    }
+
+    pub fn add(&mut self, entry: &DisktableEntry) {
+        let key = entry.get_key();
+        debug_assert!(self.len == 0 || key >= self.last_key);
+        put_varint(&mut self.buf, key - self.last_key);
+        put_varint(&mut self.buf, entry.get_rev());
+        match entry {
+            DisktableEntry::Insert {
+                value,
+                expires_at: None,
+                ..
+            } => {
+                self.buf.push(TAG_INSERT);
+                put_varint(&mut self.buf, *value);
+            }
+            DisktableEntry::Insert {
+                value,
+                expires_at: Some(expires_at),
+                ..
+            } => {
+                self.buf.push(TAG_EXPIRING_INSERT);
+                put_varint(&mut self.buf, *value);
+                put_varint(&mut self.buf, *expires_at);
+            }
+            DisktableEntry::Delete { .. } => self.buf.push(TAG_DELETE),
+            DisktableEntry::Merge {
+                operand,
+                written_at,
+                ..
+            } => {
+                self.buf.push(TAG_MERGE);
+                put_varint(&mut self.buf, *operand);
+                put_varint(&mut self.buf, *written_at);
+            }
+        }
+        self.last_key = key;
+        self.len += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn size_estimate(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn last_key(&self) -> u64 {
+        self.last_key
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.last_key = 0;
+        self.len = 0;
+        std::mem::take(&mut self.buf)
+    }
+}
+
+pub fn decode_block(raw: &[u8]) -> io::Result<Vec<DisktableEntry>> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    let mut key = 0u64;
+    while pos < raw.len() {
+        key = key
+            .checked_add(get_varint(raw, &mut pos)?)
+            .ok_or_else(|| corrupted(format!("key overflow at {}", pos)))?;
+        let rev = get_varint(raw, &mut pos)?;
+        let tag = *raw
+            .get(pos)
+            .ok_or_else(|| corrupted(format!("truncated entry at {}", pos)))?;
+        pos += 1;
+        entries.push(match tag {
+            TAG_INSERT => DisktableEntry::Insert {
+                rev,
+                key,
+                value: get_varint(raw, &mut pos)?,
+                expires_at: None,
+            },
+            TAG_EXPIRING_INSERT => DisktableEntry::Insert {
+                rev,
+                key,
+                value: get_varint(raw, &mut pos)?,
+                expires_at: Some(get_varint(raw, &mut pos)?),
+            },
+            TAG_DELETE => DisktableEntry::Delete { rev, key },
+            TAG_MERGE => DisktableEntry::Merge {
+                rev,
+                key,
+                operand: get_varint(raw, &mut pos)?,
+                written_at: get_varint(raw, &mut pos)?,
+            },
+            _ => {
+                return Err(corrupted(format!(
+                    "unknown entry tag {} at {}",
+                    tag,
+                    pos - 1
+                )))
+            }
+        });
+    }
+    Ok(entries)
+}
+
+pub fn seal_block(raw: Vec<u8>, compression: Compression) -> Vec<u8> {
+    let (compression, mut stored) = match compression {
+        Compression::None => (Compression::None, raw),
+        Compression::Lz4 => {
+            let compressed = lz4_flex::compress_prepend_size(&raw);
+            // Only keep the compressed form when it saves at least 1/8 of the block
+            if compressed.len() < raw.len() - raw.len() / 8 {
+                (Compression::Lz4, compressed)
+            } else {
+                (Compression::None, raw)
+            }
+        }
+    };
+    stored.push(compression.to_byte());
+    let crc = crc32fast::hash(&stored);
+    stored.extend_from_slice(&crc.to_le_bytes());
+    stored
+}
+
+pub fn open_block(stored: &[u8]) -> io::Result<Vec<u8>> {
+    if stored.len() < BLOCK_TRAILER_SIZE {
+        return Err(corrupted(format!(
+            "block of {} bytes is too short",
+            stored.len()
+        )));
+    }
+    let (body, crc) = stored.split_at(stored.len() - size_of::<u32>());
+    let crc = u32::from_le_bytes(crc.try_into().unwrap());
+    if crc32fast::hash(body) != crc {
+        return Err(corrupted("block checksum mismatch".to_string()));
+    }
+    let (payload, compression) = body.split_at(body.len() - 1);
+    match Compression::from_byte(compression[0])? {
+        Compression::None => Ok(payload.to_vec()),
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(payload)
+            .map_err(|err| corrupted(format!("lz4: {}", err))),
+    }
+}
+
+pub fn encode_index(index: &[IndexEntry]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for entry in index {
+        put_varint(&mut buf, entry.last_key);
+        put_varint(&mut buf, entry.handle.offset);
+        put_varint(&mut buf, entry.handle.size);
+    }
+    buf
+}
+
+pub fn decode_index(raw: &[u8]) -> io::Result<Vec<IndexEntry>> {
+    let mut index = Vec::new();
+    let mut pos = 0;
+    while pos < raw.len() {
+        let last_key = get_varint(raw, &mut pos)?;
+        let offset = get_varint(raw, &mut pos)?;
+        let size = get_varint(raw, &mut pos)?;
+        index.push(IndexEntry {
+            last_key,
+            handle: BlockHandle { offset, size },
+        });
+    }
+    Ok(index)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    hashes: u8,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn build(keys: &[u64], bits_per_key: usize) -> Self {
+        if bits_per_key == 0 || keys.is_empty() {
+            return BloomFilter {
+                hashes: 0,
+                bits: Vec::new(),
+            };
+        }
+        // ln(2) * bits_per_key minimizes the false positive rate
+        let hashes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        let bits_num = (keys.len() * bits_per_key).max(64);
+        let mut filter = BloomFilter {
+            hashes,
+            bits: vec![0; bits_num.div_ceil(8)],
+        };
+        for &key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    fn bit_positions(&self, key: u64) -> impl Iterator<Item = usize> {
+        let bits_num = (self.bits.len() * 8) as u64;
+        let mut hash = mix64(key);
+        let delta = hash.rotate_right(33);
+        (0..self.hashes).map(move |_| {
+            let bit = (hash % bits_num) as usize;
+            hash = hash.wrapping_add(delta);
+            bit
+        })
+    }
+
+    pub fn may_contain(&self, key: u64) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.bits.len() + 1);
+        buf.push(self.hashes);
+        buf.extend_from_slice(&self.bits);
+        buf
+    }
+
+    pub fn decode(raw: &[u8]) -> io::Result<Self> {
+        let (&hashes, bits) = raw
+            .split_first()
+            .ok_or_else(|| corrupted("empty filter block".to_string()))?;
+        Ok(BloomFilter {
+            hashes,
+            bits: bits.to_vec(),
+        })
+    }
+}
+
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Footer {
+    pub filter: BlockHandle,
+    pub index: BlockHandle,
+    pub entries: u64,
+}
+
+impl Footer {
+    pub fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut bytes = [0; FOOTER_SIZE];
+        let fields = [
+            self.filter.offset,
+            self.filter.size,
+            self.index.offset,
+            self.index.size,
+            self.entries,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&field.to_le_bytes());
+        }
+        bytes[44..48].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[48..56].copy_from_slice(&MAGIC.to_le_bytes());
+        let crc = Self::crc(&bytes);
+        bytes[40..44].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn crc(bytes: &[u8; FOOTER_SIZE]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[..40]);
+        hasher.update(&bytes[44..]);
+        hasher.finalize()
+    }
+
+    pub fn decode(bytes: &[u8; FOOTER_SIZE]) -> io::Result<Self> {
+        // Version and magic number end the footer in every version
+        let magic = u64::from_le_bytes(bytes[48..56].try_into().unwrap());
+        if magic != MAGIC {
+            return Err(corrupted(format!("bad magic number {:#x}", magic)));
+        }
+        let version = u32::from_le_bytes(bytes[44..48].try_into().unwrap());
+        if version != VERSION {
+            return Err(corrupted(format!(
+                "unsupported disktable version {}",
+                version
+            )));
+        }
+        let crc = u32::from_le_bytes(bytes[40..44].try_into().unwrap());
+        if Self::crc(bytes) != crc {
+            return Err(corrupted("footer checksum mismatch".to_string()));
+        }
+        let field = |i: usize| u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        Ok(Footer {
+            filter: BlockHandle {
+                offset: field(0),
+                size: field(1),
+            },
+            index: BlockHandle {
+                offset: field(2),
+                size: field(3),
+            },
+            entries: field(4),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_roundtrip() {
+        let entries = vec![
+            DisktableEntry::Insert {
+                rev: 3,
+                key: 0,
+                value: u64::MAX,
+                expires_at: Some(u64::MAX),
+            },
+            DisktableEntry::Delete { rev: 1, key: 0 },
+            DisktableEntry::Insert {
+                rev: u64::MAX,
+                key: 1000,
+                value: 0,
+                expires_at: None,
+            },
+            DisktableEntry::Merge {
+                rev: 5,
+                key: 1001,
+                operand: 42,
+                written_at: 1_700_000_000_000,
+            },
+            DisktableEntry::Delete {
+                rev: 7,
+                key: u64::MAX,
+            },
+        ];
+        for compression in [Compression::None, Compression::Lz4] {
+            let mut builder = BlockBuilder::new();
+            for entry in &entries {
+                builder.add(entry);
+            }
+            assert_eq!(builder.last_key(), u64::MAX);
+            let stored = seal_block(builder.finish(), compression);
+            assert!(builder.is_empty());
+            let decoded = decode_block(&open_block(&stored).unwrap()).unwrap();
+            assert_eq!(decoded, entries);
+        }
+    }
+
+    #[test]
+    fn sequential_keys_compress() {
+        let mut builder = BlockBuilder::new();
+        for key in 0..1000 {
+            builder.add(&DisktableEntry::Insert {
+                rev: 1,
+                key,
+                value: 42,
+                expires_at: None,
+            });
+        }
+        let raw = builder.finish();
+        let stored = seal_block(raw.clone(), Compression::Lz4);
+        assert!(stored.len() < raw.len() / 2);
+        assert_eq!(open_block(&stored).unwrap(), raw);
+
+        let mut corrupted = stored.clone();
+        corrupted[0] ^= 1;
+        assert!(open_block(&corrupted).is_err());
+    }
+
+    #[test]
+    fn bloom_filter() {
+        let keys = (0..1000u64).map(|k| k * 7919).collect::<Vec<_>>();
+        let filter = BloomFilter::build(&keys, 10);
+        let filter = BloomFilter::decode(&filter.encode()).unwrap();
+        assert!(keys.iter().all(|&key| filter.may_contain(key)));
+        let false_positives = (0..10000u64)
+            .map(|k| k * 7919 + 1)
+            .filter(|&key| filter.may_contain(key))
+            .count();
+        assert!(false_positives < 500);
+        assert!(BloomFilter::build(&[], 10).may_contain(1));
+    }
+
+    #[test]
+    fn footer_roundtrip() {
+        let footer = Footer {
+            filter: BlockHandle { offset: 1, size: 2 },
+            index: BlockHandle {
+                offset: 3,
+                size: u64::MAX,
+            },
+            entries: 5,
+        };
+        assert_eq!(Footer::decode(&footer.encode()).unwrap(), footer);
+        let mut bytes = footer.encode();
+        bytes[FOOTER_SIZE - 1] ^= 1;
+        assert!(Footer::decode(&bytes).is_err());
+        // A flipped handle byte would otherwise make a huge read
+        let mut bytes = footer.encode();
+        bytes[15] ^= 0x40;
+        assert_eq!(
+            Footer::decode(&bytes).unwrap_err().to_string(),
+            "footer checksum mismatch"
+        );
+        assert!(footer.index.check(1 << 20).is_err());
+        assert!(footer.filter.check(3).is_ok());
+        assert!(footer.filter.check(2).is_err());
+    }
+}
@@ -0,0 +1,464 @@
+// Offline check of a tree's directory: the manifest, every block of its
+// disktables and the write-ahead log, and the rewrites of the repair mode
+
+use super::format::{self, BlockHandle, BloomFilter, Footer, FOOTER_SIZE};
+use super::{
+    DisktableEntry, DisktableRepository, LSMTree, LSMTreeOptions, Manifest, WalRecord,
+    DISKTABLE_REPOSITORY, MANIFEST_FILENAME, WAL_FILENAME,
+};
+use crate::fsck::FsckReport;
+use crate::record_log;
+use crate::storage::{self, OpenMode};
+use bincode::Options;
+use std::{cmp::Reverse, collections::HashMap, io};
+
+// What could be read of a disktable
+struct Checked {
+    path: String,
+    // In order, with the offset of their block
+    entries: Vec<(u64, DisktableEntry)>,
+    damaged: bool,
+}
+
+impl Checked {
+    fn max_rev(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|(_, entry)| entry.get_rev())
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+// Blocks must end before `end`
+fn read_block(bytes: &[u8], handle: BlockHandle, end: u64) -> io::Result<Vec<u8>> {
+    handle.check(end)?;
+    format::open_block(&bytes[handle.offset as usize..(handle.offset + handle.size) as usize])
+}
+
+fn check_disktable(options: &LSMTreeOptions, filename: &str, report: &mut FsckReport) -> Checked {
+    let path = format!("{}/{}", options.dir, filename);
+    let mut checked = Checked {
+        path: path.clone(),
+        entries: Vec::new(),
+        damaged: true,
+    };
+    let bytes = match storage::read_file(&*options.storage, &path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            report.problem(&path, None, format!("can't be read: {}", err));
+            return checked;
+        }
+    };
+    if bytes.len() < FOOTER_SIZE {
+        report.problem(
+            &path,
+            None,
+            format!("{} bytes is too short for a disktable", bytes.len()),
+        );
+        return checked;
+    }
+    let footer_offset = (bytes.len() - FOOTER_SIZE) as u64;
+    let footer = match Footer::decode(bytes[footer_offset as usize..].try_into().unwrap()) {
+        Ok(footer) => footer,
+        Err(err) => {
+            report.problem(&path, Some(footer_offset), format!("footer: {}", err));
+            return checked;
+        }
+    };
+    let index = match read_block(&bytes, footer.index, footer_offset)
+        .and_then(|raw| format::decode_index(&raw))
+    {
+        Ok(index) => index,
+        Err(err) => {
+            report.problem(
+                &path,
+                Some(footer.index.offset),
+                format!("index block: {}", err),
+            );
+            return checked;
+        }
+    };
+    let filter = match read_block(&bytes, footer.filter, footer_offset)
+        .and_then(|raw| BloomFilter::decode(&raw))
+    {
+        Ok(filter) => Some(filter),
+        Err(err) => {
+            report.problem(
+                &path,
+                Some(footer.filter.offset),
+                format!("filter block: {}", err),
+            );
+            None
+        }
+    };
+    checked.damaged = filter.is_none();
+
+    let mut last = None;
+    let mut read = 0;
+    let mut unreadable = 0;
+    for (i, index_entry) in index.iter().enumerate() {
+        let handle = index_entry.handle;
+        let entries = match read_block(&bytes, handle, footer_offset)
+            .and_then(|raw| format::decode_block(&raw))
+        {
+            Ok(entries) => entries,
+            Err(err) => {
+                report.problem(
+                    &path,
+                    Some(handle.offset),
+                    format!("data block {}: {}", i, err),
+                );
+                checked.damaged = true;
+                unreadable += 1;
+                continue;
+            }
+        };
+        read += entries.len();
+        for (n, entry) in entries.iter().enumerate() {
+            let (key, rev) = (entry.get_key(), entry.get_rev());
+            // Keys ascend, the versions of a key go from the newest down
+            if let Some((last_key, Reverse(last_rev))) = last {
+                if (key, Reverse(rev)) <= (last_key, Reverse(last_rev)) {
+                    let message = if (key, rev) == (last_key, last_rev) {
+                        format!(
+                            "entry {} of data block {}: rev {} of key {} is there twice",
+                            n, i, rev, key
+                        )
+                    } else {
+                        format!(
+                            "entry {} of data block {}: key {} rev {} is out of order after key {} rev {}",
+                            n, i, key, rev, last_key, last_rev
+                        )
+                    };
+                    report.problem(&path, Some(handle.offset), message);
+                    checked.damaged = true;
+                    continue;
+                }
+            }
+            if filter
+                .as_ref()
+                .is_some_and(|filter| !filter.may_contain(key))
+            {
+                report.problem(
+                    &path,
+                    Some(footer.filter.offset),
+                    format!("the filter misses key {}", key),
+                );
+                checked.damaged = true;
+            }
+            last = Some((key, Reverse(rev)));
+            checked.entries.push((handle.offset, entry.clone()));
+        }
+        match entries.last() {
+            None => {
+                report.problem(
+                    &path,
+                    Some(handle.offset),
+                    format!("data block {} is empty", i),
+                );
+                checked.damaged = true;
+            }
+            Some(entry) if entry.get_key() != index_entry.last_key => {
+                report.problem(
+                    &path,
+                    Some(handle.offset),
+                    format!(
+                        "data block {} ends with key {} but the index says {}",
+                        i,
+                        entry.get_key(),
+                        index_entry.last_key
+                    ),
+                );
+                checked.damaged = true;
+            }
+            Some(_) => {}
+        }
+    }
+    // Unreadable blocks are reported already
+    if unreadable == 0 && read as u64 != footer.entries {
+        report.problem(
+            &path,
+            Some(footer_offset),
+            format!(
+                "the footer counts {} entries but the blocks hold {}",
+                footer.entries, read
+            ),
+        );
+        checked.damaged = true;
+    }
+    checked
+}
+
+impl LSMTree {
+    // Checks the tree as opening it would find it: the manifest, the order of
+    // the entries of every disktable, that newer disktables hold newer
+    // revisions and that the records of the write-ahead log follow each
+    // other. The tree must not be open. The repair mode rewrites damaged
+    // disktables from the entries it can read and keeps the old files at
+    // `<filename>.corrupt`, rebuilds an unreadable manifest from the
+    // disktables in the directory and cuts the log before its first
+    // unreadable record. Revisions out of order are only reported.
+    pub fn fsck(options: &LSMTreeOptions, repair: bool) -> FsckReport {
+        let mut report = FsckReport::default();
+        let storage = &*options.storage;
+        let dir = &options.dir;
+        let manifest_path = format!("{}/{}", dir, MANIFEST_FILENAME);
+        let manifest = match Manifest::read(storage, dir) {
+            Ok(Some(manifest)) => Some(manifest),
+            Ok(None) => {
+                let mut orphans = storage.read_dir(dir).unwrap_or_default();
+                orphans.retain(|filename| DisktableRepository::is_disktable(filename));
+                if orphans.is_empty() {
+                    Some(Manifest::default())
+                } else {
+                    let message = format!("missing, but there are {} disktables", orphans.len());
+                    report.problem(&manifest_path, None, message);
+                    None
+                }
+            }
+            Err(err) => {
+                report.problem(&manifest_path, None, err.to_string());
+                None
+            }
+        };
+
+        // Without a manifest, the disktables are ordered by their revisions
+        let mut tables = match &manifest {
+            Some(manifest) => manifest
+                .disktables
+                .iter()
+                .map(|filename| check_disktable(options, filename, &mut report))
+                .collect::<Vec<_>>(),
+            None => {
+                let mut filenames = storage.read_dir(dir).unwrap_or_default();
+                filenames.retain(|filename| DisktableRepository::is_disktable(filename));
+                let mut tables = filenames
+                    .iter()
+                    .map(|filename| check_disktable(options, filename, &mut report))
+                    .collect::<Vec<_>>();
+                tables.sort_by_key(Checked::max_rev);
+                tables
+            }
+        };
+        report.entries = tables.iter().map(|table| table.entries.len()).sum();
+        let max_rev = tables.iter().map(Checked::max_rev).max().unwrap_or(0);
+
+        // The newest revision of every key in the disktables checked so far
+        let mut newest = HashMap::new();
+        for table in &tables {
+            if let Some(manifest) = &manifest {
+                if table.max_rev() > manifest.flushed_rev {
+                    report.problem(
+                        &table.path,
+                        None,
+                        format!(
+                            "revisions go up to {}, past the flushed rev {} of the manifest",
+                            table.max_rev(),
+                            manifest.flushed_rev
+                        ),
+                    );
+                }
+            }
+            let mut older = table.entries.iter().filter(|(_, entry)| {
+                newest
+                    .get(&entry.get_key())
+                    .is_some_and(|&(rev, _)| rev >= entry.get_rev())
+            });
+            if let Some((offset, entry)) = older.next() {
+                let (rev, path) = newest[&entry.get_key()];
+                report.problem(
+                    &table.path,
+                    Some(*offset),
+                    format!(
+                        "rev {} of key {} isn't newer than rev {} in the older disktable {}, {} more like it",
+                        entry.get_rev(),
+                        entry.get_key(),
+                        rev,
+                        path,
+                        older.count()
+                    ),
+                );
+            }
+            for (_, entry) in &table.entries {
+                let version = newest
+                    .entry(entry.get_key())
+                    .or_insert((entry.get_rev(), &table.path));
+                if version.0 < entry.get_rev() {
+                    *version = (entry.get_rev(), &table.path);
+                }
+            }
+        }
+
+        let wal_path = format!("{}/{}", dir, WAL_FILENAME);
+        let mut wal_cut = None;
+        if let Ok(file) = storage.open(&wal_path, OpenMode::Existing) {
+            let (records, _) = record_log::read_records(&*file).unwrap();
+            let mut next_rev = None;
+            for (offset, record) in records {
+                let Ok((rev, _, batch)) =
+                    bincode::DefaultOptions::new().deserialize::<WalRecord>(&record)
+                else {
+                    report.problem(&wal_path, Some(offset), "undecodable record".to_string());
+                    wal_cut = Some(offset);
+                    break;
+                };
+                if next_rev.is_some_and(|next_rev| next_rev != rev) {
+                    report.problem(
+                        &wal_path,
+                        Some(offset),
+                        format!(
+                            "record starts at rev {} instead of {}",
+                            rev,
+                            next_rev.unwrap()
+                        ),
+                    );
+                }
+                next_rev = Some(rev + batch.len() as u64);
+                report.entries += batch.len();
+            }
+        }
+
+        if repair && !report.is_ok() {
+            let flushed_rev = manifest.as_ref().map_or(0, |manifest| manifest.flushed_rev);
+            let mut changed = manifest.is_none();
+            let mut disktables = Vec::new();
+            let mut replaced = Vec::new();
+            for table in &mut tables {
+                let filename = table.path.rsplit('/').next().unwrap().to_string();
+                if !table.damaged {
+                    disktables.push(filename);
+                    continue;
+                }
+                changed = true;
+                replaced.push(table.path.clone());
+                if table.entries.is_empty() {
+                    report.repairs.push(format!(
+                        "dropped {}, none of its entries could be read",
+                        table.path
+                    ));
+                    continue;
+                }
+                let entries = std::mem::take(&mut table.entries);
+                let count = entries.len();
+                let rewritten = DISKTABLE_REPOSITORY
+                    .lock()
+                    .unwrap()
+                    .write_entries(entries.into_iter().map(|(_, entry)| entry), options);
+                report.repairs.push(format!(
+                    "rewrote {} as {} with the {} entries that could be read",
+                    table.path,
+                    rewritten.filename(),
+                    count
+                ));
+                disktables.push(rewritten.filename());
+            }
+            if max_rev > flushed_rev && manifest.is_some() {
+                changed = true;
+                report.repairs.push(format!(
+                    "raised the flushed rev of the manifest from {} to {}",
+                    flushed_rev, max_rev
+                ));
+            }
+            if manifest.is_none() {
+                report.repairs.push(format!(
+                    "rebuilt the manifest from {} disktables",
+                    disktables.len()
+                ));
+            }
+            if changed {
+                let manifest = Manifest {
+                    flushed_rev: flushed_rev.max(max_rev),
+                    disktables,
+                };
+                manifest.write(storage, dir);
+                for path in replaced {
+                    if storage.exists(&path) {
+                        storage.rename(&path, &format!("{}.corrupt", path)).unwrap();
+                    }
+                }
+            }
+            if let Some(offset) = wal_cut {
+                let file = storage.open(&wal_path, OpenMode::Existing).unwrap();
+                file.set_len(offset).unwrap();
+                file.sync().unwrap();
+                report.repairs.push(format!(
+                    "cut {} at {}, the unreadable record and everything after it are gone",
+                    wal_path, offset
+                ));
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash_table::HashTable;
+    use crate::lsmt::DisktableOptions;
+    use crate::storage::{MemStorage, Storage};
+    use std::sync::Arc;
+
+    #[test]
+    fn check_and_repair() {
+        let storage = Arc::new(MemStorage::new());
+        let options = LSMTreeOptions {
+            dir: "fsck".to_string(),
+            memtable_capacity: 100,
+            disktable: DisktableOptions {
+                block_size: 256,
+                ..Default::default()
+            },
+            storage: storage.clone(),
+            ..Default::default()
+        };
+        {
+            let mut tree = LSMTree::new(&options);
+            for key in 0..1050 {
+                tree.set(key, key + 1);
+            }
+        }
+        let report = LSMTree::fsck(&options, false);
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.entries, 1050);
+
+        // A byte of the first data block of the newest disktable flips
+        let manifest_path = format!("fsck/{}", MANIFEST_FILENAME);
+        let manifest: Manifest = bincode::DefaultOptions::new()
+            .deserialize(&storage::read_file(&*storage, &manifest_path).unwrap())
+            .unwrap();
+        let newest = format!("fsck/{}", manifest.disktables.last().unwrap());
+        let file = storage.open(&newest, OpenMode::Existing).unwrap();
+        let mut byte = [0];
+        file.read_exact_at(&mut byte, 10).unwrap();
+        file.write_all_at(&[byte[0] ^ 1], 10).unwrap();
+        drop(file);
+
+        let report = LSMTree::fsck(&options, true);
+        assert_eq!(report.problems.len(), 1, "{}", report);
+        assert_eq!(
+            report.problems[0].to_string(),
+            format!("{} at 0: data block 0: block checksum mismatch", newest)
+        );
+        assert_eq!(report.repairs.len(), 1);
+        assert!(storage.exists(&format!("{}.corrupt", newest)));
+        assert!(LSMTree::fsck(&options, false).is_ok());
+        {
+            let tree = LSMTree::new(&options);
+            let lost = (0..1050)
+                .filter(|&key| tree.get(key) != Some(key + 1))
+                .count();
+            assert!(lost > 0 && lost < 200, "{}", lost);
+        }
+
+        // An unreadable manifest is rebuilt from the disktables
+        storage::write_atomically(&*storage, &manifest_path, b"garbage").unwrap();
+        let before = LSMTree::fsck(&options, true);
+        assert!(before.problems[0].message.starts_with("undecodable"));
+        assert!(before.repairs[0].starts_with("rebuilt the manifest from"));
+        assert!(LSMTree::fsck(&options, false).is_ok());
+        let tree = LSMTree::new(&options);
+        assert_eq!(tree.get(1049), Some(1050));
+    }
+}
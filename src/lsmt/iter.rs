@@ -0,0 +1,411 @@
+use super::{DisktableEntry, LSMTreeOptions};
+use crate::merge::MergeOperator;
+use std::{cmp::Reverse, collections::BTreeSet, iter::Peekable};
+
+pub(super) type EntryIter<'a> = Box<dyn Iterator<Item = DisktableEntry> + 'a>;
+
+// Merges sources that are sorted by key and then by descending revision into
+// a single stream with the same order
+pub(super) struct MergingIter<'a> {
+    sources: Vec<Peekable<EntryIter<'a>>>,
+}
+
+impl<'a> MergingIter<'a> {
+    pub(super) fn new(sources: Vec<EntryIter<'a>>) -> Self {
+        MergingIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MergingIter<'a> {
+    type Item = DisktableEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (pos, _) = self
+            .sources
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(pos, source)| {
+                source
+                    .peek()
+                    .map(|entry| (pos, (entry.get_key(), Reverse(entry.get_rev()))))
+            })
+            .min_by_key(|(_, order)| *order)?;
+        self.sources[pos].next()
+    }
+}
+
+// Turns the versions of a key into its value as of `now`
+#[derive(Clone, Debug)]
+pub(super) struct Resolver {
+    merge_operator: MergeOperator,
+    now: u64,
+}
+
+impl Resolver {
+    pub(super) fn new(options: &LSMTreeOptions) -> Self {
+        Resolver {
+            merge_operator: options.merge_operator.clone(),
+            now: options.clock.now(),
+        }
+    }
+
+    fn is_expired(&self, expires_at: Option<u64>) -> bool {
+        expires_at.is_some_and(|expires_at| expires_at <= self.now)
+    }
+
+    // Applies an operand to the (value, expiry) below it. The result expires
+    // together with that value, unless it had expired when the operand was
+    // written, then the operand starts from nothing.
+    fn merge(
+        &self,
+        below: Option<(u64, Option<u64>)>,
+        operand: u64,
+        written_at: u64,
+    ) -> (u64, Option<u64>) {
+        let below = below.filter(|(_, expires_at)| !expires_at.is_some_and(|e| e <= written_at));
+        let value = self
+            .merge_operator
+            .full_merge(below.map(|(value, _)| value), operand);
+        (value, below.and_then(|(_, expires_at)| expires_at))
+    }
+
+    pub(super) fn resolve<I>(&self, versions: I) -> Option<u64>
+    where
+        I: Iterator<Item = DisktableEntry>,
+    {
+        self.resolve_with_expiry(versions).map(|(value, _)| value)
+    }
+
+    // `versions` are newest first, the merge operands are applied to the
+    // newest insert or delete below them, oldest operand first
+    pub(super) fn resolve_with_expiry<I>(&self, versions: I) -> Option<(u64, Option<u64>)>
+    where
+        I: Iterator<Item = DisktableEntry>,
+    {
+        let mut operands = Vec::new();
+        let mut value = None;
+        for entry in versions {
+            match entry {
+                DisktableEntry::Insert {
+                    value: base,
+                    expires_at,
+                    ..
+                } => {
+                    value = Some((base, expires_at));
+                    break;
+                }
+                DisktableEntry::Delete { .. } => break,
+                DisktableEntry::Merge {
+                    operand,
+                    written_at,
+                    ..
+                } => operands.push((operand, written_at)),
+            }
+        }
+        for (operand, written_at) in operands.into_iter().rev() {
+            value = Some(self.merge(value, operand, written_at));
+        }
+        value.filter(|(_, expires_at)| !self.is_expired(*expires_at))
+    }
+}
+
+// Live (key, value, expiry) entries as of revision `rev`: for every key only
+// the newest version not newer than `rev` counts, and deleted keys are
+// skipped
+pub(super) struct VisibleIter<'a> {
+    iter: Peekable<MergingIter<'a>>,
+    rev: u64,
+    resolver: Resolver,
+    resolved: Option<u64>,
+}
+
+impl<'a> VisibleIter<'a> {
+    pub(super) fn new(iter: MergingIter<'a>, rev: u64, resolver: Resolver) -> Self {
+        VisibleIter {
+            iter: iter.peekable(),
+            rev,
+            resolver,
+            resolved: None,
+        }
+    }
+}
+
+impl<'a> Iterator for VisibleIter<'a> {
+    type Item = (u64, u64, Option<u64>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.iter.next()?;
+            if entry.get_rev() > self.rev || self.resolved == Some(entry.get_key()) {
+                continue;
+            }
+            let key = entry.get_key();
+            self.resolved = Some(key);
+            // The older versions of the key come right after it
+            let older = std::iter::from_fn(|| self.iter.next_if(|entry| entry.get_key() == key));
+            let versions = std::iter::once(entry).chain(older);
+            if let Some((value, expires_at)) = self.resolver.resolve_with_expiry(versions) {
+                return Some((key, value, expires_at));
+            }
+        }
+    }
+}
+
+// `versions` are all versions of one key, newest first. A version survives if
+// it is the newest one or some snapshot sees it, i.e. the snapshot revision is
+// in [its rev, rev of the next newer version). Tombstones with nothing older
+// left are only needed when there may be older data below.
+//
+// Merge operands with a known value below them become inserts of the merged
+// value. The others are kept, since the value they apply to may be in an
+// older disktable, but runs of them written at the same time that no snapshot
+// tells apart are combined into one. Operands written at different times
+// can't be, the value below may expire between them. Expired inserts read
+// like tombstones, so they become ones.
+pub(super) fn retain_versions(
+    versions: &mut Vec<DisktableEntry>,
+    snapshots: &BTreeSet<u64>,
+    bottom: bool,
+    resolver: &Resolver,
+) {
+    let mut below: Option<Option<(u64, Option<u64>)>> = bottom.then_some(None);
+    for entry in versions.iter_mut().rev() {
+        match *entry {
+            DisktableEntry::Insert {
+                value, expires_at, ..
+            } => below = Some(Some((value, expires_at))),
+            DisktableEntry::Delete { .. } => below = Some(None),
+            DisktableEntry::Merge {
+                rev,
+                key,
+                operand,
+                written_at,
+            } => {
+                if let Some(value) = below {
+                    let (value, expires_at) = resolver.merge(value, operand, written_at);
+                    *entry = DisktableEntry::Insert {
+                        rev,
+                        key,
+                        value,
+                        expires_at,
+                    };
+                    below = Some(Some((value, expires_at)));
+                }
+            }
+        }
+        if let DisktableEntry::Insert {
+            rev,
+            key,
+            expires_at,
+            ..
+        } = *entry
+        {
+            if resolver.is_expired(expires_at) {
+                *entry = DisktableEntry::Delete { rev, key };
+            }
+        }
+    }
+
+    let mut newer: Option<u64> = None;
+    versions.retain(|entry| {
+        let rev = entry.get_rev();
+        let keep = match newer {
+            None => true,
+            Some(_) if matches!(entry, DisktableEntry::Merge { .. }) => true,
+            Some(newer) => snapshots.range(rev..newer).next().is_some(),
+        };
+        newer = Some(rev);
+        keep
+    });
+    if bottom {
+        while let Some(DisktableEntry::Delete { .. }) = versions.last() {
+            versions.pop();
+        }
+    }
+
+    let mut combined: Vec<DisktableEntry> = Vec::with_capacity(versions.len());
+    for entry in versions.drain(..) {
+        if let (
+            Some(DisktableEntry::Merge {
+                rev: newer_rev,
+                operand: newer,
+                written_at: newer_written_at,
+                ..
+            }),
+            DisktableEntry::Merge {
+                rev,
+                operand,
+                written_at,
+                ..
+            },
+        ) = (combined.last_mut(), &entry)
+        {
+            if *newer_written_at == *written_at && snapshots.range(rev..newer_rev).next().is_none()
+            {
+                *newer = resolver.merge_operator.partial_merge(*operand, *newer);
+                continue;
+            }
+        }
+        combined.push(entry);
+    }
+    *versions = combined;
+}
+
+pub(super) fn compact_versions<'a, I>(
+    iter: I,
+    snapshots: BTreeSet<u64>,
+    bottom: bool,
+    resolver: Resolver,
+) -> impl Iterator<Item = DisktableEntry> + 'a
+where
+    I: Iterator<Item = DisktableEntry> + 'a,
+{
+    let mut iter = iter.peekable();
+    std::iter::from_fn(move || {
+        let first = iter.next()?;
+        let key = first.get_key();
+        let mut versions = vec![first];
+        while let Some(entry) = iter.next_if(|entry| entry.get_key() == key) {
+            versions.push(entry);
+        }
+        retain_versions(&mut versions, &snapshots, bottom, &resolver);
+        Some(versions)
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(key: u64, rev: u64) -> DisktableEntry {
+        DisktableEntry::Insert {
+            rev,
+            key,
+            value: rev * 10,
+            expires_at: None,
+        }
+    }
+
+    fn resolver(now: u64) -> Resolver {
+        Resolver {
+            merge_operator: MergeOperator::saturating_add(),
+            now,
+        }
+    }
+
+    #[test]
+    fn merge_and_resolve() {
+        let older = vec![insert(1, 1), insert(2, 2), insert(4, 3)];
+        let newer = vec![
+            DisktableEntry::Delete { rev: 5, key: 1 },
+            insert(2, 6),
+            insert(3, 4),
+        ];
+        let merged = || {
+            MergingIter::new(vec![
+                Box::new(older.clone().into_iter()),
+                Box::new(newer.clone().into_iter()),
+            ])
+        };
+        assert_eq!(
+            merged()
+                .map(|e| (e.get_key(), e.get_rev()))
+                .collect::<Vec<_>>(),
+            vec![(1, 5), (1, 1), (2, 6), (2, 2), (3, 4), (4, 3)]
+        );
+        assert_eq!(
+            VisibleIter::new(merged(), u64::MAX, resolver(0)).collect::<Vec<_>>(),
+            vec![(2, 60, None), (3, 40, None), (4, 30, None)]
+        );
+        assert_eq!(
+            VisibleIter::new(merged(), 3, resolver(0)).collect::<Vec<_>>(),
+            vec![(1, 10, None), (2, 20, None), (4, 30, None)]
+        );
+    }
+
+    #[test]
+    fn version_retention() {
+        let versions = vec![
+            insert(1, 9),
+            DisktableEntry::Delete { rev: 7, key: 1 },
+            insert(1, 5),
+            insert(1, 3),
+            DisktableEntry::Delete { rev: 1, key: 1 },
+        ];
+        let revs = |snapshots: &[u64], bottom| {
+            let mut versions = versions.clone();
+            retain_versions(
+                &mut versions,
+                &snapshots.iter().copied().collect(),
+                bottom,
+                &resolver(0),
+            );
+            versions.iter().map(|e| e.get_rev()).collect::<Vec<_>>()
+        };
+        assert_eq!(revs(&[], false), vec![9]);
+        assert_eq!(revs(&[8], false), vec![9, 7]);
+        assert_eq!(revs(&[4, 6], false), vec![9, 5, 3]);
+        assert_eq!(revs(&[2], false), vec![9, 1]);
+        assert_eq!(revs(&[2], true), vec![9]);
+        assert_eq!(revs(&[0], false), vec![9]);
+    }
+
+    #[test]
+    fn merge_operands_and_expiry() {
+        let merge = |rev, operand, written_at| DisktableEntry::Merge {
+            rev,
+            key: 1,
+            operand,
+            written_at,
+        };
+        let expiring = |rev, value, expires_at| DisktableEntry::Insert {
+            rev,
+            key: 1,
+            value,
+            expires_at,
+        };
+        let versions = vec![merge(9, 1, 0), merge(7, 10, 0), merge(5, 100, 0)];
+        assert_eq!(resolver(0).resolve(versions.clone().into_iter()), Some(111));
+
+        // Without the value below them the operands are combined into one,
+        // unless a snapshot sees one of them or they're of different times
+        let mut retained = versions.clone();
+        retain_versions(&mut retained, &BTreeSet::new(), false, &resolver(0));
+        assert_eq!(retained, vec![merge(9, 111, 0)]);
+        let mut retained = versions.clone();
+        retain_versions(&mut retained, &[7].into(), false, &resolver(0));
+        assert_eq!(retained, vec![merge(9, 1, 0), merge(7, 110, 0)]);
+        let mut retained = vec![merge(9, 1, 5), merge(7, 10, 0), merge(5, 100, 0)];
+        retain_versions(&mut retained, &BTreeSet::new(), false, &resolver(0));
+        assert_eq!(retained, vec![merge(9, 1, 5), merge(7, 110, 0)]);
+        let mut bottom = versions.clone();
+        retain_versions(&mut bottom, &BTreeSet::new(), true, &resolver(0));
+        assert_eq!(bottom, vec![expiring(9, 111, None)]);
+
+        // Operands merged into a value expire with it, the ones written after
+        // it expired start afresh
+        let mut versions = vec![
+            merge(9, 1, 18),
+            merge(7, 10, 15),
+            expiring(3, 1000, Some(20)),
+        ];
+        assert_eq!(
+            resolver(19).resolve(versions.clone().into_iter()),
+            Some(1011)
+        );
+        assert_eq!(resolver(20).resolve(versions.clone().into_iter()), None);
+        versions.insert(0, merge(10, 5, 60));
+        assert_eq!(resolver(70).resolve(versions.clone().into_iter()), Some(5));
+        retain_versions(&mut versions, &[8].into(), false, &resolver(70));
+        assert_eq!(
+            versions,
+            vec![
+                expiring(10, 5, None),
+                DisktableEntry::Delete { rev: 7, key: 1 }
+            ]
+        );
+    }
+}
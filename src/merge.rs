@@ -0,0 +1,62 @@
+use std::{fmt, sync::Arc};
+
+// Associative function that folds a merge operand into the current value. As
+// it is associative, runs of operands can be combined before the value they
+// apply to is known.
+#[derive(Clone)]
+pub struct MergeOperator(Arc<dyn Fn(u64, u64) -> u64 + Send + Sync>);
+
+impl MergeOperator {
+    pub fn new<F: Fn(u64, u64) -> u64 + Send + Sync + 'static>(merge: F) -> Self {
+        MergeOperator(Arc::new(merge))
+    }
+
+    pub fn saturating_add() -> Self {
+        Self::new(u64::saturating_add)
+    }
+
+    // A missing value takes the operand as it is
+    pub fn full_merge(&self, value: Option<u64>, operand: u64) -> u64 {
+        match value {
+            Some(value) => (self.0)(value, operand),
+            None => operand,
+        }
+    }
+
+    // One operand with the effect of `older` followed by `newer`, compactions
+    // combine runs of operands with it
+    pub fn partial_merge(&self, older: u64, newer: u64) -> u64 {
+        (self.0)(older, newer)
+    }
+}
+
+impl Default for MergeOperator {
+    fn default() -> Self {
+        Self::saturating_add()
+    }
+}
+
+impl fmt::Debug for MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MergeOperator")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn associativity() {
+        let add = MergeOperator::saturating_add();
+        assert_eq!(add.full_merge(None, 5), 5);
+        assert_eq!(add.full_merge(Some(u64::MAX - 1), 5), u64::MAX);
+        let max = MergeOperator::new(u64::max);
+        let operands = [3, 9, 4];
+        let one_by_one = operands
+            .iter()
+            .fold(7, |value, &operand| max.full_merge(Some(value), operand));
+        let combined = max.partial_merge(max.partial_merge(3, 9), 4);
+        assert_eq!(one_by_one, max.full_merge(Some(7), combined));
+    }
+}
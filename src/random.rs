@@ -0,0 +1,52 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{fmt, sync::Mutex};
+
+// Source of the randomness of the tables, like the names of new files. A
+// seeded one makes a run reproducible.
+pub trait Random: fmt::Debug + Send + Sync {
+    fn next_u64(&self) -> u64;
+
+    // A generator seeded from this one, for code that wants an Rng
+    fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.next_u64())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ThreadRandom;
+
+impl Random for ThreadRandom {
+    fn next_u64(&self) -> u64 {
+        rand::thread_rng().gen()
+    }
+}
+
+#[derive(Debug)]
+pub struct SeededRandom {
+    rng: Mutex<StdRng>,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom {
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+}
+
+impl Random for SeededRandom {
+    fn next_u64(&self) -> u64 {
+        self.rng.lock().unwrap().gen()
+    }
+}
+
+// Seed of a randomized test: HASTY_SEED if it is set, a random one otherwise.
+// It is printed, so the output of a failed test tells how to replay it.
+pub fn test_seed() -> u64 {
+    let seed = match std::env::var("HASTY_SEED") {
+        Ok(seed) => seed.parse().expect("HASTY_SEED must be a u64"),
+        Err(_) => rand::thread_rng().gen(),
+    };
+    println!("HASTY_SEED={}", seed);
+    seed
+}
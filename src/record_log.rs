@@ -0,0 +1,143 @@
+// Append-only log of checksummed records: [len: u32][crc32: u32][payload].
+// A record torn by a crash fails its length or checksum check, so it and
+// everything after it is discarded on open.
+
+use crate::storage::{self, OpenMode, Storage, StorageFile};
+use std::{io, mem::size_of};
+
+const HEADER_SIZE: usize = 2 * size_of::<u32>();
+
+// A record payload with the offset it can be read back at
+pub type OffsetRecord = (u64, Vec<u8>);
+
+pub struct RecordLog {
+    file: Box<dyn StorageFile>,
+    end: u64,
+}
+
+impl RecordLog {
+    pub fn open(storage: &dyn Storage, path: &str) -> io::Result<(Self, Vec<Vec<u8>>)> {
+        let (log, records) = Self::open_with_offsets(storage, path)?;
+        Ok((log, records.into_iter().map(|(_, record)| record).collect()))
+    }
+
+    pub fn open_with_offsets(
+        storage: &dyn Storage,
+        path: &str,
+    ) -> io::Result<(Self, Vec<OffsetRecord>)> {
+        let created = !storage.exists(path);
+        let file = storage.open(path, OpenMode::Create)?;
+        if created {
+            storage.sync_dir(storage::parent_dir(path))?;
+        }
+        let (records, end) = read_records(&*file)?;
+        if end < file.len()? {
+            file.set_len(end)?;
+        }
+        Ok((RecordLog { file, end }, records))
+    }
+
+    // Returns the offset of the record
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<u64> {
+        let mut record = Vec::with_capacity(HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        record.extend_from_slice(payload);
+        self.file.write_all_at(&record, self.end)?;
+        let offset = self.end;
+        self.end += record.len() as u64;
+        Ok(offset)
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.end = 0;
+        self.file.sync()
+    }
+
+    pub fn size(&self) -> usize {
+        self.end as usize
+    }
+}
+
+// The intact records of a log without touching it, and where they end
+pub fn read_records(file: &dyn StorageFile) -> io::Result<(Vec<OffsetRecord>, u64)> {
+    let file_size = file.len()?;
+    let mut records = Vec::new();
+    let mut pos = 0u64;
+    while pos + HEADER_SIZE as u64 <= file_size {
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact_at(&mut header, pos)?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+        let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if pos + HEADER_SIZE as u64 + len > file_size {
+            break;
+        }
+        let mut payload = vec![0; len as usize];
+        file.read_exact_at(&mut payload, pos + HEADER_SIZE as u64)?;
+        if crc32fast::hash(&payload) != crc {
+            break;
+        }
+        records.push((pos, payload));
+        pos += HEADER_SIZE as u64 + len;
+    }
+    Ok((records, pos))
+}
+
+// Reads the record at `offset` of a log file opened by anyone
+pub fn read_record(file: &dyn StorageFile, offset: u64) -> io::Result<Vec<u8>> {
+    let mut header = [0; HEADER_SIZE];
+    file.read_exact_at(&mut header, offset)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut payload = vec![0; len];
+    file.read_exact_at(&mut payload, offset + HEADER_SIZE as u64)?;
+    if crc32fast::hash(&payload) != crc {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record checksum mismatch",
+        ));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FsStorage;
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn torn_tail() {
+        let path = "record_log_torn_tail.log";
+        let _ = fs::remove_file(path);
+        {
+            let (mut log, records) = RecordLog::open(&FsStorage, path).unwrap();
+            assert!(records.is_empty());
+            log.append(b"first").unwrap();
+            log.append(b"").unwrap();
+            log.append(b"third record").unwrap();
+            log.sync().unwrap();
+        }
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+
+        let (mut log, records) = RecordLog::open(&FsStorage, path).unwrap();
+        assert_eq!(records, vec![b"first".to_vec(), Vec::new()]);
+        let offset = log.append(b"fourth").unwrap();
+        assert_eq!(read_record(&*log.file, offset).unwrap(), b"fourth");
+        let (mut log, records) = RecordLog::open_with_offsets(&FsStorage, path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2], (offset, b"fourth".to_vec()));
+
+        log.clear().unwrap();
+        assert_eq!(log.size(), 0);
+        let (_, records) = RecordLog::open(&FsStorage, path).unwrap();
+        assert!(records.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+// Everything the tables keep on disk goes through a Storage, so they can run
+// on the real filesystem, in memory, or on a storage that fails on purpose.
+// Paths are '/' separated and relative to wherever the storage is rooted.
+
+mod faulty;
+mod memory;
+mod recording;
+
+pub use faulty::FaultyStorage;
+pub use memory::MemStorage;
+pub use recording::{RecordingStorage, TraceOp};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::prelude::FileExt,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenMode {
+    Existing,
+    Create,
+    // Fails with AlreadyExists if there is a file at the path
+    CreateNew,
+}
+
+pub trait StorageFile: Send + Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    // Writing past the end extends the file
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+    fn len(&self) -> io::Result<u64>;
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    fn sync(&self) -> io::Result<()>;
+}
+
+pub trait Storage: fmt::Debug + Send + Sync {
+    // Files are always open for both reading and writing
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>>;
+    fn exists(&self, path: &str) -> bool;
+    fn remove(&self, path: &str) -> io::Result<()>;
+    // Replaces the file at `to` if there is one
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    // Names of the files in `dir`
+    fn read_dir(&self, dir: &str) -> io::Result<Vec<String>>;
+    fn create_dir_all(&self, dir: &str) -> io::Result<()>;
+    // Makes creations, renames and removals in `dir` durable
+    fn sync_dir(&self, dir: &str) -> io::Result<()>;
+}
+
+pub fn read_file(storage: &dyn Storage, path: &str) -> io::Result<Vec<u8>> {
+    let file = storage.open(path, OpenMode::Existing)?;
+    let mut bytes = vec![0; file.len()? as usize];
+    file.read_exact_at(&mut bytes, 0)?;
+    Ok(bytes)
+}
+
+// Directory to sync to make a creation, rename or removal of `path` durable
+pub fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or(".", |(dir, _)| dir)
+}
+
+// Writes a temporary file and renames it over `path`, so a crash leaves
+// either the old or the new contents
+pub fn write_atomically(storage: &dyn Storage, path: &str, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let file = storage.open(&tmp_path, OpenMode::Create)?;
+    file.set_len(0)?;
+    file.write_all_at(bytes, 0)?;
+    file.sync()?;
+    storage.rename(&tmp_path, path)?;
+    storage.sync_dir(parent_dir(path))
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsStorage;
+
+impl StorageFile for fs::File {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        FileExt::write_all_at(self, buf, offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl Storage for FsStorage {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(mode == OpenMode::Create)
+            .create_new(mode == OpenMode::CreateNew)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        std::path::Path::new(path).exists()
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn read_dir(&self, dir: &str) -> io::Result<Vec<String>> {
+        fs::read_dir(dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &str) -> io::Result<()> {
+        fs::File::open(dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) fn exercise(storage: &dyn Storage, dir: &str) {
+        storage.create_dir_all(dir).unwrap();
+        let path = format!("{}/file", dir);
+        assert!(!storage.exists(&path));
+        assert_eq!(
+            storage
+                .open(&path, OpenMode::Existing)
+                .err()
+                .map(|err| err.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+        let file = storage.open(&path, OpenMode::Create).unwrap();
+        file.write_all_at(b"hello", 0).unwrap();
+        file.write_all_at(b"world", 10).unwrap();
+        assert_eq!(file.len().unwrap(), 15);
+        let mut buf = [1; 15];
+        file.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello\0\0\0\0\0world");
+        assert!(file.read_exact_at(&mut buf, 1).is_err());
+        file.set_len(5).unwrap();
+        file.sync().unwrap();
+        assert_eq!(
+            storage
+                .open(&path, OpenMode::CreateNew)
+                .err()
+                .map(|err| err.kind()),
+            Some(io::ErrorKind::AlreadyExists)
+        );
+
+        write_atomically(storage, &format!("{}/other", dir), b"bytes").unwrap();
+        storage
+            .rename(&format!("{}/other", dir), &format!("{}/renamed", dir))
+            .unwrap();
+        let mut names = storage.read_dir(dir).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["file", "renamed"]);
+        assert_eq!(
+            read_file(storage, &format!("{}/renamed", dir)).unwrap(),
+            b"bytes"
+        );
+        assert_eq!(read_file(storage, &path).unwrap(), b"hello");
+        storage.remove(&path).unwrap();
+        assert!(!storage.exists(&path));
+        storage.remove(&format!("{}/renamed", dir)).unwrap();
+    }
+
+    #[test]
+    fn backends() {
+        let dir = "storage_backends";
+        let _ = fs::remove_dir_all(dir);
+        exercise(&FsStorage, dir);
+        fs::remove_dir_all(dir).unwrap();
+        exercise(&MemStorage::new(), dir);
+        exercise(&FaultyStorage::new(), dir);
+    }
+}
@@ -0,0 +1,253 @@
+use super::memory::{files_in, not_found, read_exact_at, write_all_at};
+use super::{parent_dir, OpenMode, Storage, StorageFile};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+struct FileState {
+    data: Vec<u8>,
+    // What survives a crash
+    synced: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Faults {
+    writes: usize,
+    writes_left: Option<usize>,
+    short_writes: bool,
+    failing: bool,
+}
+
+impl Faults {
+    fn check(&self) -> io::Result<()> {
+        if self.failing {
+            return Err(io::Error::other("injected failure"));
+        }
+        Ok(())
+    }
+}
+
+// In-memory storage that forgets everything that wasn't synced when it
+// crashes and can be told to fail after a number of writes, optionally
+// writing only part of the failed write. Creations, renames and removals are
+// forgotten too unless their directory was synced.
+#[derive(Default)]
+pub struct FaultyStorage {
+    files: Mutex<HashMap<String, Arc<Mutex<FileState>>>>,
+    // What the directories held when they were last synced
+    synced_files: Mutex<HashMap<String, Arc<Mutex<FileState>>>>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultyStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The next `writes` writes succeed, everything after them fails until
+    // the storage heals or crashes
+    pub fn fail_after(&self, writes: usize) {
+        self.faults.lock().unwrap().writes_left = Some(writes);
+    }
+
+    pub fn set_short_writes(&self, short_writes: bool) {
+        self.faults.lock().unwrap().short_writes = short_writes;
+    }
+
+    pub fn heal(&self) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.writes_left = None;
+        faults.failing = false;
+    }
+
+    pub fn is_failing(&self) -> bool {
+        self.faults.lock().unwrap().failing
+    }
+
+    // Number of successful writes so far
+    pub fn writes(&self) -> usize {
+        self.faults.lock().unwrap().writes
+    }
+
+    // Every directory and file goes back to what it held when it was last
+    // synced and the storage heals, like a machine that restarts
+    pub fn crash(&self) {
+        let mut files = self.files.lock().unwrap();
+        *files = self.synced_files.lock().unwrap().clone();
+        for file in files.values() {
+            let mut file = file.lock().unwrap();
+            file.data = file.synced.clone();
+        }
+        self.heal();
+    }
+}
+
+impl fmt::Debug for FaultyStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultyStorage")
+            .field("files", &self.files.lock().unwrap().len())
+            .field("writes", &self.writes())
+            .field("failing", &self.is_failing())
+            .finish()
+    }
+}
+
+struct FaultyFile {
+    state: Arc<Mutex<FileState>>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl StorageFile for FaultyFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(&self.state.lock().unwrap().data, buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        faults.check()?;
+        let mut state = self.state.lock().unwrap();
+        if faults.writes_left == Some(0) {
+            faults.failing = true;
+            if faults.short_writes {
+                write_all_at(&mut state.data, &buf[..buf.len() / 2], offset);
+            }
+            return faults.check();
+        }
+        if let Some(writes_left) = &mut faults.writes_left {
+            *writes_left -= 1;
+        }
+        faults.writes += 1;
+        write_all_at(&mut state.data, buf, offset);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.state.lock().unwrap().data.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        self.state.lock().unwrap().data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        let mut state = self.state.lock().unwrap();
+        state.synced = state.data.clone();
+        Ok(())
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut files = self.files.lock().unwrap();
+        let state = match (files.get(path), mode) {
+            (Some(_), OpenMode::CreateNew) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path),
+                ))
+            }
+            (Some(state), _) => state.clone(),
+            (None, OpenMode::Existing) => return Err(not_found(path)),
+            (None, _) => {
+                self.faults.lock().unwrap().check()?;
+                files.entry(path.to_string()).or_default().clone()
+            }
+        };
+        Ok(Box::new(FaultyFile {
+            state,
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        let mut files = self.files.lock().unwrap();
+        let state = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), state);
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(files_in(dir, self.files.lock().unwrap().keys()))
+    }
+
+    fn create_dir_all(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &str) -> io::Result<()> {
+        self.faults.lock().unwrap().check()?;
+        let files = self.files.lock().unwrap();
+        let mut synced_files = self.synced_files.lock().unwrap();
+        synced_files.retain(|path, _| parent_dir(path) != dir);
+        for (path, state) in files.iter() {
+            if parent_dir(path) == dir {
+                synced_files.insert(path.clone(), state.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_log::RecordLog;
+
+    #[test]
+    fn crashes_and_failures() {
+        let storage = FaultyStorage::new();
+        let (mut log, _) = RecordLog::open(&storage, "log").unwrap();
+        log.append(b"first").unwrap();
+        log.sync().unwrap();
+        log.append(b"second").unwrap();
+        storage.crash();
+        let (mut log, records) = RecordLog::open(&storage, "log").unwrap();
+        assert_eq!(records, vec![b"first".to_vec()]);
+
+        // A short write leaves a torn record behind, which is cut off
+        storage.fail_after(1);
+        storage.set_short_writes(true);
+        log.append(b"second").unwrap();
+        assert!(log.append(b"third").is_err());
+        assert!(log.sync().is_err());
+        assert!(storage.is_failing());
+        storage.heal();
+        log.sync().unwrap();
+        let (_, records) = RecordLog::open(&storage, "log").unwrap();
+        assert_eq!(records, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(storage.writes(), 3);
+
+        // A file is only there after a crash if its directory was synced
+        let file = storage.open("dir/file", OpenMode::Create).unwrap();
+        file.sync().unwrap();
+        storage.rename("log", "dir/log").unwrap();
+        storage.crash();
+        assert!(!storage.exists("dir/file"));
+        assert!(storage.exists("log") && !storage.exists("dir/log"));
+        storage.open("dir/file", OpenMode::Create).unwrap();
+        storage.sync_dir("dir").unwrap();
+        storage.crash();
+        assert!(storage.exists("dir/file"));
+    }
+}
@@ -0,0 +1,172 @@
+use super::{OpenMode, Storage, StorageFile};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{Arc, Mutex, RwLock},
+};
+
+type Contents = Arc<RwLock<Vec<u8>>>;
+
+// Files are byte vectors keyed by their path. Directories are implied by the
+// paths, creating them does nothing.
+#[derive(Default)]
+pub struct MemStorage {
+    files: Mutex<HashMap<String, Contents>>,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl fmt::Debug for MemStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemStorage")
+            .field("files", &self.files.lock().unwrap().len())
+            .finish()
+    }
+}
+
+pub(super) fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no file at {}", path))
+}
+
+pub(super) fn read_exact_at(data: &[u8], buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let start = offset as usize;
+    let Some(bytes) = data.get(start..start + buf.len()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "read past the end of the file",
+        ));
+    };
+    buf.copy_from_slice(bytes);
+    Ok(())
+}
+
+pub(super) fn write_all_at(data: &mut Vec<u8>, buf: &[u8], offset: u64) {
+    let start = offset as usize;
+    if data.len() < start + buf.len() {
+        data.resize(start + buf.len(), 0);
+    }
+    data[start..start + buf.len()].copy_from_slice(buf);
+}
+
+// Names of the files right inside `dir` among `paths`
+pub(super) fn files_in<'a>(dir: &str, paths: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let prefix = format!("{}/", dir.trim_end_matches('/'));
+    paths
+        .filter_map(|path| path.strip_prefix(&prefix))
+        .filter(|name| !name.contains('/'))
+        .map(str::to_string)
+        .collect()
+}
+
+struct MemFile(Contents);
+
+impl StorageFile for MemFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_exact_at(&self.0.read().unwrap(), buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        write_all_at(&mut self.0.write().unwrap(), buf, offset);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.read().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.write().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut files = self.files.lock().unwrap();
+        let contents = match (files.get(path), mode) {
+            (Some(_), OpenMode::CreateNew) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path),
+                ))
+            }
+            (Some(contents), _) => contents.clone(),
+            (None, OpenMode::Existing) => return Err(not_found(path)),
+            (None, _) => files.entry(path.to_string()).or_default().clone(),
+        };
+        Ok(Box::new(MemFile(contents)))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.files.lock().unwrap().contains_key(path)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let contents = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_string(), contents);
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(files_in(dir, self.files.lock().unwrap().keys()))
+    }
+
+    fn create_dir_all(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_dir(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcask::{Bitcask, BitcaskOptions};
+    use crate::clock::ManualClock;
+    use crate::conformance;
+    use crate::lsmt::{LSMTree, LSMTreeOptions};
+
+    #[test]
+    fn tables_in_memory() {
+        let storage = Arc::new(MemStorage::new());
+        let clock = Arc::new(ManualClock::default());
+        let options = LSMTreeOptions {
+            dir: "lsmt".to_string(),
+            memtable_capacity: 500,
+            storage: storage.clone(),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        conformance::run(|| LSMTree::new(&options), &clock, 7);
+        assert!(!storage.read_dir("lsmt").unwrap().is_empty());
+
+        let options = BitcaskOptions {
+            dir: "bitcask".to_string(),
+            max_file_size: 1 << 14,
+            storage: storage.clone(),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        conformance::run(|| Bitcask::new(&options), &clock, 7);
+    }
+}
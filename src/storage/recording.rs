@@ -0,0 +1,187 @@
+use super::{MemStorage, OpenMode, Storage, StorageFile};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{Arc, Mutex},
+};
+
+// What a table did to its files, in order. Files get an id when they are
+// created, so writes through a handle still land in the right file after it
+// was renamed or removed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    Create {
+        file: u64,
+        path: String,
+    },
+    Write {
+        file: u64,
+        offset: u64,
+        bytes: Vec<u8>,
+    },
+    SetLen {
+        file: u64,
+        len: u64,
+    },
+    Sync {
+        file: u64,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Remove {
+        path: String,
+    },
+    SyncDir {
+        dir: String,
+    },
+}
+
+#[derive(Default)]
+struct Recorder {
+    trace: Vec<TraceOp>,
+    ids: HashMap<String, u64>,
+    next_id: u64,
+}
+
+// In-memory storage that records every write and sync, to replay what a disk
+// could hold after a crash at any point of a workload
+#[derive(Default)]
+pub struct RecordingStorage {
+    inner: MemStorage,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl RecordingStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trace(&self) -> Vec<TraceOp> {
+        self.recorder.lock().unwrap().trace.clone()
+    }
+
+    pub fn trace_len(&self) -> usize {
+        self.recorder.lock().unwrap().trace.len()
+    }
+}
+
+impl fmt::Debug for RecordingStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordingStorage")
+            .field("inner", &self.inner)
+            .field("trace", &self.trace_len())
+            .finish()
+    }
+}
+
+struct RecordingFile {
+    id: u64,
+    inner: Box<dyn StorageFile>,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl RecordingFile {
+    fn record(&self, op: TraceOp) {
+        self.recorder.lock().unwrap().trace.push(op);
+    }
+}
+
+impl StorageFile for RecordingFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.inner.read_exact_at(buf, offset)
+    }
+
+    fn write_all_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.inner.write_all_at(buf, offset)?;
+        self.record(TraceOp::Write {
+            file: self.id,
+            offset,
+            bytes: buf.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)?;
+        self.record(TraceOp::SetLen { file: self.id, len });
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.record(TraceOp::Sync { file: self.id });
+        Ok(())
+    }
+}
+
+impl Storage for RecordingStorage {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut recorder = self.recorder.lock().unwrap();
+        let inner = self.inner.open(path, mode)?;
+        let id = match recorder.ids.get(path) {
+            Some(&id) => id,
+            None => {
+                let id = recorder.next_id;
+                recorder.next_id += 1;
+                recorder.ids.insert(path.to_string(), id);
+                recorder.trace.push(TraceOp::Create {
+                    file: id,
+                    path: path.to_string(),
+                });
+                id
+            }
+        };
+        Ok(Box::new(RecordingFile {
+            id,
+            inner,
+            recorder: self.recorder.clone(),
+        }))
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn remove(&self, path: &str) -> io::Result<()> {
+        let mut recorder = self.recorder.lock().unwrap();
+        self.inner.remove(path)?;
+        recorder.ids.remove(path);
+        recorder.trace.push(TraceOp::Remove {
+            path: path.to_string(),
+        });
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut recorder = self.recorder.lock().unwrap();
+        self.inner.rename(from, to)?;
+        let id = recorder.ids.remove(from).unwrap();
+        recorder.ids.insert(to.to_string(), id);
+        recorder.trace.push(TraceOp::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+        Ok(())
+    }
+
+    fn read_dir(&self, dir: &str) -> io::Result<Vec<String>> {
+        self.inner.read_dir(dir)
+    }
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn sync_dir(&self, dir: &str) -> io::Result<()> {
+        self.inner.sync_dir(dir)?;
+        self.recorder.lock().unwrap().trace.push(TraceOp::SyncDir {
+            dir: dir.to_string(),
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+// Traces of the operations on a table, to capture access patterns and replay
+// them against other engines and configurations. A trace is a header
+// followed by bincode events with varint integers, each with the time since
+// the one before in microseconds.
+
+use crate::hash_table::HashTable;
+use crate::write_batch::WriteBatch;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+const MAGIC: &[u8; 8] = b"hastytr1";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TableOp {
+    Set { key: u64, value: u64 },
+    SetWithTtl { key: u64, value: u64, ttl_ms: u64 },
+    Get { key: u64 },
+    GetMany { keys: Vec<u64> },
+    Remove { key: u64 },
+    Merge { key: u64, operand: u64 },
+    Write(WriteBatch),
+    Iter,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub delta_micros: u64,
+    pub op: TableOp,
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+// Passes everything through to the table and logs it to `out`. Recording
+// stops at the first error writing the trace, which flush and into_inner
+// return, and the table keeps working.
+pub struct RecordingTable<T, W: Write> {
+    inner: T,
+    out: RefCell<W>,
+    last: RefCell<Instant>,
+    error: RefCell<Option<io::Error>>,
+}
+
+impl<T: HashTable> RecordingTable<T, BufWriter<File>> {
+    pub fn create(inner: T, path: &str) -> io::Result<Self> {
+        Self::new(inner, BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<T: HashTable, W: Write> RecordingTable<T, W> {
+    pub fn new(inner: T, mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        Ok(RecordingTable {
+            inner,
+            out: RefCell::new(out),
+            last: RefCell::new(Instant::now()),
+            error: RefCell::new(None),
+        })
+    }
+
+    fn record(&self, op: TableOp) {
+        if self.error.borrow().is_some() {
+            return;
+        }
+        let now = Instant::now();
+        let delta = now - self.last.replace(now);
+        let event = TraceEvent {
+            delta_micros: delta.as_micros() as u64,
+            op,
+        };
+        if let Err(err) = options().serialize_into(&mut *self.out.borrow_mut(), &event) {
+            let err = match *err {
+                bincode::ErrorKind::Io(err) => err,
+                err => io::Error::other(err.to_string()),
+            };
+            *self.error.borrow_mut() = Some(err);
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        if let Some(err) = &*self.error.borrow() {
+            return Err(io::Error::new(err.kind(), err.to_string()));
+        }
+        self.out.borrow_mut().flush()
+    }
+
+    // The trace is an error if recording stopped early
+    pub fn into_inner(self) -> (T, io::Result<W>) {
+        let mut out = self.out.into_inner();
+        let result = match self.error.into_inner() {
+            Some(err) => Err(err),
+            None => out.flush().map(|_| out),
+        };
+        (self.inner, result)
+    }
+}
+
+impl<T: HashTable, W: Write> HashTable for RecordingTable<T, W> {
+    fn set(&mut self, key: u64, value: u64) {
+        self.record(TableOp::Set { key, value });
+        self.inner.set(key, value);
+    }
+
+    fn set_with_ttl(&mut self, key: u64, value: u64, ttl: Duration) {
+        self.record(TableOp::SetWithTtl {
+            key,
+            value,
+            ttl_ms: ttl.as_millis() as u64,
+        });
+        self.inner.set_with_ttl(key, value, ttl);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.record(TableOp::Get { key });
+        self.inner.get(key)
+    }
+
+    fn get_many(&self, keys: &[u64]) -> Vec<Option<u64>> {
+        self.record(TableOp::GetMany {
+            keys: keys.to_vec(),
+        });
+        self.inner.get_many(keys)
+    }
+
+    fn remove(&mut self, key: u64) {
+        self.record(TableOp::Remove { key });
+        self.inner.remove(key);
+    }
+
+    fn merge(&mut self, key: u64, operand: u64) {
+        self.record(TableOp::Merge { key, operand });
+        self.inner.merge(key, operand);
+    }
+
+    fn write(&mut self, batch: &WriteBatch) {
+        self.record(TableOp::Write(batch.clone()));
+        self.inner.write(batch);
+    }
+
+    fn on_disk_size(&self) -> usize {
+        self.inner.on_disk_size()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (u64, u64)> + '_> {
+        self.record(TableOp::Iter);
+        self.inner.iter()
+    }
+
+    fn iter_with_expiry(&self) -> Box<dyn Iterator<Item = (u64, u64, Option<u64>)> + '_> {
+        self.record(TableOp::Iter);
+        self.inner.iter_with_expiry()
+    }
+}
+
+// The events of a trace in order. A trace cut short by a crash ends with an
+// error for the torn event.
+pub struct TraceReader<R: Read> {
+    input: BufReader<R>,
+}
+
+impl TraceReader<File> {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(input: R) -> io::Result<Self> {
+        let mut input = BufReader::new(input);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a trace"));
+        }
+        Ok(TraceReader { input })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.input.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => Some(
+                options()
+                    .deserialize_from(&mut self.input)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            ),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    // Keeps the recorded time between operations, when the table keeps up
+    Original,
+    // One operation right after the other
+    Max,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub ops: usize,
+    pub elapsed: Duration,
+}
+
+// Drives the table with the operations of the trace
+pub fn replay<R: Read>(
+    trace: TraceReader<R>,
+    table: &mut dyn HashTable,
+    speed: Speed,
+) -> io::Result<ReplayStats> {
+    let start = Instant::now();
+    // When the current operation was recorded, relative to the first
+    let mut offset = Duration::ZERO;
+    let mut ops = 0;
+    for event in trace {
+        let event = event?;
+        offset += Duration::from_micros(event.delta_micros);
+        if speed == Speed::Original {
+            if let Some(wait) = (start + offset).checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        match event.op {
+            TableOp::Set { key, value } => table.set(key, value),
+            TableOp::SetWithTtl { key, value, ttl_ms } => {
+                table.set_with_ttl(key, value, Duration::from_millis(ttl_ms))
+            }
+            TableOp::Get { key } => {
+                table.get(key);
+            }
+            TableOp::GetMany { keys } => {
+                table.get_many(&keys);
+            }
+            TableOp::Remove { key } => table.remove(key),
+            TableOp::Merge { key, operand } => table.merge(key, operand),
+            TableOp::Write(batch) => table.write(&batch),
+            TableOp::Iter => {
+                table.iter().count();
+            }
+        }
+        ops += 1;
+    }
+    Ok(ReplayStats {
+        ops,
+        elapsed: start.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linear_probing::{LPHashTable, LPHashTableOptions};
+    use crate::lsmt::{LSMTree, LSMTreeOptions};
+    use crate::storage::MemStorage;
+    use std::{collections::HashMap, sync::Arc};
+
+    fn lp() -> LPHashTable {
+        LPHashTable::new(&LPHashTableOptions {
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        })
+    }
+
+    fn contents(table: &dyn HashTable) -> HashMap<u64, u64> {
+        table.iter().collect()
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut table = RecordingTable::new(lp(), Vec::new()).unwrap();
+        for key in 0..1000 {
+            table.set(key, key * 2);
+        }
+        table.remove(7);
+        table.merge(8, 1);
+        table.get(9);
+        table.get_many(&[1, 2, 3]);
+        table.write(WriteBatch::new().set(2000, 1).remove(3));
+        table.set_with_ttl(3000, 1, Duration::from_secs(3600));
+        thread::sleep(Duration::from_millis(30));
+        table.iter().count();
+        let (recorded, trace) = table.into_inner();
+        let trace = trace.unwrap();
+        // Small keys take a few bytes per operation
+        assert!(trace.len() < 8 + 1000 * 8, "{}", trace.len());
+
+        let events = TraceReader::new(&trace[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(events.len(), 1007);
+        assert_eq!(events[1000].op, TableOp::Remove { key: 7 });
+        assert!(events[1006].delta_micros >= 30000);
+
+        let mut lsm = LSMTree::new(&LSMTreeOptions {
+            storage: Arc::new(MemStorage::new()),
+            ..Default::default()
+        });
+        let stats = replay(TraceReader::new(&trace[..]).unwrap(), &mut lsm, Speed::Max).unwrap();
+        assert_eq!(stats.ops, 1007);
+        assert_eq!(contents(&lsm), contents(&recorded));
+
+        let mut replayed = lp();
+        let stats = replay(
+            TraceReader::new(&trace[..]).unwrap(),
+            &mut replayed,
+            Speed::Original,
+        )
+        .unwrap();
+        assert!(stats.elapsed >= Duration::from_millis(30));
+        assert_eq!(contents(&replayed), contents(&recorded));
+
+        // A torn last event is an error, a foreign file isn't a trace
+        let torn = TraceReader::new(&trace[..trace.len() - 1]).unwrap();
+        assert!(torn.last().unwrap().is_err());
+        assert!(TraceReader::new(&b"not a trace"[..]).is_err());
+    }
+
+    // Takes `room` bytes, then fails
+    #[derive(Debug)]
+    struct FullWriter {
+        room: usize,
+    }
+
+    impl Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "full"));
+            }
+            let written = buf.len().min(self.room);
+            self.room -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_write_errors() {
+        let mut table = RecordingTable::new(lp(), FullWriter { room: 100 }).unwrap();
+        for key in 0..100 {
+            table.set(key, key);
+        }
+        assert_eq!(table.get(99), Some(99));
+        assert_eq!(
+            table.flush().unwrap_err().kind(),
+            io::ErrorKind::StorageFull
+        );
+        let (inner, trace) = table.into_inner();
+        assert_eq!(trace.unwrap_err().kind(), io::ErrorKind::StorageFull);
+        assert_eq!(inner.iter().count(), 100);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::hash_table::HashTable;
+use crate::lsmt::LSMTree;
+use crate::write_batch::WriteBatch;
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+};
+
+// Tables that can tell when a key was last written
+pub trait Versioned {
+    fn last_rev(&self) -> u64;
+    fn key_rev(&self, key: u64) -> Option<u64>;
+}
+
+impl Versioned for LSMTree {
+    fn last_rev(&self) -> u64 {
+        LSMTree::last_rev(self)
+    }
+
+    fn key_rev(&self, key: u64) -> Option<u64> {
+        LSMTree::key_rev(self, key)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub key: u64,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key {} was written by someone else", self.key)
+    }
+}
+
+impl Error for Conflict {}
+
+// Optimistic transaction: writes are buffered until commit, which fails if
+// any key the transaction read was written after the transaction began
+pub struct Transaction {
+    start_rev: u64,
+    // The revision of every key read, when it was first read. Compactions
+    // drop tombstones and old versions, so a key removed since may have no
+    // revision at all by the time of the commit
+    reads: HashMap<u64, Option<u64>>,
+    writes: BTreeMap<u64, Option<u64>>,
+}
+
+impl Transaction {
+    pub fn begin<T: Versioned>(table: &T) -> Self {
+        Transaction {
+            start_rev: table.last_rev(),
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get<T: HashTable + Versioned>(&mut self, table: &T, key: u64) -> Option<u64> {
+        if let Some(value) = self.writes.get(&key) {
+            return *value;
+        }
+        self.reads.entry(key).or_insert_with(|| table.key_rev(key));
+        table.get(key)
+    }
+
+    pub fn set(&mut self, key: u64, value: u64) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: u64) {
+        self.writes.insert(key, None);
+    }
+
+    pub fn commit<T: HashTable + Versioned>(self, table: &mut T) -> Result<(), Conflict> {
+        for (&key, &rev) in &self.reads {
+            if rev.is_some_and(|rev| rev > self.start_rev) || table.key_rev(key) != rev {
+                return Err(Conflict { key });
+            }
+        }
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        table.write(&batch);
+        Ok(())
+    }
+
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsmt::LSMTreeOptions;
+    use crate::storage::MemStorage;
+    use std::sync::Arc;
+
+    #[test]
+    fn read_modify_write() {
+        let options = LSMTreeOptions {
+            dir: "lsmt/transactions".to_string(),
+            memtable_capacity: 10,
+            ..Default::default()
+        };
+        let _ = std::fs::remove_dir_all(&options.dir);
+        let mut tree = LSMTree::new(&options);
+        for key in 0..20 {
+            tree.set(key, 100);
+        }
+
+        let mut txn1 = Transaction::begin(&tree);
+        let mut txn2 = Transaction::begin(&tree);
+        let balance = txn1.get(&tree, 1).unwrap();
+        txn1.set(1, balance - 10);
+        let balance = txn1.get(&tree, 2).unwrap();
+        txn1.set(2, balance + 10);
+        assert_eq!(txn1.get(&tree, 1), Some(90));
+        assert_eq!(tree.get(1), Some(100));
+
+        let balance = txn2.get(&tree, 2).unwrap();
+        txn2.set(2, balance - 50);
+        txn2.remove(3);
+        txn1.commit(&mut tree).unwrap();
+        assert_eq!(txn2.commit(&mut tree), Err(Conflict { key: 2 }));
+        assert_eq!(tree.get(1), Some(90));
+        assert_eq!(tree.get(2), Some(110));
+        assert_eq!(tree.get(3), Some(100));
+
+        let mut txn3 = Transaction::begin(&tree);
+        let mut txn4 = Transaction::begin(&tree);
+        let value = txn3.get(&tree, 4).unwrap();
+        txn3.set(5, value);
+        txn4.set(4, 0);
+        txn4.set(5, 0);
+        txn4.commit(&mut tree).unwrap();
+        assert_eq!(txn3.commit(&mut tree), Err(Conflict { key: 4 }));
+
+        // Writes to keys the transaction didn't read don't conflict
+        let mut txn5 = Transaction::begin(&tree);
+        let mut txn6 = Transaction::begin(&tree);
+        let value = txn5.get(&tree, 6).unwrap();
+        txn5.set(6, value + 1);
+        txn6.set(7, 0);
+        txn6.commit(&mut tree).unwrap();
+        txn5.commit(&mut tree).unwrap();
+        assert_eq!(tree.get(6), Some(101));
+
+        let mut txn7 = Transaction::begin(&tree);
+        txn7.remove(8);
+        txn7.rollback();
+        assert_eq!(tree.get(8), Some(100));
+
+        drop(tree);
+        std::fs::remove_dir_all(options.dir).unwrap();
+    }
+
+    #[test]
+    fn removal_compacted_away() {
+        let options = LSMTreeOptions {
+            storage: Arc::new(MemStorage::new()),
+            memtable_capacity: 10,
+            ..Default::default()
+        };
+        let mut tree = LSMTree::new(&options);
+        for key in 0..20 {
+            tree.set(key, 100);
+        }
+        let mut txn = Transaction::begin(&tree);
+        let balance = txn.get(&tree, 3).unwrap();
+        txn.set(3, balance + 10);
+
+        // Someone else removes the key, and compactions drop the tombstone
+        tree.remove(3);
+        let mut key = 1000;
+        while tree.key_rev(3).is_some() {
+            tree.set(key, 0);
+            key += 1;
+            assert!(key < 100_000, "the tombstone is never dropped");
+        }
+        assert_eq!(txn.commit(&mut tree), Err(Conflict { key: 3 }));
+        assert_eq!(tree.get(3), None);
+    }
+}
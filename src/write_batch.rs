@@ -0,0 +1,101 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    // Expiry is absolute, in milliseconds since the Unix epoch
+    Set {
+        key: u64,
+        value: u64,
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: u64,
+    },
+    Merge {
+        key: u64,
+        operand: u64,
+    },
+}
+
+impl BatchOp {
+    pub fn key(&self) -> u64 {
+        match self {
+            BatchOp::Set { key, .. } => *key,
+            BatchOp::Remove { key } => *key,
+            BatchOp::Merge { key, operand: _ } => *key,
+        }
+    }
+}
+
+// Operations that are applied all together or not at all, in insertion order
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn set(&mut self, key: u64, value: u64) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            expires_at: None,
+        });
+        self
+    }
+
+    pub fn set_with_expiry(&mut self, key: u64, value: u64, expires_at: u64) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        });
+        self
+    }
+
+    pub fn remove(&mut self, key: u64) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    pub fn merge(&mut self, key: u64, operand: u64) -> &mut Self {
+        self.ops.push(BatchOp::Merge { key, operand });
+        self
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    pub fn serialize(&self) -> bincode::Result<Vec<u8>> {
+        bincode::DefaultOptions::new().serialize(&self)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::DefaultOptions::new().deserialize(bytes)
+    }
+}
+
+impl FromIterator<BatchOp> for WriteBatch {
+    fn from_iter<T: IntoIterator<Item = BatchOp>>(iter: T) -> Self {
+        WriteBatch {
+            ops: iter.into_iter().collect(),
+        }
+    }
+}
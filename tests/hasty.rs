@@ -0,0 +1,128 @@
+// Runs the hasty binary on stores in a directory of its own per test
+use std::fs;
+use std::process::{Command, Output};
+
+fn store_dir(name: &str) -> String {
+    let dir = format!("{}/hasty/{}", env!("CARGO_TARGET_TMPDIR"), name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn hasty(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_hasty"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn engine_detection() {
+    let dir = store_dir("engine_detection");
+    let lp = format!("{}/lp.bin", dir);
+    let lsm = format!("{}/lsm", dir);
+
+    // Stores that don't exist need an engine, existing ones are a file or a
+    // directory
+    let output = hasty(&[&lp, "set", "1", "2"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        format!(
+            "hasty: {} doesn't exist, pick an --engine to create it\n",
+            lp
+        )
+    );
+    stdout(&hasty(&[&lp, "--engine", "lp", "set", "1", "2"]));
+    stdout(&hasty(&[&lsm, "--engine", "lsm", "set", "1", "3"]));
+    assert!(fs::metadata(&lp).unwrap().is_file());
+    assert!(fs::metadata(&lsm).unwrap().is_dir());
+    assert_eq!(stdout(&hasty(&[&lp, "get", "1"])), "2\n");
+    assert_eq!(stdout(&hasty(&[&lsm, "get", "1"])), "3\n");
+    assert!(stdout(&hasty(&[&lp, "stats"])).starts_with("engine          lp\n"));
+    assert!(stdout(&hasty(&[&lsm, "stats"])).starts_with("engine            lsm\n"));
+
+    let output = hasty(&[&lp, "get", "5"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "hasty: 5 not found\n");
+    stdout(&hasty(&[&lp, "remove", "1"]));
+    assert_eq!(hasty(&[&lp, "get", "1"]).status.code(), Some(1));
+}
+
+#[test]
+fn scan_and_stats() {
+    let dir = store_dir("scan_and_stats");
+    let lp = format!("{}/lp.bin", dir);
+    for key in [40, 7, 1000, 25, 3, 18] {
+        let value = (key * 2).to_string();
+        stdout(&hasty(&[
+            &lp,
+            "--engine",
+            "lp",
+            "set",
+            &key.to_string(),
+            &value,
+        ]));
+    }
+
+    // Hash order is no order, so the range comes out sorted
+    assert_eq!(
+        stdout(&hasty(&[&lp, "scan", "--from", "5", "--to", "100"])),
+        "7 14\n18 36\n25 50\n40 80\n"
+    );
+    assert_eq!(
+        stdout(&hasty(&[&lp, "scan", "--limit", "2"])),
+        "3 6\n7 14\n"
+    );
+
+    let stats = stdout(&hasty(&[&lp, "stats"]));
+    let lines = stats.lines().collect::<Vec<_>>();
+    assert_eq!(lines[0], "engine          lp");
+    assert_eq!(lines[1], "entries         6");
+    assert!(lines[4].starts_with("load            0.000 (grows over 0.5)"));
+    assert!(stats.contains("probe lengths   mean "));
+
+    let lsm = format!("{}/lsm", dir);
+    stdout(&hasty(&[&lsm, "--engine", "lsm", "set", "1", "1"]));
+    let stats = stdout(&hasty(&[&lsm, "stats"]));
+    assert!(stats.contains("memtable entries  1\n"));
+    assert!(stats.contains("disktables        0, oldest first\n"));
+}
+
+#[test]
+fn dump_and_load_files() {
+    let dir = store_dir("dump_and_load_files");
+    let lp = format!("{}/lp.bin", dir);
+    let dump = format!("{}/dump.txt", dir);
+    stdout(&hasty(&[&lp, "--engine", "lp", "set", "1", "2"]));
+    stdout(&hasty(&[&lp, "dump", "--output", &dump]));
+    assert_eq!(fs::read_to_string(&dump).unwrap(), "1 2\n");
+
+    let lsm = format!("{}/lsm", dir);
+    stdout(&hasty(&[&lsm, "--engine", "lsm", "load", &dump]));
+    assert_eq!(stdout(&hasty(&[&lsm, "get", "1"])), "2\n");
+
+    // Paths that can't be opened fail with a message instead of a panic
+    let missing = format!("{}/missing/dump.txt", dir);
+    for (args, message) in [
+        ([lp.as_str(), "dump", "--output", &missing], "can't create"),
+        (
+            [lp.as_str(), "load", "--format=text", &missing],
+            "can't open",
+        ),
+    ] {
+        let output = hasty(&args);
+        assert_eq!(output.status.code(), Some(1));
+        let prefix = format!("hasty: {} {}: ", message, missing);
+        assert!(stderr(&output).starts_with(&prefix), "{:?}", output);
+    }
+}